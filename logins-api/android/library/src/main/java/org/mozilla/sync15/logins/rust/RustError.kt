@@ -55,6 +55,9 @@ open class RustError : Structure() {
             4 -> return InvalidRecordException(message)
             5 -> return InvalidKeyException(message)
             6 -> return RequestFailedException(message)
+            // Other sync errors, from `sync15_adapter::ffi::error_codes`.
+            in 100..119 -> return RequestFailedException(message)
+            in 120..139 -> return SyncAuthInvalidException(message)
             else -> return LoginsStorageException(message)
         }
     }
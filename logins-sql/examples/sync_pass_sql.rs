@@ -32,7 +32,7 @@ use failure::Fail;
 use std::{fs, io::{self, Read, Write}};
 use std::collections::HashMap;
 use fxa_client::{FirefoxAccount, Config, OAuthInfo};
-use sync::{Sync15StorageClientInit, KeyBundle};
+use sync::{Sync15StorageClientInit, TokenserverCredentials, KeyBundle};
 use logins_sql::{PasswordEngine, Login};
 
 const CLIENT_ID: &str = "98adfa37698f255b";
@@ -378,8 +378,10 @@ fn main() -> Result<()> {
     let key = keys.get(SYNC_SCOPE).unwrap();
 
     let client_init = Sync15StorageClientInit {
-        key_id: key.kid.clone(),
-        access_token: token.access_token.clone(),
+        credentials: TokenserverCredentials::OAuth {
+            access_token: token.access_token.clone(),
+            key_id: key.kid.clone(),
+        },
         tokenserver_url,
     };
     let root_sync_key = KeyBundle::from_ksync_base64(&key.k)?;
@@ -455,7 +457,8 @@ fn main() -> Result<()> {
             }
             'S' | 's' => {
                 info!("Syncing!");
-                if let Err(e) = engine.sync(&client_init, &root_sync_key) {
+                let interrupt = sync::InterruptHandle::new();
+                if let Err(e) = engine.sync(&client_init, &root_sync_key, &interrupt) {
                     warn!("Sync failed! {}", e);
                     warn!("BT: {:?}", e.backtrace());
                 } else {
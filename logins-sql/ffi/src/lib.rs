@@ -73,13 +73,17 @@ pub unsafe extern "C" fn sync15_passwords_sync(
     call_with_result(error, || {
         state.sync(
             &sync15_adapter::Sync15StorageClientInit {
-                key_id: rust_string_from_c(key_id),
-                access_token: rust_string_from_c(access_token),
+                credentials: sync15_adapter::TokenserverCredentials::OAuth {
+                    access_token: rust_string_from_c(access_token),
+                    key_id: rust_string_from_c(key_id),
+                },
                 tokenserver_url: parse_url(rust_str_from_c(tokenserver_url))?,
             },
             &sync15_adapter::KeyBundle::from_ksync_base64(
                 rust_str_from_c(sync_key)
-            )?
+            )?,
+            // TODO: Expose a way to interrupt syncs over the FFI.
+            &sync15_adapter::InterruptHandle::new(),
         )
     })
 }
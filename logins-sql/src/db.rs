@@ -5,11 +5,15 @@
 use rusqlite::{Connection, types::{ToSql, FromSql}};
 use std::time::SystemTime;
 use std::path::Path;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use error::*;
 use schema;
 use login::{LocalLogin, MirrorLogin, Login, SyncStatus, SyncLoginData};
-use sync::{self, ServerTimestamp, IncomingChangeset, Store, OutgoingChangeset, Payload};
+use sync::{self, ServerTimestamp, IncomingChangeset, Store, OutgoingChangeset, Payload,
+           InterruptHandle};
+use sync::telemetry;
+use sync::dry_run::{DryRunChanges, LocalChange};
+use serde_json::{self, Map, Value as JsonValue};
 use update_plan::UpdatePlan;
 use sql_support::{self, ConnExt};
 use util;
@@ -519,16 +523,25 @@ impl LoginDb {
         Ok(())
     }
 
-    fn reconcile(&self, records: Vec<SyncLoginData>, server_now: ServerTimestamp) -> Result<UpdatePlan> {
+    fn reconcile(
+        &self,
+        records: Vec<SyncLoginData>,
+        server_now: ServerTimestamp,
+        interrupt: &InterruptHandle,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<UpdatePlan> {
         let mut plan = UpdatePlan::default();
 
         for mut record in records {
+            // Nothing's changed yet, so it's safe to stop here.
+            interrupt.err_if_interrupted()?;
             debug!("Processing remote change {}", record.guid());
             let upstream = if let Some(inbound) = record.inbound.0.take() {
                 inbound
             } else {
                 debug!("Processing inbound deletion (always prefer)");
                 plan.plan_delete(record.guid.clone());
+                telem.applied(1);
                 continue;
             };
             let upstream_time = record.inbound.1;
@@ -537,22 +550,27 @@ impl LoginDb {
                     debug!("  Conflict between remote and local, Resolving with 3WM");
                     plan.plan_three_way_merge(
                         local, mirror, upstream, upstream_time, server_now);
+                    telem.reconciled(1);
                 }
                 (Some(_mirror), None) => {
                     debug!("  Forwarding mirror to remote");
                     plan.plan_mirror_update(upstream, upstream_time);
+                    telem.applied(1);
                 }
                 (None, Some(local)) => {
                     debug!("  Conflicting record without shared parent, using newer");
                     plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
+                    telem.reconciled(1);
                 }
                 (None, None) => {
                     if let Some(dupe) = self.find_dupe(&upstream)? {
                         debug!("  Incoming record {} was is a dupe of local record {}", upstream.id, dupe.id);
                         plan.plan_two_way_merge(&dupe, (upstream, upstream_time));
+                        telem.reconciled(1);
                     } else {
                         debug!("  No dupe found, inserting into mirror");
                         plan.plan_mirror_insert(upstream, upstream_time, false);
+                        telem.applied(1);
                     }
                 }
             }
@@ -592,14 +610,51 @@ impl LoginDb {
 
     fn do_apply_incoming(
         &self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        interrupt: &InterruptHandle,
+        telem: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset> {
         let data = self.fetch_login_data(&inbound.changes)?;
-        let plan = self.reconcile(data, inbound.timestamp)?;
+        let plan = self.reconcile(data, inbound.timestamp, interrupt, telem)?;
         self.execute_plan(plan)?;
         Ok(self.fetch_outgoing(inbound.timestamp)?)
     }
 
+    fn do_dry_run_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<DryRunChanges> {
+        let data = self.fetch_login_data(&inbound.changes)?;
+        // Dry runs don't change anything, so there's no need to interrupt them.
+        let plan = self.reconcile(data, inbound.timestamp, &InterruptHandle::new(), telem)?;
+
+        // Incoming records can also replace local dupes with different IDs.
+        let mut ids = inbound.changes.iter()
+            .map(|(payload, _)| payload.id.clone())
+            .collect::<Vec<_>>();
+        ids.extend(plan.delete_local.iter().cloned());
+        ids.sort();
+        ids.dedup();
+        let before = ids.iter().map(|id| self.get_by_id(id)).collect::<Result<Vec<_>>>()?;
+
+        // Apply the plan in a transaction that we always roll back, so that
+        // we can see what would change without keeping it.
+        let tx = self.db.unchecked_transaction()?;
+        plan.execute(&tx)?;
+        let mut local = Vec::new();
+        for (id, old) in ids.into_iter().zip(before) {
+            let new = self.get_by_id(&id)?;
+            let change = LocalChange::between(id, login_fields(old)?.as_ref(),
+                                              login_fields(new)?.as_ref());
+            local.extend(change.map(redact_password));
+        }
+        let outgoing = self.fetch_outgoing(inbound.timestamp)?;
+        tx.rollback()?;
+
+        Ok(DryRunChanges { local, outgoing })
+    }
+
     fn put_meta(&self, key: &str, value: &ToSql) -> Result<()> {
         self.execute_named_cached(
             "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
@@ -640,18 +695,50 @@ impl LoginDb {
 impl Store for LoginDb {
     type Error = Error;
 
+    fn collection_name(&self) -> &'static str {
+        "passwords"
+    }
+
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        LoginDb::get_last_sync(self)
+    }
+
+    fn reset(&self) -> Result<()> {
+        LoginDb::reset(self)
+    }
+
     fn apply_incoming(
         &self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        interrupt: &InterruptHandle,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset> {
-        self.do_apply_incoming(inbound)
+        self.do_apply_incoming(inbound, interrupt, incoming_telemetry)
+    }
+
+    fn dry_run_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<Option<DryRunChanges>> {
+        Ok(Some(self.do_dry_run_incoming(inbound, incoming_telemetry)?))
+    }
+
+    fn set_high_water_mark(
+        &self,
+        high_water_mark: ServerTimestamp,
+    ) -> Result<()> {
+        self.set_last_sync(high_water_mark)
     }
 
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
         records_synced: &[String],
+        _records_failed: &HashMap<String, String>,
     ) -> Result<()> {
+        // Failed records stay in `loginsL`, so we'll upload them again on
+        // the next sync.
         self.mark_as_synchronized(
             &records_synced.iter().map(|r| r.as_str()).collect::<Vec<_>>(),
             new_timestamp
@@ -659,6 +746,31 @@ impl Store for LoginDb {
     }
 }
 
+// Returns a login's fields for `LocalChange::between`, minus the ID.
+fn login_fields(login: Option<Login>) -> Result<Option<Map<String, JsonValue>>> {
+    let login = match login {
+        Some(login) => login,
+        None => return Ok(None),
+    };
+    Ok(match serde_json::to_value(login)? {
+        JsonValue::Object(mut fields) => {
+            fields.remove("id");
+            Some(fields)
+        }
+        _ => None,
+    })
+}
+
+// Dry run reports are meant to be shared with support, so they shouldn't
+// include passwords; just whether they changed.
+fn redact_password(mut change: LocalChange) -> LocalChange {
+    for field in change.fields.iter_mut().filter(|field| field.name == "password") {
+        field.old = field.old.take().map(|_| JsonValue::from("(redacted)"));
+        field.new = field.new.take().map(|_| JsonValue::from("(redacted)"));
+    }
+    change
+}
+
 lazy_static! {
 
     static ref GET_ALL_SQL: String = format!("
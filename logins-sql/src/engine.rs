@@ -3,7 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 use login::Login;
 use error::*;
-use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle};
+use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle,
+           InterruptHandle};
 use db::LoginDb;
 use std::path::Path;
 use std::cell::Cell;
@@ -77,16 +78,20 @@ impl PasswordEngine {
         &self.db.db
     }
 
+    /// Syncs passwords. If `interrupt` is interrupted, the sync stops at the
+    /// next request, or the next incoming login, and fails with
+    /// `Interrupted`; pass a new handle to the next sync.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
-        root_sync_key: &KeyBundle
+        root_sync_key: &KeyBundle,
+        interrupt: &InterruptHandle,
     ) -> Result<()> {
 
-        // Note: If `to_ready` (or anything else with a ?) fails below, this
-        // `replace()` means we end up with `state.sync.is_none()`, which means the
-        // next sync will redownload meta/global, crypto/keys, etc. without
-        // needing to. Apparently this is both okay and by design.
+        // Note: If anything with a ? fails before we restore `self.sync` below,
+        // this `replace()` means we end up with `state.sync.is_none()`, which
+        // means the next sync will redownload meta/global, crypto/keys, etc.
+        // without needing to. Apparently this is both okay and by design.
         let maybe_sync_info = self.sync.replace(None).map(Ok);
 
         // `maybe_sync_info` is None if we haven't called `sync` since
@@ -97,19 +102,16 @@ impl PasswordEngine {
         // we've `reset()`, which clears it out).
         let mut sync_info = maybe_sync_info.unwrap_or_else(|| -> Result<SyncInfo> {
             info!("First time through since unlock. Trying to load persisted global state.");
-            let state = if let Some(persisted_global_state) = self.db.get_global_state()? {
-                serde_json::from_str::<GlobalState>(&persisted_global_state)
-                .unwrap_or_else(|_| {
-                    // Don't log the error since it might contain sensitive
-                    // info like keys (the JSON does, after all).
-                    error!("Failed to parse GlobalState from JSON! Falling back to default");
-                    // Unstick ourselves by using the default state.
-                    GlobalState::default()
-                })
-            } else {
-                info!("No previously persisted global state, using default");
-                GlobalState::default()
-            };
+            let persisted = self.db.get_global_state()?;
+            let loaded = GlobalState::load_persisted(persisted.as_ref().map(String::as_str));
+            if loaded.discarded {
+                // We can't tell if the server was wiped or our keys changed
+                // since we last synced, so download everything again. Our
+                // mirror keeps this from re-uploading every login.
+                warn!("Discarded persisted global state; resetting last sync time");
+                self.db.set_last_sync(sync::SERVER_EPOCH)?;
+            }
+            let state = loaded.state;
             let client = Sync15StorageClient::new(storage_init.clone())?;
             Ok(SyncInfo {
                 state,
@@ -135,21 +137,25 @@ impl PasswordEngine {
             sync_info.last_client_init = storage_init.clone();
         }
 
-        // Advance the state machine to the point where it can perform a full
-        // sync. This may involve uploading meta/global, crypto/keys etc.
-        {
-            // Scope borrow of `sync_info.client`
-            let mut state_machine =
-                sync::SetupStateMachine::for_full_sync(&sync_info.client, &root_sync_key);
-            info!("Advancing state machine to ready (full)");
-            let next_sync_state = state_machine.to_ready(sync_info.state)?;
-            sync_info.state = next_sync_state;
-        }
-
-        // Reset our local state if necessary.
-        if sync_info.state.engines_that_need_local_reset().contains("passwords") {
-            info!("Passwords sync ID changed; engine needs local reset");
-            self.db.reset()?;
+        // The manager advances the state machine to the point where it can
+        // perform a full sync (which may involve uploading meta/global,
+        // crypto/keys etc), resets our local state if necessary, and then
+        // syncs the passwords collection. We don't use `?` here so that we can
+        // persist the state and restore the value of `self.sync` even if sync
+        // fails.
+        info!("Syncing passwords engine!");
+        let mut telem = sync::telemetry::SyncTelemetry::new();
+        let result = sync::SyncManager::new(vec![&self.db]).sync(
+            &sync_info.client,
+            &mut sync_info.state,
+            root_sync_key,
+            interrupt,
+            &mut telem,
+        );
+        // We don't submit telemetry ourselves yet, so just log it.
+        match serde_json::to_string(&telem) {
+            Ok(json) => info!("Sync telemetry: {}", json),
+            Err(e) => warn!("Failed to serialize sync telemetry: {}", e),
         }
 
         // Persist the current sync state in the DB.
@@ -157,29 +163,21 @@ impl PasswordEngine {
         let s = sync_info.state.to_persistable_string();
         self.db.set_global_state(&s)?;
 
-        info!("Syncing passwords engine!");
-
-        let ts = self.db.get_last_sync()?.unwrap_or_default();
+        // Restore our value of `sync_info` even if the sync failed.
+        self.sync.replace(Some(sync_info));
 
-        // We don't use `?` here so that we can restore the value of of
-        // `self.sync` even if sync fails.
-        let result = sync::synchronize(
-            &sync_info.client,
-            &sync_info.state,
-            &self.db,
-            "passwords".into(),
-            ts,
-            true
-        );
+        // If the user declined passwords on another device, there's no
+        // result, and nothing to report.
+        let result = match result?.engine_results.remove("passwords") {
+            Some(engine_result) => engine_result.map(|_| ()),
+            None => Ok(()),
+        };
 
         match &result {
             Ok(()) => info!("Sync was successful!"),
             Err(e) => warn!("Sync failed! {:?}", e),
         }
 
-        // Restore our value of `sync_info` even if the sync failed.
-        self.sync.replace(Some(sync_info));
-
         Ok(result?)
     }
 }
@@ -8,7 +8,7 @@
 
 use rusqlite;
 use ffi_support::{ErrorCode, ExternError};
-use sync::{ErrorKind as Sync15ErrorKind};
+use sync;
 use {Error, ErrorKind, PasswordEngine, Login};
 
 pub mod error_codes {
@@ -16,9 +16,12 @@ pub mod error_codes {
     /// by the application.
     pub const UNEXPECTED: i32 = -2;
 
-    // Note: -1 and 0 (panic and success) codes are reserved by the ffi-support library
+    // Note: -1 and 0 (panic and success) codes are reserved by the ffi-support library,
+    // and sync errors use the codes in `sync15_adapter::ffi::error_codes`, except for
+    // the two below, which we returned before sync errors had their own codes.
 
     /// Indicates the FxA credentials are invalid, and should be refreshed.
+    /// Used instead of `sync15_adapter::ffi::error_codes::AUTH_INVALID`.
     pub const AUTH_INVALID: i32 = 1;
 
     /// Returned from an `update()` call where the record ID did not exist.
@@ -35,7 +38,8 @@ pub mod error_codes {
     /// provided encryption key.
     pub const INVALID_KEY: i32 = 5;
 
-    /// A request to the sync server failed.
+    /// A request to the sync server failed. Used instead of
+    /// `sync15_adapter::ffi::error_codes::NETWORK`.
     pub const NETWORK: i32 = 6;
 }
 
@@ -43,14 +47,12 @@ fn get_code(err: &Error) -> ErrorCode {
     match err.kind() {
         ErrorKind::SyncAdapterError(e) => {
             error!("Sync error {:?}", e);
-            match e.kind() {
-                Sync15ErrorKind::TokenserverHttpError(401) => {
-                    ErrorCode::new(error_codes::AUTH_INVALID)
-                },
-                Sync15ErrorKind::RequestError(_) => {
-                    ErrorCode::new(error_codes::NETWORK)
-                }
-                _ => ErrorCode::new(error_codes::UNEXPECTED),
+            let code = sync::ffi::error_code(e);
+            // Keep returning the codes that apps already handle.
+            match code.code() {
+                sync::ffi::error_codes::AUTH_INVALID => ErrorCode::new(error_codes::AUTH_INVALID),
+                sync::ffi::error_codes::NETWORK => ErrorCode::new(error_codes::NETWORK),
+                _ => code,
             }
         }
         ErrorKind::DuplicateGuid(id) => {
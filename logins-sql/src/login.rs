@@ -110,6 +110,25 @@ impl Login {
     }
 }
 
+/// Validates `passwords` records with `sync::validation`: checks that they
+/// deserialize, and pass `Login::check_valid`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoginValidator;
+
+impl sync::CollectionValidator for LoginValidator {
+    fn collection_name(&self) -> &str {
+        "passwords"
+    }
+
+    fn check_record(&self, record: &sync::Payload) -> Option<String> {
+        let login = match record.clone().into_record::<Login>() {
+            Ok(login) => login,
+            Err(e) => return Some(e.to_string()),
+        };
+        login.check_valid().err().map(|e| e.to_string())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct MirrorLogin {
     pub login: Login,
@@ -434,3 +453,53 @@ impl Login {
         delta
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+    use sync::validation::{self, Problem};
+
+    #[test]
+    fn test_validator() {
+        let records: Vec<sync::Payload> = serde_json::from_str(r#"[
+            {
+                "id": "aaaaaaaaaaaa",
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com/login",
+                "password": "p4ssw0rd"
+            },
+            {
+                "id": "bbbbbbbbbbbb",
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com/login",
+                "password": ""
+            },
+            {
+                "id": "cccccccccccc",
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com/login",
+                "httpRealm": "Example",
+                "password": "p4ssw0rd"
+            },
+            { "id": "dddddddddddd", "hostname": 5 }
+        ]"#).unwrap();
+        let report = validation::validate(&LoginValidator, records);
+        assert_eq!(report.collection, "passwords");
+        assert_eq!(report.record_count, 4);
+        assert_eq!(report.problems.len(), 3);
+        assert_eq!(report.problems[0], Problem::InvalidRecord {
+            id: "bbbbbbbbbbbb".into(),
+            reason: "Invalid login: Password is empty".into(),
+        });
+        assert_eq!(report.problems[1], Problem::InvalidRecord {
+            id: "cccccccccccc".into(),
+            reason: "Invalid login: Both `formSubmitUrl` and `httpRealm` are present".into(),
+        });
+        match report.problems[2] {
+            Problem::InvalidRecord { ref id, .. } => assert_eq!(id, "dddddddddddd"),
+            ref problem => panic!("Unexpected problem {:?}", problem),
+        }
+        assert_eq!(report.summary["invalidRecord"], 3);
+    }
+}
@@ -264,6 +264,16 @@ impl EncryptedPayload {
     pub fn serialized_len(&self) -> usize {
         (*EMPTY_ENCRYPTED_PAYLOAD_SIZE) + self.ciphertext.len() + self.hmac.len() + self.iv.len()
     }
+
+    /// Returns the `serialized_len` of a payload with `cleartext_len` bytes
+    /// of JSON, once it's encrypted, without encrypting it. The ciphertext
+    /// is padded to the next AES block, and base64-encoded along with the
+    /// IV; the SHA-256 HMAC is hex-encoded.
+    pub fn serialized_len_for_cleartext(cleartext_len: usize) -> usize {
+        let base64_len = |len: usize| (len + 2) / 3 * 4;
+        let ciphertext_len = (cleartext_len / 16 + 1) * 16;
+        (*EMPTY_ENCRYPTED_PAYLOAD_SIZE) + base64_len(ciphertext_len) + 64 + base64_len(16)
+    }
 }
 
 impl EncryptedBso {
@@ -380,6 +390,21 @@ mod tests {
         assert_eq!(decrypted, orig_record);
     }
 
+    #[test]
+    fn test_serialized_len_for_cleartext() {
+        let keybundle = KeyBundle::new_random().unwrap();
+        // Check either side of the AES block and base64 boundaries.
+        for len in 0..50 {
+            let record = Payload::from_json(json!({ "id": "a", "value": "x".repeat(len) }))
+                .unwrap()
+                .into_bso("dummy".into());
+            let cleartext_len = serde_json::to_string(&record.payload).unwrap().len();
+            let encrypted = record.encrypt(&keybundle).unwrap();
+            assert_eq!(EncryptedPayload::serialized_len_for_cleartext(cleartext_len),
+                       encrypted.payload.serialized_len());
+        }
+    }
+
     #[test]
     fn test_roundtrip_crypt_record() {
         let payload = json!({ "id": "aaaaaaaaaaaa", "age": 105, "meta": "data" });
@@ -2,11 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::collections::HashMap;
+use std::mem;
+
 use bso_record::{EncryptedBso, Payload};
 use client::Sync15StorageClient;
 use error::{self, ErrorKind, Result};
 use key_bundle::KeyBundle;
-use request::{NormalResponseHandler, UploadInfo};
+use request::{CollectionRequest, NormalResponseHandler, RequestOrder, UploadInfo, UploadPolicy};
 use state::GlobalState;
 use util::ServerTimestamp;
 
@@ -55,12 +58,33 @@ impl OutgoingChangeset {
         self,
         client: &Sync15StorageClient,
         state: &GlobalState,
-        fully_atomic: bool,
+        policy: UploadPolicy,
     ) -> Result<UploadInfo> {
-        Ok(CollectionUpdate::new_from_changeset(client, state, self, fully_atomic)?.upload()?)
+        Ok(CollectionUpdate::new_from_changeset(client, state, self, policy)?.upload()?)
     }
 }
 
+/// A page of incoming records, fetched with `IncomingChangeset::fetch_page`.
+#[derive(Debug, Clone)]
+pub struct IncomingPage {
+    pub changeset: IncomingChangeset,
+    /// The offset for the next page, or `None` if this is the last page.
+    pub next_offset: Option<String>,
+    /// The newest timestamp that a store can resume downloading from
+    /// (using it as `since`) without missing any records, once it has
+    /// applied this page. `None` if resuming anywhere in this page could
+    /// miss records.
+    pub high_water_mark: Option<ServerTimestamp>,
+    /// IDs of records that we skipped because they failed HMAC
+    /// verification, even after refetching `crypto/keys`.
+    pub bad_record_ids: Vec<String>,
+    /// True if we refetched `crypto/keys` for this page, and they changed.
+    /// Records from earlier pages were decrypted with the old keys, so
+    /// callers should check `GlobalState::engines_that_need_local_reset`,
+    /// and start over if the collection needs a reset.
+    pub keys_changed: bool,
+}
+
 impl IncomingChangeset {
     pub fn fetch(
         client: &Sync15StorageClient,
@@ -69,12 +93,98 @@ impl IncomingChangeset {
         since: ServerTimestamp,
     ) -> Result<IncomingChangeset> {
         let records = client.get_encrypted_records(&collection, since)?;
+        IncomingChangeset::decrypt(state, collection, records)
+    }
+
+    /// Fetches a page of at most `limit` records changed since `since`,
+    /// oldest first. Pass the `next_offset` of the previous page as `offset`
+    /// to fetch the next one.
+    ///
+    /// If a record fails HMAC verification, we refetch `crypto/keys` (using
+    /// `root_key`, and updating `state`), and try again. Records that still
+    /// fail are skipped, and reported in `bad_record_ids`. If the keys
+    /// changed, the page's `keys_changed` is set.
+    ///
+    /// Every page is pinned to the collection's last modified time in
+    /// `state.collections`. If another client writes to the collection
+    /// after that, this fails with `ConcurrentModification`, since the pages
+    /// we fetch from then on could skip or repeat records. Callers should
+    /// refresh `state.collections` and start over from their last high-water
+    /// mark.
+    pub fn fetch_page(
+        client: &Sync15StorageClient,
+        state: &mut GlobalState,
+        root_key: &KeyBundle,
+        collection: String,
+        since: ServerTimestamp,
+        limit: usize,
+        offset: Option<String>,
+    ) -> Result<IncomingPage> {
+        let page = client.get_encrypted_records_page(
+            CollectionRequest::new(collection.clone())
+                .full()
+                .newer_than(since)
+                .sort_by(RequestOrder::Oldest)
+                .limit(limit)
+                .offset(offset),
+            state.collections.get(&collection).cloned(),
+        ).map_err(|e| match e.kind() {
+            ErrorKind::StorageHttpError { code: 412, .. } => {
+                ErrorKind::ConcurrentModification(collection.clone()).into()
+            }
+            _ => e,
+        })?;
+        let high_water_mark = high_water_mark(&page.records, page.next_offset.is_some());
+
+        let timestamp = state.last_modified_or_zero(&collection);
+        let mut changeset = IncomingChangeset::new(collection, timestamp);
+        changeset.changes.reserve(page.records.len());
+        let mut bad_record_ids = Vec::new();
+        let mut refreshed_keys = false;
+        let mut keys_changed = false;
+        for record in page.records {
+            // Only refetch keys once per page. If the keys didn't change, or
+            // the record still fails with the new keys, the record is bad.
+            let needs_new_keys = !refreshed_keys && {
+                let key = state.key_for_collection(&changeset.collection)?;
+                !key.verify_hmac_string(&record.payload.hmac, &record.payload.ciphertext)?
+            };
+            if needs_new_keys {
+                refreshed_keys = true;
+                keys_changed = state.refresh_keys(client, root_key)?;
+            }
+            let id = record.id.clone();
+            match record.decrypt(state.key_for_collection(&changeset.collection)?) {
+                Ok(decrypted) => changeset.changes.push(decrypted.into_timestamped_payload()),
+                Err(e) => match e.kind() {
+                    ErrorKind::HmacMismatch => {
+                        warn!("Skipping record {} that failed HMAC verification", id);
+                        bad_record_ids.push(id);
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(IncomingPage {
+            changeset,
+            next_offset: page.next_offset,
+            high_water_mark,
+            bad_record_ids,
+            keys_changed,
+        })
+    }
+
+    fn decrypt(
+        state: &GlobalState,
+        collection: String,
+        records: Vec<EncryptedBso>,
+    ) -> Result<IncomingChangeset> {
         let timestamp = state.last_modified_or_zero(&collection);
         let mut result = IncomingChangeset::new(collection, timestamp);
         result.changes.reserve(records.len());
         let key = state.key_for_collection(&result.collection)?;
         for record in records {
-            // TODO: if we see a HMAC error, may need to update crypto/keys?
+            // Note: `fetch_page` recovers from HMAC errors, this doesn't.
             let decrypted = record.decrypt(&key)?;
             result.changes.push(decrypted.into_timestamped_payload());
         }
@@ -82,14 +192,33 @@ impl IncomingChangeset {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Returns the high-water mark for a page of records sorted oldest first.
+/// If there are more pages, the next page might start with more records
+/// that have the same timestamp as the last one in this page, so we can
+/// only resume from the timestamp before that.
+fn high_water_mark(records: &[EncryptedBso], has_more: bool) -> Option<ServerTimestamp> {
+    let last = records.last()?.modified;
+    if !has_more {
+        return Some(last);
+    }
+    records.iter()
+           .rev()
+           .map(|record| record.modified)
+           .find(|modified| *modified < last)
+}
+
+/// Shrinks a record that's too large to upload. See
+/// `CollectionUpdate::shrink_oversized`.
+pub type ShrinkRecord<'b> = Box<Fn(Payload, usize) -> Option<Payload> + 'b>;
+
 pub struct CollectionUpdate<'a, 'b> {
     client: &'a Sync15StorageClient,
     state: &'b GlobalState,
     collection: String,
     xius: ServerTimestamp,
     to_update: Vec<EncryptedBso>,
-    fully_atomic: bool,
+    policy: UploadPolicy,
+    shrink: Option<ShrinkRecord<'b>>,
 }
 
 impl<'a, 'b> CollectionUpdate<'a, 'b> {
@@ -99,7 +228,7 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
         collection: String,
         xius: ServerTimestamp,
         records: Vec<EncryptedBso>,
-        fully_atomic: bool,
+        policy: UploadPolicy,
     ) -> CollectionUpdate<'a, 'b> {
         CollectionUpdate {
             client,
@@ -107,7 +236,8 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
             collection,
             xius,
             to_update: records,
-            fully_atomic,
+            policy,
+            shrink: None,
         }
     }
 
@@ -115,7 +245,7 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
         client: &'a Sync15StorageClient,
         state: &'b GlobalState,
         changeset: OutgoingChangeset,
-        fully_atomic: bool,
+        policy: UploadPolicy,
     ) -> Result<CollectionUpdate<'a, 'b>> {
         let collection = changeset.collection.clone();
         let key_bundle = state.key_for_collection(&collection)?;
@@ -131,35 +261,126 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
             collection,
             xius,
             to_update,
-            fully_atomic,
+            policy,
         ))
     }
 
-    /// Returns a list of the IDs that failed if allowed_dropped_records is true, otherwise
-    /// returns an empty vec.
-    pub fn upload(self) -> error::Result<UploadInfo> {
-        let mut failed = vec![];
+    /// Sets a callback to shrink records that are too large to upload. It's
+    /// called with the record's cleartext payload and the server's
+    /// `max_record_payload_bytes`, and can return a smaller payload to try
+    /// instead, or `None` to give up. If the smaller payload still doesn't
+    /// fit, it's called again with that one, as long as each try is smaller
+    /// than the last.
+    pub fn shrink_oversized<F>(&mut self, shrink: F) -> &mut Self
+        where F: Fn(Payload, usize) -> Option<Payload> + 'b
+    {
+        self.shrink = Some(Box::new(shrink));
+        self
+    }
+
+    /// Uploads the records. With `UploadPolicy::Atomic`, a record that's too
+    /// large to upload, even after shrinking, fails the upload with
+    /// `RecordTooLargeError`, and a record that the server rejected fails it
+    /// with `RecordUploadFailed`. With `UploadPolicy::AllowFailures`, those
+    /// records are skipped, and returned in `UploadInfo::failed`.
+    pub fn upload(mut self) -> error::Result<UploadInfo> {
+        let mut failed = HashMap::new();
         let mut q = self.client.new_post_queue(
             &self.collection,
             &self.state.config,
             self.xius,
-            NormalResponseHandler::new(!self.fully_atomic),
+            NormalResponseHandler::new(self.policy),
         )?;
 
-        for record in self.to_update.into_iter() {
-            let enqueued = q.enqueue(&record)?;
-            if !enqueued && self.fully_atomic {
-                return Err(ErrorKind::RecordTooLargeError.into());
+        let to_update = mem::replace(&mut self.to_update, Vec::new());
+        for mut record in to_update {
+            while !q.enqueue(&record)? {
+                let smaller = match self.shrink {
+                    Some(ref shrink) => self.shrink_record(&**shrink, &record)?,
+                    None => None,
+                };
+                match smaller {
+                    Some(smaller) => record = smaller,
+                    None if self.policy == UploadPolicy::Atomic => {
+                        // We haven't committed the batch, so nothing we
+                        // already sent will be applied, if the server
+                        // supports batches.
+                        warn!("Record {} is too large to upload; failing the upload", record.id);
+                        return Err(ErrorKind::RecordTooLargeError.into());
+                    }
+                    None => {
+                        warn!("Skipping record {} that's too large to upload", record.id);
+                        failed.insert(record.id, "Record too large to upload".into());
+                        break;
+                    }
+                }
             }
         }
 
         q.flush(true)?;
         let mut info = q.completed_upload_info();
-        info.failed_ids.append(&mut failed);
-        if self.fully_atomic {
-            assert_eq!(info.failed_ids.len(), 0,
-                       "Bug: Should have failed by now if we aren't allowing dropped records");
+        // The response handler fails atomic uploads as soon as the server
+        // rejects a record, but check anyway, rather than report success.
+        if self.policy == UploadPolicy::Atomic && !info.failed.is_empty() {
+            warn!("Server rejected {} records in an atomic upload", info.failed.len());
+            return Err(ErrorKind::RecordUploadFailed.into());
         }
+        info.failed.extend(failed);
         Ok(info)
     }
+
+    fn shrink_record(
+        &self,
+        shrink: &Fn(Payload, usize) -> Option<Payload>,
+        record: &EncryptedBso,
+    ) -> error::Result<Option<EncryptedBso>> {
+        let key = self.state.key_for_collection(&self.collection)?;
+        let max_payload_bytes = self.state.config.max_record_payload_bytes;
+        let smaller = match record.clone()
+                                  .decrypt(key)?
+                                  .map_payload_or(|payload| shrink(payload, max_payload_bytes)) {
+            Some(smaller) => smaller.encrypt(key)?,
+            None => return Ok(None),
+        };
+        if smaller.payload.serialized_len() >= record.payload.serialized_len() {
+            warn!("Shrinking record {} didn't make it smaller", record.id);
+            return Ok(None);
+        }
+        Ok(Some(smaller))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bso_record::EncryptedPayload;
+
+    fn records_modified_at(timestamps: &[f64]) -> Vec<EncryptedBso> {
+        timestamps.iter().enumerate().map(|(i, &modified)| EncryptedBso {
+            id: format!("record{:06}", i),
+            collection: "testing".into(),
+            modified: ServerTimestamp(modified),
+            sortindex: None,
+            ttl: None,
+            payload: EncryptedPayload {
+                iv: "iv".into(),
+                hmac: "hmac".into(),
+                ciphertext: "ciphertext".into(),
+            },
+        }).collect()
+    }
+
+    #[test]
+    fn test_high_water_mark() {
+        assert_eq!(high_water_mark(&[], true), None);
+        assert_eq!(high_water_mark(&[], false), None);
+
+        let records = records_modified_at(&[1.0, 2.0, 2.0, 3.0, 3.0]);
+        assert_eq!(high_water_mark(&records, false), Some(ServerTimestamp(3.0)));
+        // The next page might have more records modified at 3.0.
+        assert_eq!(high_water_mark(&records, true), Some(ServerTimestamp(2.0)));
+
+        let records = records_modified_at(&[5.0, 5.0, 5.0]);
+        assert_eq!(high_water_mark(&records, true), None);
+    }
 }
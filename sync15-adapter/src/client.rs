@@ -2,30 +2,51 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::cell::Cell;
-use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
-use hyper::{Method};
-use reqwest::{Client, Request, Response, Url, header::{self, HeaderValue, ACCEPT, AUTHORIZATION}};
+use hyper::{Method, StatusCode};
+use reqwest::{Url, header::{self, HeaderValue, ACCEPT, AUTHORIZATION}};
 use serde;
 use serde_json;
 
 use bso_record::{BsoRecord, EncryptedBso};
 use error::{self, ErrorKind};
+use interrupt::InterruptHandle;
 use record_types::MetaGlobalRecord;
-use request::{BatchPoster, CollectionRequest, InfoConfiguration, PostQueue, PostResponse,
-              PostResponseHandler, X_IF_UNMODIFIED_SINCE, X_WEAVE_TIMESTAMP, InfoCollections};
+use request::{BatchPoster, CollectionRequest, InfoCollectionCounts, InfoCollectionUsage,
+              InfoConfiguration, InfoQuota, PostQueue, PostResponse, PostResponseHandler,
+              X_IF_UNMODIFIED_SINCE, X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET,
+              X_WEAVE_QUOTA_REMAINING, X_WEAVE_TIMESTAMP, InfoCollections};
 use std::str::FromStr;
-use token;
-use util::ServerTimestamp;
+use token::{self, CredentialsProvider, TokenserverCredentials};
+use transport::{HttpRequest, HttpResponse, HttpTransport, ReqwestTransport};
+use util::{self, ServerTimestamp};
+
+const X_WEAVE_BACKOFF: &str = "X-Weave-Backoff";
+const RETRY_AFTER: &str = "Retry-After";
+
+/// How long to back off for if the server returns a 503 without a
+/// `Retry-After` header.
+const DEFAULT_BACKOFF_SECS: u64 = 60;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Sync15StorageClientInit {
-    pub key_id: String,
-    pub access_token: String,
+    pub credentials: TokenserverCredentials,
     pub tokenserver_url: Url,
 }
 
+/// A single page of records from a paged collection request.
+#[derive(Debug, Clone)]
+pub struct EncryptedRecordsPage {
+    pub records: Vec<EncryptedBso>,
+    /// The offset token to pass to `CollectionRequest::offset` to fetch the
+    /// next page, or `None` if this is the last page.
+    pub next_offset: Option<String>,
+}
+
 /// A trait containing the methods required to run through the setup state
 /// machine. This is factored out into a separate trait to make mocking
 /// easier.
@@ -33,20 +54,57 @@ pub trait SetupStorageClient {
     fn fetch_info_configuration(&self) -> error::Result<InfoConfiguration>;
     fn fetch_info_collections(&self) -> error::Result<InfoCollections>;
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>>;
-    fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()>;
+    /// Uploads `global`, and returns its new modified time. If `xius` is
+    /// given, this fails with a 412 if someone else changed `meta/global`
+    /// since then.
+    fn put_meta_global(
+        &self,
+        global: &BsoRecord<MetaGlobalRecord>,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp>;
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso>;
-    fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()>;
+    /// Uploads `keys`, and returns their new modified time. If `xius` is
+    /// given, this fails with a 412 if someone else changed `crypto/keys`
+    /// since then.
+    fn put_crypto_keys(
+        &self,
+        keys: &EncryptedBso,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp>;
     fn wipe_all_remote(&self) -> error::Result<()>;
 }
 
-#[derive(Debug)]
 pub struct Sync15StorageClient {
-    http_client: Client,
+    transport: Box<HttpTransport>,
     // We update this when we make requests
     timestamp: Cell<ServerTimestamp>,
+    // The latest backoff deadline the server asked for, if any.
+    backoff: Cell<Option<SystemTime>>,
+    // The last `X-Weave-Quota-Remaining` we saw, in KB.
+    quota_remaining_kb: Cell<Option<f64>>,
+    // Set when the storage server rejects our token, until the sync manager
+    // notices and restarts the sync.
+    unauthorized: Cell<bool>,
+    // The handle for the sync in progress, which we check before each request.
+    interrupt: RefCell<InterruptHandle>,
     tsc: token::TokenProvider,
 }
 
+// Transports aren't required to implement Debug.
+impl fmt::Debug for Sync15StorageClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sync15StorageClient")
+         .field("transport", &"(omitted)")
+         .field("timestamp", &self.timestamp)
+         .field("backoff", &self.backoff)
+         .field("quota_remaining_kb", &self.quota_remaining_kb)
+         .field("unauthorized", &self.unauthorized)
+         .field("interrupt", &self.interrupt)
+         .field("tsc", &self.tsc)
+         .finish()
+    }
+}
+
 impl SetupStorageClient for Sync15StorageClient {
     fn fetch_info_configuration(&self) -> error::Result<InfoConfiguration> {
         let server_config = self.fetch_info::<InfoConfiguration>("info/configuration")?;
@@ -59,7 +117,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
-        let mut resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
+        let resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
             Ok(r) => Ok(r),
             Err(ref e) if e.is_not_found() => Err(ErrorKind::NoMetaGlobal.into()),
             Err(e) => Err(e)
@@ -70,22 +128,30 @@ impl SetupStorageClient for Sync15StorageClient {
         Ok(meta_global)
     }
 
-    fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
-        self.put("storage/meta/global", None, global)
+    fn put_meta_global(
+        &self,
+        global: &BsoRecord<MetaGlobalRecord>,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp> {
+        self.put("storage/meta/global", xius, global)
     }
 
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
-        let mut keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
+        let keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
         let keys: EncryptedBso = keys_resp.json()?;
         Ok(keys)
     }
 
-    fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()> {
-        self.put("storage/crypto/keys", None, keys)
+    fn put_crypto_keys(
+        &self,
+        keys: &EncryptedBso,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp> {
+        self.put("storage/crypto/keys", xius, keys)
     }
 
     fn wipe_all_remote(&self) -> error::Result<()> {
-        let s = self.tsc.api_endpoint(&self.http_client)?;
+        let s = self.api_endpoint()?;
         let url = Url::parse(&s)?;
 
         let req = self.build_request(Method::DELETE, url)?;
@@ -98,19 +164,44 @@ impl SetupStorageClient for Sync15StorageClient {
 }
 
 impl Sync15StorageClient {
+    /// Creates a storage client that makes requests using `reqwest`.
     pub fn new(init_params: Sync15StorageClientInit) -> error::Result<Sync15StorageClient> {
-        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
-        let tsc = token::TokenProvider::new(
+        let transport = ReqwestTransport::new()?;
+        Ok(Sync15StorageClient::with_transport(init_params, Box::new(transport)))
+    }
+
+    /// Creates a storage client that sends all of its requests, including
+    /// requests to the tokenserver, through `transport`.
+    pub fn with_transport(
+        init_params: Sync15StorageClientInit,
+        transport: Box<HttpTransport>,
+    ) -> Sync15StorageClient {
+        Sync15StorageClient::with_credentials(
             init_params.tokenserver_url,
-            init_params.access_token,
-            init_params.key_id,
-        );
+            Box::new(init_params.credentials),
+            transport,
+        )
+    }
+
+    /// Like `with_transport`, but asks `credentials` for new credentials each
+    /// time we fetch a token, instead of reusing the same ones. Clients that
+    /// use BrowserID assertions, which expire, should use this.
+    pub fn with_credentials(
+        tokenserver_url: Url,
+        credentials: Box<CredentialsProvider>,
+        transport: Box<HttpTransport>,
+    ) -> Sync15StorageClient {
+        let tsc = token::TokenProvider::new(tokenserver_url, credentials);
         let timestamp = ServerTimestamp(0f64);
-        Ok(Sync15StorageClient {
-            http_client: client,
+        Sync15StorageClient {
+            transport,
             timestamp: Cell::new(timestamp),
+            backoff: Cell::new(None),
+            quota_remaining_kb: Cell::new(None),
+            unauthorized: Cell::new(false),
+            interrupt: RefCell::new(InterruptHandle::new()),
             tsc,
-        })
+        }
     }
 
     #[inline]
@@ -118,82 +209,276 @@ impl Sync15StorageClient {
         return self.timestamp.get();
     }
 
+    /// Returns the time before which the server asked us not to make any more
+    /// requests, via `X-Weave-Backoff`, `Retry-After`, or a 503. Callers
+    /// should finish up what they're doing and stop syncing until then. This
+    /// may be in the past if the backoff period has already elapsed.
+    #[inline]
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        self.backoff.get()
+    }
+
+    /// Returns the handle that interrupts this client's requests.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.borrow().clone()
+    }
+
+    /// Replaces the handle that interrupts this client's requests.
+    /// `SyncManager::sync` and `synchronize` call this with the handle for
+    /// each sync, so a client that was interrupted can sync again later.
+    pub fn set_interrupt_handle(&self, interrupt: InterruptHandle) {
+        *self.interrupt.borrow_mut() = interrupt;
+    }
+
+    /// Forces the client to fetch a new token for its next request. If the
+    /// tokenserver moved us to a different storage node, this also
+    /// acknowledges the new node; callers should reset their `GlobalState`
+    /// and all engines first, since the new node won't have any of our data.
+    pub fn drop_token(&self) {
+        self.tsc.drop_token();
+    }
+
+    /// Returns true if the tokenserver assigned us to a different storage
+    /// node. All requests fail with `StorageResetError` until the caller
+    /// calls `drop_token`.
+    pub fn node_reassigned(&self) -> bool {
+        self.tsc.is_node_reassigned()
+    }
+
+    /// Returns the hashed FxA uid for the current token, for the `uid` in a
+    /// telemetry ping. This is `None` if we haven't fetched a token yet.
+    pub fn hashed_uid(&self) -> Option<String> {
+        self.tsc.hashed_fxa_uid()
+    }
+
+    /// Returns true if the storage server returned a 401 since the last call.
+    /// We've already dropped the token in that case, so retrying will fetch
+    /// a new one.
+    pub(crate) fn take_unauthorized(&self) -> bool {
+        self.unauthorized.replace(false)
+    }
+
+    /// Returns how much space the user has left on the server, in KB, as of
+    /// the last write. This is `None` if we haven't written anything yet, or
+    /// if the server doesn't enforce a quota.
+    #[inline]
+    pub fn quota_remaining_kb(&self) -> Option<f64> {
+        self.quota_remaining_kb.get()
+    }
+
+    /// Fetches the user's total usage and quota.
+    pub fn fetch_info_quota(&self) -> error::Result<InfoQuota> {
+        self.fetch_info("info/quota")
+    }
+
+    /// Fetches how much space each collection takes up. This is expensive
+    /// for the server to compute, so callers shouldn't fetch it every sync.
+    pub fn fetch_info_collection_usage(&self) -> error::Result<InfoCollectionUsage> {
+        self.fetch_info("info/collection_usage")
+    }
+
+    /// Fetches the number of records in each collection.
+    pub fn fetch_info_collection_counts(&self) -> error::Result<InfoCollectionCounts> {
+        self.fetch_info("info/collection_counts")
+    }
+
     pub fn get_encrypted_records(
         &self,
         collection: &str,
         since: ServerTimestamp,
     ) -> error::Result<Vec<EncryptedBso>> {
-        let mut resp = self.collection_request(
+        let resp = self.collection_request(
             Method::GET,
             CollectionRequest::new(collection).full().newer_than(since),
         )?;
         Ok(resp.json()?)
     }
 
+    /// Fetches a single page of records. `request` should set a `limit`, and a
+    /// `sort` order so that pages are stable.
+    ///
+    /// If `xius` is given, this fails with a 412 if the collection changed
+    /// since then. Passing the same `xius` for every page makes sure that
+    /// the pages all come from the same view of the collection.
+    pub fn get_encrypted_records_page(
+        &self,
+        request: &CollectionRequest,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<EncryptedRecordsPage> {
+        let url = request.build_url(Url::parse(&self.api_endpoint()?)?)?;
+        let mut req = self.build_request(Method::GET, url)?;
+        if let Some(ts) = xius {
+            req.headers.insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
+        }
+        let resp = self.exec_request(req, true)?;
+        let next_offset = resp.headers
+                              .get(X_WEAVE_NEXT_OFFSET)
+                              .and_then(|v| v.to_str().ok())
+                              .map(|s| s.to_string());
+        Ok(EncryptedRecordsPage {
+            records: resp.json()?,
+            next_offset,
+        })
+    }
+
+    /// Deletes every record in `collection`, leaving other collections alone.
+    /// If `xius` is given, this fails with a 412 if the collection changed
+    /// since then. Returns the server's new last modified time.
+    pub fn delete_collection(
+        &self,
+        collection: &str,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp> {
+        self.delete(&CollectionRequest::new(collection), xius)
+    }
+
+    /// Deletes the records with `ids` from `collection`. The IDs go in the
+    /// URL, so they're split across as many requests as it takes to keep the
+    /// URL short enough for servers and proxies to accept, and to stay under
+    /// the server's limit on IDs per request.
+    ///
+    /// If `xius` is given, this fails with a 412 if the collection changed
+    /// since then. Each request after the first uses the last modified time
+    /// from the one before, so this can fail partway through if another
+    /// client writes to the collection at the same time. Returns the
+    /// collection's new last modified time.
+    pub fn delete_records(
+        &self,
+        collection: &str,
+        ids: &[String],
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp> {
+        let mut xius = xius;
+        let mut last_modified = self.last_server_time();
+        for chunk in chunk_ids(ids, MAX_IDS_QUERY_BYTES) {
+            last_modified = self.delete(CollectionRequest::new(collection).ids(chunk), xius)?;
+            if xius.is_some() {
+                xius = Some(last_modified);
+            }
+        }
+        Ok(last_modified)
+    }
+
+    /// Deletes a single record. If `xius` is given, this fails with a 412 if
+    /// the record changed since then. Returns the collection's new last
+    /// modified time.
+    pub fn delete_record(
+        &self,
+        collection: &str,
+        id: &str,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp> {
+        self.delete(CollectionRequest::new(collection).record(id), xius)
+    }
+
     #[inline]
-    fn authorized(&self, mut req: Request) -> error::Result<Request> {
-        let hawk_header_value = self.tsc.authorization(&self.http_client, &req)?;
-        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?);
+    fn authorized(&self, mut req: HttpRequest) -> error::Result<HttpRequest> {
+        let hawk_header_value = self.tsc.authorization(&*self.transport, &req)?;
+        req.headers.insert(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?);
         Ok(req)
     }
 
     // TODO: probably want a builder-like API to do collection requests (e.g. something
     // that occupies roughly the same conceptual role as the Collection class in desktop)
-    fn build_request(&self, method: Method, url: Url) -> error::Result<Request> {
-        self.authorized(self.http_client
-            .request(method, url)
-            .header(ACCEPT, "application/json")
-            .build()?)
+    fn build_request(&self, method: Method, url: Url) -> error::Result<HttpRequest> {
+        let mut req = HttpRequest::new(method, url);
+        req.headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        self.authorized(req)
     }
 
     fn relative_storage_request<T>(
         &self,
         method: Method,
         relative_path: T,
-    ) -> error::Result<Response>
+    ) -> error::Result<HttpResponse>
     where
         T: AsRef<str>,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
+        let s = self.api_endpoint()? + "/";
         let url = Url::parse(&s)?.join(relative_path.as_ref())?;
         Ok(self.make_storage_request(method, url)?)
     }
 
-    fn make_storage_request(&self, method: Method, url: Url) -> error::Result<Response> {
+    /// Returns the storage node URL from our token, fetching a new token if
+    /// we need one. Fetching blocks, so we check for interruptions first.
+    fn api_endpoint(&self) -> error::Result<String> {
+        self.interrupt.borrow().err_if_interrupted()?;
+        self.tsc.api_endpoint(&*self.transport)
+    }
+
+    fn make_storage_request(&self, method: Method, url: Url) -> error::Result<HttpResponse> {
         // I'm shocked that method isn't Copy...
         Ok(self.exec_request(self.build_request(method.clone(), url)?, true)?)
     }
 
-    fn exec_request(&self, req: Request, require_success: bool) -> error::Result<Response> {
-        let resp = self.http_client.execute(req)?;
+    fn exec_request(&self, req: HttpRequest, require_success: bool) -> error::Result<HttpResponse> {
+        self.interrupt.borrow().err_if_interrupted()?;
+        let resp = self.transport.execute(req)?;
+
+        self.update_timestamp(&resp.headers);
+        self.update_backoff(&resp.headers);
+        self.update_quota_remaining(&resp.headers);
+
+        // The server is overloaded or down for maintenance, so fail
+        // regardless of `require_success`.
+        if resp.status == StatusCode::SERVICE_UNAVAILABLE {
+            warn!("Server unavailable during storage request to {}", resp.url.path());
+            let now = SystemTime::now();
+            let until = match self.backoff.get() {
+                Some(until) if until > now => until,
+                _ => {
+                    let until = now + Duration::from_secs(DEFAULT_BACKOFF_SECS);
+                    self.backoff.set(Some(until));
+                    until
+                }
+            };
+            return Err(ErrorKind::BackoffError(until).into());
+        }
+
+        // Our token expired early, or we were moved to a different node.
+        // Either way, we need a new token, so also fail regardless of
+        // `require_success`.
+        if resp.status == StatusCode::UNAUTHORIZED {
+            warn!("Token rejected during storage request to {}", resp.url.path());
+            self.tsc.drop_token();
+            self.unauthorized.set(true);
+            return Err(ErrorKind::StorageHttpError {
+                code: resp.status.as_u16(),
+                route: resp.url.path().into(),
+            }.into());
+        }
 
-        self.update_timestamp(resp.headers());
+        // Retrying won't help until the user frees up some space, so this is
+        // also always an error.
+        if resp.status == StatusCode::INSUFFICIENT_STORAGE {
+            warn!("Over quota during storage request to {}", resp.url.path());
+            return Err(ErrorKind::QuotaExceeded {
+                route: resp.url.path().into(),
+            }.into());
+        }
 
-        if require_success && !resp.status().is_success() {
+        if require_success && !resp.is_success() {
             error!(
                 "HTTP error {} ({}) during storage request to {}",
-                resp.status().as_u16(),
-                resp.status(),
-                resp.url().path()
+                resp.status.as_u16(),
+                resp.status,
+                resp.url.path()
             );
             return Err(ErrorKind::StorageHttpError {
-                code: resp.status().as_u16(),
-                route: resp.url().path().into(),
+                code: resp.status.as_u16(),
+                route: resp.url.path().into(),
             }.into());
         }
 
-        // TODO:
-        // - handle backoff
-        // - x-weave-quota?
-        // - ... almost certainly other things too...
+        // TODO: ... almost certainly other things too...
 
         Ok(resp)
     }
 
-    fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<Response> {
+    fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<HttpResponse> {
         self.make_storage_request(
             method.clone(),
-            r.build_url(Url::parse(&self.tsc.api_endpoint(&self.http_client)?)?)?,
+            r.build_url(Url::parse(&self.api_endpoint()?)?)?,
         )
     }
 
@@ -201,7 +486,7 @@ impl Sync15StorageClient {
     where
         for<'a> T: serde::de::Deserialize<'a>,
     {
-        let mut resp = self.relative_storage_request(Method::GET, path)?;
+        let resp = self.relative_storage_request(Method::GET, path)?;
         let result: T = resp.json()?;
         Ok(result)
     }
@@ -215,6 +500,32 @@ impl Sync15StorageClient {
         }
     }
 
+    // Both headers can be in seconds, and `Retry-After` can also be a date.
+    // If the server sends both, or a request already asked for a longer
+    // backoff, we use the longest.
+    fn update_backoff(&self, hm: &header::HeaderMap) {
+        let now = SystemTime::now();
+        let requested = [X_WEAVE_BACKOFF, RETRY_AFTER].iter()
+            .filter_map(|name| hm.get(*name))
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|s| util::parse_backoff(s, now))
+            .max();
+        if let Some(until) = requested {
+            warn!("Server requested backoff for {:?}",
+                  until.duration_since(now).unwrap_or_default());
+            self.backoff.set(cmp::max(self.backoff.get(), Some(until)));
+        }
+    }
+
+    fn update_quota_remaining(&self, hm: &header::HeaderMap) {
+        let remaining = hm.get(X_WEAVE_QUOTA_REMAINING)
+                          .and_then(|v| v.to_str().ok())
+                          .and_then(|s| s.trim().parse::<f64>().ok());
+        if let Some(kb) = remaining {
+            self.quota_remaining_kb.set(Some(kb));
+        }
+    }
+
     pub fn new_post_queue<'a, F: PostResponseHandler>(
         &'a self,
         coll: &str,
@@ -234,28 +545,86 @@ impl Sync15StorageClient {
         relative_path: P,
         xius: Option<ServerTimestamp>,
         body: &B,
-    ) -> error::Result<()>
+    ) -> error::Result<ServerTimestamp>
     where
         P: AsRef<str>,
         B: serde::ser::Serialize,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
+        let s = self.api_endpoint()? + "/";
         let url = Url::parse(&s)?.join(relative_path.as_ref())?;
 
         let bytes = serde_json::to_vec(body)?;
 
         let mut req = self.build_request(Method::PUT, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        req.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
         if let Some(ts) = xius {
-            req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
+            req.headers.insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
         }
-        *req.body_mut() = Some(bytes.into());
-        let _ = self.exec_request(req, true)?;
+        req.body = Some(bytes);
+        let resp = self.exec_request(req, true)?;
+        Ok(self.last_modified(&resp))
+    }
 
-        Ok(())
+    fn delete(
+        &self,
+        request: &CollectionRequest,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<ServerTimestamp> {
+        let url = request.build_url(Url::parse(&self.api_endpoint()?)?)?;
+        let mut req = self.build_request(Method::DELETE, url)?;
+        if let Some(ts) = xius {
+            req.headers.insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
+        }
+        match self.exec_request(req, true) {
+            Ok(resp) => Ok(self.last_modified(&resp)),
+            // Already deleted.
+            Err(ref e) if e.is_not_found() => Ok(self.last_server_time()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The new modified time after a write is in `X-Last-Modified`, which
+    // should match `X-Weave-Timestamp`.
+    fn last_modified(&self, resp: &HttpResponse) -> ServerTimestamp {
+        resp.headers
+            .get(X_LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| ServerTimestamp::from_str(s).ok())
+            .unwrap_or_else(|| self.last_server_time())
     }
 }
 
+/// The most IDs the server accepts in a single `ids` query parameter.
+const MAX_IDS_PER_REQUEST: usize = 100;
+
+/// The longest `ids` query parameter we send. Many servers and proxies limit
+/// URLs to 8KB, and this leaves room for the rest of the URL.
+const MAX_IDS_QUERY_BYTES: usize = 7 * 1024;
+
+// Splits `ids` into chunks whose `ids` query parameters fit in `max_bytes`,
+// assuming every comma is percent-encoded. Every chunk has at least one ID,
+// even if that ID alone is too big.
+fn chunk_ids(ids: &[String], max_bytes: usize) -> Vec<&[String]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+    for (i, id) in ids.iter().enumerate() {
+        // "%2C" before every ID but the first.
+        let id_bytes = if i == start { id.len() } else { id.len() + 3 };
+        if i > start && (bytes + id_bytes > max_bytes || i - start >= MAX_IDS_PER_REQUEST) {
+            chunks.push(&ids[start..i]);
+            start = i;
+            bytes = id.len();
+        } else {
+            bytes += id_bytes;
+        }
+    }
+    if start < ids.len() {
+        chunks.push(&ids[start..]);
+    }
+    chunks
+}
+
 pub struct PostWrapper<'a> {
     client: &'a Sync15StorageClient,
     coll: String,
@@ -273,17 +642,132 @@ impl<'a> BatchPoster for PostWrapper<'a> {
         let url = CollectionRequest::new(self.coll.clone())
             .batch(batch)
             .commit(commit)
-            .build_url(Url::parse(&self.client
-                .tsc
-                .api_endpoint(&self.client.http_client)?)?)?;
+            .build_url(Url::parse(&self.client.api_endpoint()?)?)?;
 
         let mut req = self.client.build_request(Method::POST, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?);
+        req.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        req.headers.insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?);
         // It's very annoying that we need to copy the body here, the request
         // shouldn't need to take ownership of it...
-        *req.body_mut() = Some(Vec::from(bytes).into());
-        let mut resp = self.client.exec_request(req, false)?;
-        Ok(PostResponse::from_response(&mut resp)?)
+        req.body = Some(Vec::from(bytes));
+        let resp = self.client.exec_request(req, false)?;
+        Ok(PostResponse::from_response(&resp)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use transport::{HeaderMap, StatusCode};
+
+    // Answers tokenserver and `info/collections` requests, and remembers
+    // every request it was asked to make.
+    #[derive(Clone, Default)]
+    struct FakeTransport {
+        requests: Rc<RefCell<Vec<HttpRequest>>>,
+    }
+
+    impl HttpTransport for FakeTransport {
+        fn execute(&self, req: HttpRequest) -> error::Result<HttpResponse> {
+            self.requests.borrow_mut().push(req.clone());
+            let mut headers = HeaderMap::new();
+            let body = match req.url.path() {
+                "/1.0/sync/1.5" => {
+                    headers.insert("X-Timestamp", HeaderValue::from_static("1300"));
+                    json!({
+                        "id": "id",
+                        "key": "key",
+                        "api_endpoint": "https://storage.example.com/1.5/12345",
+                        "uid": 12345,
+                        "duration": 300,
+                        "hashed_fxa_uid": "hash",
+                    })
+                }
+                "/1.5/12345/info/collections" => {
+                    headers.insert(X_WEAVE_TIMESTAMP, HeaderValue::from_static("1300.25"));
+                    json!({ "passwords": 1234.56 })
+                }
+                path => panic!("Unexpected request to {}", path),
+            };
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers,
+                body: serde_json::to_vec(&body)?,
+                url: req.url,
+            })
+        }
+    }
+
+    #[test]
+    fn test_requests_use_transport() {
+        let transport = FakeTransport::default();
+        let client = Sync15StorageClient::with_transport(
+            Sync15StorageClientInit {
+                credentials: TokenserverCredentials::OAuth {
+                    access_token: "access".into(),
+                    key_id: "kid".into(),
+                },
+                tokenserver_url: Url::parse("https://token.example.com/1.0/sync/1.5").unwrap(),
+            },
+            Box::new(transport.clone()),
+        );
+
+        let collections = client.fetch_info_collections().expect("should fetch info/collections");
+        assert_eq!(collections.get("passwords"), Some(&ServerTimestamp(1234.56)));
+        assert_eq!(client.last_server_time(), ServerTimestamp(1300.25));
+
+        let requests = transport.requests.borrow();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers.get(AUTHORIZATION).unwrap(), "Bearer access");
+        assert_eq!(requests[0].headers.get("X-KeyID").unwrap(), "kid");
+        assert!(requests[1].headers.get(AUTHORIZATION).unwrap()
+                                   .to_str().unwrap().starts_with("Hawk "));
+    }
+
+    #[test]
+    fn test_browserid_credentials() {
+        let transport = FakeTransport::default();
+        // Each token needs a new assertion.
+        let assertions = Cell::new(0);
+        let client = Sync15StorageClient::with_credentials(
+            Url::parse("https://token.example.com/1.0/sync/1.5").unwrap(),
+            Box::new(move || {
+                assertions.set(assertions.get() + 1);
+                Ok(TokenserverCredentials::BrowserID {
+                    assertion: format!("assertion{}", assertions.get()),
+                    client_state: "abcdef".into(),
+                })
+            }),
+            Box::new(transport.clone()),
+        );
+
+        client.fetch_info_collections().expect("should fetch info/collections");
+        client.drop_token();
+        client.fetch_info_collections().expect("should fetch a new token");
+
+        let requests = transport.requests.borrow();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].headers.get(AUTHORIZATION).unwrap(), "BrowserID assertion1");
+        assert_eq!(requests[0].headers.get("X-Client-State").unwrap(), "abcdef");
+        assert!(requests[0].headers.get("X-KeyID").is_none());
+        assert_eq!(requests[2].headers.get(AUTHORIZATION).unwrap(), "BrowserID assertion2");
+    }
+
+    #[test]
+    fn test_chunk_ids() {
+        let ids = (0..5).map(|i| format!("id{}", i)).collect::<Vec<_>>();
+        // "id0%2Cid1" is 9 bytes, and "id0%2Cid1%2Cid2" is 15.
+        let chunks = chunk_ids(&ids, 14);
+        assert_eq!(chunks, vec![&ids[0..2], &ids[2..4], &ids[4..5]]);
+        assert_eq!(chunk_ids(&ids, 1000), vec![&ids[..]]);
+        // IDs that are too long still get their own chunk.
+        assert_eq!(chunk_ids(&ids, 1).len(), 5);
+        assert!(chunk_ids(&[], 1000).is_empty());
+
+        let many = (0..250).map(|i| format!("{:03}", i)).collect::<Vec<_>>();
+        let chunks = chunk_ids(&many, usize::max_value());
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![100, 100, 50]);
     }
 }
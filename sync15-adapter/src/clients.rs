@@ -0,0 +1,404 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The clients engine. Every client uploads a record describing itself to the
+//! `clients` collection, which other devices use to show a list of connected
+//! devices, and to send commands (like "wipe your bookmarks") to each other.
+//!
+//! Unlike other engines, the clients engine doesn't have a local store. Apps
+//! describe the local client and handle incoming commands by implementing
+//! `CommandProcessor`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde_json::{Map, Value as JsonValue};
+
+use changeset::{CollectionUpdate, IncomingChangeset};
+use client::Sync15StorageClient;
+use error;
+use request::UploadPolicy;
+use state::GlobalState;
+use telemetry;
+use typed_store::{IncomingKind, IncomingRecord, OutgoingRecord, SyncRecord};
+use util::{ServerTimestamp, SERVER_EPOCH};
+
+const COLLECTION_NAME: &str = "clients";
+
+/// How long our record lives on the server, in seconds. If we stop syncing,
+/// the server expires our record, and we drop out of other devices' lists.
+const CLIENTS_TTL: u32 = 21 * 24 * 60 * 60;
+
+/// How often we reupload our record if it hasn't changed, so that it doesn't
+/// expire while we're still syncing, in seconds.
+const CLIENTS_TTL_REFRESH: u64 = 7 * 24 * 60 * 60;
+
+/// The Sync protocol versions we speak.
+const PROTOCOLS: &[&str] = &["1.5"];
+
+/// The kind of device a client runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    VR,
+    TV,
+}
+
+impl DeviceType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+            DeviceType::Tablet => "tablet",
+            DeviceType::VR => "vr",
+            DeviceType::TV => "tv",
+        }
+    }
+
+    /// Returns `None` for device types we don't know about.
+    pub fn parse(s: &str) -> Option<DeviceType> {
+        Some(match s {
+            "desktop" => DeviceType::Desktop,
+            "mobile" => DeviceType::Mobile,
+            "tablet" => DeviceType::Tablet,
+            "vr" => DeviceType::VR,
+            "tv" => DeviceType::TV,
+            _ => return None,
+        })
+    }
+}
+
+/// A client record, as stored in the `clients` collection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientRecord {
+    pub id: String,
+
+    pub name: String,
+
+    // Kept as a string, so that we don't fail to parse records from devices
+    // with types we don't know about.
+    #[serde(rename = "type")]
+    pub typ: String,
+
+    #[serde(default)]
+    pub commands: Vec<CommandRecord>,
+
+    #[serde(rename = "fxaDeviceId", default, skip_serializing_if = "Option::is_none")]
+    pub fxa_device_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<String>,
+}
+
+impl SyncRecord for ClientRecord {
+    const COLLECTION: &'static str = COLLECTION_NAME;
+    const STORAGE_VERSION: usize = 1;
+
+    fn record_id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A command, as stored in a client record. Other clients append these to our
+/// record, and we remove them once we've processed them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub command: String,
+
+    #[serde(default)]
+    pub args: Vec<JsonValue>,
+
+    #[serde(rename = "flowID", default, skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
+}
+
+/// A command sent to us by another client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Wipe all local data for the named engine.
+    Wipe(String),
+    /// Reset the named engine's sync state, so that it syncs everything
+    /// again, merging with what's on the server.
+    Reset(String),
+    /// Reset every engine.
+    ResetAll,
+    /// Show a URI that another device sent us.
+    DisplayUri {
+        uri: String,
+        /// The client ID of the device that sent the URI.
+        sender_id: Option<String>,
+        title: Option<String>,
+    },
+}
+
+impl Command {
+    /// Returns `None` for commands we don't know about, or with missing
+    /// arguments.
+    pub fn from_record(record: &CommandRecord) -> Option<Command> {
+        let arg = |index: usize| {
+            record.args.get(index).and_then(JsonValue::as_str).map(String::from)
+        };
+        match record.command.as_str() {
+            "wipeEngine" => arg(0).map(Command::Wipe),
+            "resetEngine" => arg(0).map(Command::Reset),
+            "resetAll" => Some(Command::ResetAll),
+            "displayURI" => arg(0).map(|uri| Command::DisplayUri {
+                uri,
+                sender_id: arg(1),
+                title: arg(2),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// What the app did with an incoming command. We remove commands from our
+/// record however they were handled, so that we don't process them again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    Applied,
+    /// The command was valid, but there was nothing to do, like wiping an
+    /// engine that doesn't exist locally.
+    Ignored,
+    /// The app doesn't support the command.
+    Unsupported,
+}
+
+/// Describes the local client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// A stable ID for this client, used as the ID of our record. Apps should
+    /// generate it once (for example, with `util::random_guid`) and persist
+    /// it, since changing it makes us show up twice in other devices' lists
+    /// until the old record expires.
+    pub client_id: String,
+    /// The Firefox Accounts device ID for this client, if it has one.
+    pub fxa_device_id: Option<String>,
+    pub name: String,
+    pub device_type: DeviceType,
+}
+
+/// Implemented by the app to describe the local client and handle commands
+/// from other clients.
+pub trait CommandProcessor {
+    fn settings(&self) -> &Settings;
+
+    /// Handles an incoming command. Commands are passed in the order they
+    /// were sent.
+    fn apply_incoming_command(&self, command: Command) -> CommandStatus;
+}
+
+/// Another client, as we saw it in our last sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteClient {
+    pub id: String,
+    pub fxa_device_id: Option<String>,
+    pub name: String,
+    /// `None` if the client's device type is one we don't know about.
+    pub device_type: Option<DeviceType>,
+    /// When the client last uploaded its record.
+    pub last_modified: ServerTimestamp,
+}
+
+/// Syncs our client record, and processes our incoming commands.
+pub struct Engine<'a> {
+    command_processor: &'a CommandProcessor,
+    recent_clients: RefCell<HashMap<String, RemoteClient>>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(command_processor: &'a CommandProcessor) -> Engine<'a> {
+        Engine {
+            command_processor,
+            recent_clients: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the settings for the local client.
+    pub fn settings(&self) -> &Settings {
+        self.command_processor.settings()
+    }
+
+    /// Returns all other clients with unexpired records, keyed by client ID,
+    /// as of the last sync.
+    pub fn recent_clients(&self) -> HashMap<String, RemoteClient> {
+        self.recent_clients.borrow().clone()
+    }
+
+    /// Fetches all client records, dispatches the commands in our record to
+    /// the `CommandProcessor`, and uploads our record if it's missing, out
+    /// of date, or about to expire. `state` must be ready. If `needs_reset`
+    /// is true, because our sync ID or keys changed, or we moved to a new
+    /// node, we forget the clients we know about, and always upload.
+    ///
+    /// The clients collection is small, so we always fetch all of it, and
+    /// don't track a last sync time.
+    pub fn sync(
+        &self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        needs_reset: bool,
+        telem_engine: &mut telemetry::Engine,
+    ) -> error::Result<()> {
+        let settings = self.command_processor.settings();
+        if needs_reset {
+            info!("Resetting clients engine");
+            self.recent_clients.borrow_mut().clear();
+        }
+        let inbound = IncomingChangeset::fetch(
+            client,
+            state,
+            COLLECTION_NAME.into(),
+            SERVER_EPOCH,
+        )?;
+
+        let mut xius = inbound.timestamp;
+        let mut current = None;
+        let mut recent_clients = HashMap::new();
+        for (payload, modified) in inbound.changes {
+            if modified > xius {
+                xius = modified;
+            }
+            match IncomingRecord::<ClientRecord>::from_payload(payload, modified).kind {
+                IncomingKind::Record { record, unknown_fields } => {
+                    telem_engine.incoming().applied(1);
+                    if record.id == settings.client_id {
+                        current = Some((record, unknown_fields, modified));
+                    } else {
+                        recent_clients.insert(record.id.clone(), RemoteClient {
+                            device_type: DeviceType::parse(&record.typ),
+                            id: record.id,
+                            fxa_device_id: record.fxa_device_id,
+                            name: record.name,
+                            last_modified: modified,
+                        });
+                    }
+                }
+                IncomingKind::Tombstone => {}
+                IncomingKind::Malformed { payload, .. } => {
+                    warn!("Skipping malformed client record {}", payload.id);
+                    telem_engine.incoming().failed(1);
+                }
+            }
+        }
+        *self.recent_clients.borrow_mut() = recent_clients;
+
+        let local = ClientRecord {
+            id: settings.client_id.clone(),
+            name: settings.name.clone(),
+            typ: settings.device_type.as_str().into(),
+            commands: Vec::new(),
+            fxa_device_id: settings.fxa_device_id.clone(),
+            protocols: PROTOCOLS.iter().map(|p| p.to_string()).collect(),
+        };
+        let (unknown_fields, needs_upload) = match current {
+            Some((mut remote, unknown_fields, modified)) => {
+                let had_commands = !remote.commands.is_empty();
+                for command in remote.commands.drain(..) {
+                    self.apply_command(&command);
+                }
+                let is_stale = client
+                    .last_server_time()
+                    .duration_since(modified)
+                    .map(|age| age.as_secs() >= CLIENTS_TTL_REFRESH)
+                    .unwrap_or(false);
+                (unknown_fields, had_commands || is_stale || remote != local)
+            }
+            None => (Map::new(), true),
+        };
+        if !needs_upload && !needs_reset {
+            info!("Our client record is up to date");
+            return Ok(());
+        }
+
+        info!("Uploading our client record");
+        let payload = OutgoingRecord::Record {
+            record: local,
+            unknown_fields,
+        }.into_payload()?;
+        let mut bso = payload.into_bso(COLLECTION_NAME.into());
+        bso.ttl = Some(CLIENTS_TTL);
+        let encrypted = bso.encrypt(state.key_for_collection(COLLECTION_NAME)?)?;
+        let info = CollectionUpdate::new(
+            client,
+            state,
+            COLLECTION_NAME.into(),
+            xius,
+            vec![encrypted],
+            UploadPolicy::Atomic,
+        ).upload()?;
+        telem_engine.outgoing(telemetry::EngineOutgoing::new(
+            info.successful_ids.len() as u32,
+            info.failed.len() as u32,
+        ));
+        Ok(())
+    }
+
+    fn apply_command(&self, record: &CommandRecord) {
+        let command = match Command::from_record(record) {
+            Some(command) => command,
+            None => {
+                warn!("Ignoring unknown or malformed command {}", record.command);
+                return;
+            }
+        };
+        match self.command_processor.apply_incoming_command(command) {
+            CommandStatus::Applied => info!("Applied command {}", record.command),
+            CommandStatus::Ignored => info!("Ignored command {}", record.command),
+            CommandStatus::Unsupported => warn!("Unsupported command {}", record.command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn command(value: JsonValue) -> Option<Command> {
+        Command::from_record(&serde_json::from_value(value).unwrap())
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(command(json!({ "command": "wipeEngine", "args": ["bookmarks"] })),
+                   Some(Command::Wipe("bookmarks".into())));
+        assert_eq!(command(json!({ "command": "resetEngine", "args": ["passwords"] })),
+                   Some(Command::Reset("passwords".into())));
+        assert_eq!(command(json!({ "command": "resetAll", "args": [] })),
+                   Some(Command::ResetAll));
+        assert_eq!(command(json!({ "command": "resetAll" })), Some(Command::ResetAll));
+        assert_eq!(command(json!({
+            "command": "displayURI",
+            "args": ["https://example.com", "aaaaaaaaaaaa", "Example"],
+            "flowID": "flow",
+        })), Some(Command::DisplayUri {
+            uri: "https://example.com".into(),
+            sender_id: Some("aaaaaaaaaaaa".into()),
+            title: Some("Example".into()),
+        }));
+        assert_eq!(command(json!({
+            "command": "displayURI",
+            "args": ["https://example.com", "aaaaaaaaaaaa", null],
+        })), Some(Command::DisplayUri {
+            uri: "https://example.com".into(),
+            sender_id: Some("aaaaaaaaaaaa".into()),
+            title: None,
+        }));
+
+        assert_eq!(command(json!({ "command": "wipeEngine", "args": [] })), None);
+        assert_eq!(command(json!({ "command": "logout", "args": [] })), None);
+    }
+
+    #[test]
+    fn test_device_type() {
+        for device_type in &[DeviceType::Desktop, DeviceType::Mobile, DeviceType::Tablet,
+                             DeviceType::VR, DeviceType::TV] {
+            assert_eq!(DeviceType::parse(device_type.as_str()), Some(*device_type));
+        }
+        assert_eq!(DeviceType::parse("toaster"), None);
+    }
+}
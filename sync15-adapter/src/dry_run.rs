@@ -0,0 +1,214 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Dry runs, for debugging reports like "sync is deleting my passwords". A
+//! dry run downloads and decrypts incoming records like a normal sync, and
+//! asks the store what it would change locally and upload, but the store
+//! doesn't keep any of its changes, and we don't write anything to the
+//! server.
+
+use serde_json::{Map, Value as JsonValue};
+
+use changeset::{IncomingChangeset, OutgoingChangeset};
+use client::Sync15StorageClient;
+use error::{self, ErrorKind};
+use key_bundle::KeyBundle;
+use state::GlobalState;
+use sync::{Store, DOWNLOAD_PAGE_SIZE};
+use telemetry;
+use util::ServerTimestamp;
+
+/// What a store would do with incoming records, returned by
+/// `Store::dry_run_incoming`.
+#[derive(Debug, Clone)]
+pub struct DryRunChanges {
+    /// Records that would change locally.
+    pub local: Vec<LocalChange>,
+    /// Records that we'd upload, like `Store::apply_incoming` returns.
+    pub outgoing: OutgoingChangeset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A record that would change locally.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocalChange {
+    pub id: String,
+    pub kind: LocalChangeKind,
+    /// The fields that would change, if the store can tell us. Stores should
+    /// leave out or redact the values of sensitive fields, like passwords.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldChange>,
+}
+
+/// A field that would change. `old` is `None` if the field would be added,
+/// and `new` is `None` if it would be removed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub name: String,
+    pub old: Option<JsonValue>,
+    pub new: Option<JsonValue>,
+}
+
+impl LocalChange {
+    /// Compares two versions of a record, field by field, where `None` means
+    /// the record doesn't exist. Returns `None` if nothing would change.
+    pub fn between(
+        id: String,
+        old: Option<&Map<String, JsonValue>>,
+        new: Option<&Map<String, JsonValue>>,
+    ) -> Option<LocalChange> {
+        let kind = match (old, new) {
+            (None, None) => return None,
+            (None, Some(_)) => LocalChangeKind::Insert,
+            (Some(_), None) => LocalChangeKind::Delete,
+            (Some(_), Some(_)) => LocalChangeKind::Update,
+        };
+        let empty = Map::new();
+        let (old, new) = (old.unwrap_or(&empty), new.unwrap_or(&empty));
+        let mut fields = old.iter()
+            .filter(|(name, value)| new.get(*name) != Some(value))
+            .map(|(name, value)| FieldChange {
+                name: name.clone(),
+                old: Some(value.clone()),
+                new: new.get(name).cloned(),
+            })
+            .chain(new.iter().filter(|(name, _)| !old.contains_key(*name)).map(|(name, value)| {
+                FieldChange {
+                    name: name.clone(),
+                    old: None,
+                    new: Some(value.clone()),
+                }
+            }))
+            .collect::<Vec<_>>();
+        if kind == LocalChangeKind::Update && fields.is_empty() {
+            return None;
+        }
+        fields.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(LocalChange { id, kind, fields })
+    }
+}
+
+/// The outcome of a dry run. Serializes to JSON, so that support engineers
+/// can ask users to send it to them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DryRunReport {
+    pub collection: String,
+    /// IDs of the incoming records we downloaded and decrypted.
+    pub incoming_ids: Vec<String>,
+    /// IDs of incoming records that we couldn't decrypt, even after
+    /// refetching `crypto/keys`.
+    pub bad_incoming_ids: Vec<String>,
+    /// The store's incoming counts, as they'd be reported in telemetry.
+    pub incoming_counts: telemetry::EngineIncoming,
+    /// Records that would change locally.
+    pub local_changes: Vec<LocalChange>,
+    /// IDs of the records we'd upload, including tombstones.
+    pub outgoing_ids: Vec<String>,
+    /// IDs of the tombstones we'd upload, deleting those records on other
+    /// devices.
+    pub outgoing_tombstone_ids: Vec<String>,
+}
+
+/// Does a dry run of `synchronize` for `store`'s collection: downloads
+/// everything that changed since `timestamp`, and reports what the store
+/// would change locally, and what we'd upload, without changing the store
+/// or the server. `state` must already be ready, and is updated if we
+/// refetch `crypto/keys`.
+///
+/// Unlike a real sync, the store gets all incoming records at once, since it
+/// doesn't apply earlier pages. Stores that don't implement
+/// `Store::dry_run_incoming` fail with `ErrorKind::DryRunUnsupported`.
+pub fn dry_run<E>(
+    client: &Sync15StorageClient,
+    state: &mut GlobalState,
+    root_key: &KeyBundle,
+    store: &Store<Error = E>,
+    timestamp: ServerTimestamp,
+) -> Result<DryRunReport, E>
+where
+    E: From<error::Error>,
+{
+    let collection = store.collection_name();
+    info!("Dry run for collection {}", collection);
+    let mut report = DryRunReport {
+        collection: collection.into(),
+        ..DryRunReport::default()
+    };
+    let mut inbound = IncomingChangeset::new(collection.into(), timestamp);
+    let mut offset = None;
+    loop {
+        let mut page = IncomingChangeset::fetch_page(client, state, root_key, collection.into(),
+                                                     timestamp, DOWNLOAD_PAGE_SIZE, offset)?;
+        report.bad_incoming_ids.append(&mut page.bad_record_ids);
+        inbound.timestamp = page.changeset.timestamp;
+        inbound.changes.append(&mut page.changeset.changes);
+        offset = page.next_offset;
+        if offset.is_none() {
+            break;
+        }
+    }
+    info!("Downloaded {} remote changes", inbound.changes.len());
+    report.incoming_ids = inbound.changes.iter().map(|(payload, _)| payload.id.clone()).collect();
+    report.incoming_counts.failed(report.bad_incoming_ids.len() as u32);
+
+    let changes = match store.dry_run_incoming(inbound, &mut report.incoming_counts)? {
+        Some(changes) => changes,
+        None => {
+            let err = error::Error::from(ErrorKind::DryRunUnsupported(collection.into()));
+            return Err(err.into());
+        }
+    };
+    report.local_changes = changes.local;
+    for payload in changes.outgoing.changes {
+        if payload.is_tombstone() {
+            report.outgoing_tombstone_ids.push(payload.id.clone());
+        }
+        report.outgoing_ids.push(payload.id);
+    }
+    info!("Dry run finished: {} local changes, {} outgoing changes",
+          report.local_changes.len(), report.outgoing_ids.len());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn fields(value: JsonValue) -> Map<String, JsonValue> {
+        match value {
+            JsonValue::Object(map) => map,
+            _ => panic!("Not an object"),
+        }
+    }
+
+    #[test]
+    fn test_local_change_between() {
+        let old = fields(json!({ "a": 1, "b": "same", "c": true }));
+        let new = fields(json!({ "a": 2, "b": "same", "d": null }));
+        let change = LocalChange::between("id".into(), Some(&old), Some(&new)).unwrap();
+        assert_eq!(change.kind, LocalChangeKind::Update);
+        assert_eq!(change.fields, vec![
+            FieldChange { name: "a".into(), old: Some(json!(1)), new: Some(json!(2)) },
+            FieldChange { name: "c".into(), old: Some(json!(true)), new: None },
+            FieldChange { name: "d".into(), old: None, new: Some(json!(null)) },
+        ]);
+
+        assert_eq!(LocalChange::between("id".into(), Some(&old), Some(&old)), None);
+        assert_eq!(LocalChange::between("id".into(), None, None), None);
+
+        let deleted = LocalChange::between("id".into(), Some(&old), None).unwrap();
+        assert_eq!(deleted.kind, LocalChangeKind::Delete);
+        assert_eq!(deleted.fields.len(), 3);
+        assert!(deleted.fields.iter().all(|field| field.new.is_none()));
+        assert_eq!(serde_json::to_value(&deleted).unwrap()["kind"], "delete");
+    }
+}
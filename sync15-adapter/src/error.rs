@@ -48,6 +48,79 @@ impl Error {
             _ => false
         }
     }
+
+    /// Returns true if the server rejected a write because the user is over
+    /// their storage quota.
+    pub fn is_quota_exceeded(&self) -> bool {
+        match self.kind() {
+            ErrorKind::QuotaExceeded { .. } => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if the sync was stopped with an `InterruptHandle`.
+    pub fn is_interrupted(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Interrupted => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if we couldn't reach the tokenserver or storage server,
+    /// or if it had a problem (a 5xx status).
+    pub fn is_network_error(&self) -> bool {
+        match self.kind() {
+            ErrorKind::RequestError(_) => true,
+            ErrorKind::TokenserverHttpError(code) |
+            ErrorKind::StorageHttpError { code, .. } => *code >= 500,
+            _ => false
+        }
+    }
+
+    /// Returns true if the tokenserver rejected our credentials, so the user
+    /// needs to sign in again. A 401 from the storage server isn't an auth
+    /// error: it means our token expired, or our clock is off, and syncing
+    /// again fetches a new token.
+    pub fn is_auth_error(&self) -> bool {
+        match self.kind() {
+            ErrorKind::TokenserverHttpError(401) |
+            ErrorKind::TokenserverHttpError(403) => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if syncing again later might succeed without the user
+    /// doing anything. See `retry_after` for when.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind() {
+            ErrorKind::BackoffError(_) |
+            ErrorKind::Interrupted |
+            ErrorKind::BatchInterrupted |
+            ErrorKind::ConcurrentModification(_) |
+            ErrorKind::StorageHttpError { code: 401, .. } => true,
+            _ => self.is_network_error()
+        }
+    }
+
+    /// Returns the time before which we shouldn't retry, if the server asked
+    /// us to back off.
+    pub fn retry_after(&self) -> Option<SystemTime> {
+        match self.kind() {
+            ErrorKind::BackoffError(until) => Some(*until),
+            _ => None
+        }
+    }
+
+    /// Returns true if syncing won't succeed until the user does something,
+    /// like signing in again, upgrading the app, or freeing up space on the
+    /// server.
+    pub fn requires_user_action(&self) -> bool {
+        match self.kind() {
+            ErrorKind::ClientUpgradeRequired |
+            ErrorKind::QuotaExceeded { .. } => true,
+            _ => self.is_auth_error()
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -78,6 +151,12 @@ pub enum ErrorKind {
     #[fail(display = "HTTP status {} during a storage request to \"{}\"", code, route)]
     StorageHttpError { code: u16, route: String },
 
+    /// The server returned a 507 because the user is over their storage
+    /// quota. Stores can avoid this by uploading less, like dropping old
+    /// history; see `Sync15StorageClient::fetch_info_quota`.
+    #[fail(display = "Over quota during a storage request to \"{}\"", route)]
+    QuotaExceeded { route: String },
+
     #[fail(display = "Server requested backoff. Retry after {:?}", _0)]
     BackoffError(SystemTime),
 
@@ -90,9 +169,21 @@ pub enum ErrorKind {
     #[fail(display = "Outgoing record is too large to upload")]
     RecordTooLargeError,
 
+    /// Another client wrote to the collection while we were downloading it,
+    /// or before we could write to it.
+    #[fail(display = "Collection {} changed while we were syncing it", _0)]
+    ConcurrentModification(String),
+
     #[fail(display = "The batch was not committed due to being interrupted")]
     BatchInterrupted,
 
+    #[fail(display = "The {} store doesn't support dry runs", _0)]
+    DryRunUnsupported(String),
+
+    /// The sync was stopped with an `InterruptHandle`.
+    #[fail(display = "The operation was interrupted")]
+    Interrupted,
+
     // Do we want to record the concrete problems?
     #[fail(display = "Not all records were successfully uploaded")]
     RecordUploadFailed,
@@ -111,6 +202,10 @@ pub enum ErrorKind {
     #[fail(display = "Unexpected server behavior during batch upload: {}", _0)]
     ServerBatchProblem(&'static str),
 
+    /// The persisted `GlobalState` didn't make sense, so we can't use it.
+    #[fail(display = "Invalid persisted state: {}", _0)]
+    InvalidPersistedState(&'static str),
+
     #[fail(display = "Setup state machine cycle detected")]
     SetupStateCycleError,
 
@@ -190,3 +285,45 @@ impl From<hawk::Error> for Error {
         ErrorKind::from(e).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(kind: ErrorKind) -> Error {
+        Error::from(kind)
+    }
+
+    #[test]
+    fn test_classification() {
+        let err = error(ErrorKind::StorageHttpError { code: 503, route: "".into() });
+        assert!(err.is_network_error() && err.is_retryable());
+        assert!(!err.is_auth_error() && !err.requires_user_action());
+        assert_eq!(err.retry_after(), None);
+
+        let err = error(ErrorKind::StorageHttpError { code: 404, route: "".into() });
+        assert!(!err.is_network_error() && !err.is_retryable());
+
+        let err = error(ErrorKind::TokenserverHttpError(401));
+        assert!(err.is_auth_error() && err.requires_user_action());
+        assert!(!err.is_retryable());
+
+        let err = error(ErrorKind::StorageHttpError { code: 401, route: "".into() });
+        assert!(!err.is_auth_error() && !err.requires_user_action());
+        assert!(err.is_retryable());
+
+        let until = SystemTime::now();
+        let err = error(ErrorKind::BackoffError(until));
+        assert!(err.is_retryable() && !err.is_network_error());
+        assert_eq!(err.retry_after(), Some(until));
+
+        let err = error(ErrorKind::ClientUpgradeRequired);
+        assert!(err.requires_user_action() && !err.is_retryable());
+
+        let err = error(ErrorKind::QuotaExceeded { route: "".into() });
+        assert!(err.requires_user_action() && !err.is_auth_error());
+
+        let err = error(ErrorKind::HmacMismatch);
+        assert!(!err.is_retryable() && !err.requires_user_action());
+    }
+}
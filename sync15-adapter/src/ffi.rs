@@ -0,0 +1,126 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![cfg(feature = "ffi")]
+
+//! Error codes for sync errors, shared by every component that syncs, so
+//! that apps can decide what to show the user from the code's range alone.
+//! Components should use `error_code` for their sync errors, and keep their
+//! own codes outside of `error_codes::FIRST..=error_codes::LAST`.
+
+use ffi_support::{ErrorCode, ExternError};
+use error::{Error, ErrorKind};
+
+pub mod error_codes {
+    /// The first code reserved for sync errors.
+    pub const FIRST: i32 = 100;
+    /// The last code reserved for sync errors.
+    pub const LAST: i32 = 199;
+
+    // 100-119: Syncing again later might succeed. See `Error::is_retryable`.
+
+    /// We couldn't reach the server, or it had a problem.
+    pub const NETWORK: i32 = 100;
+    /// The server asked us to back off.
+    pub const BACKOFF: i32 = 101;
+    /// The sync was interrupted.
+    pub const INTERRUPTED: i32 = 102;
+    /// Another client changed the server while we were syncing.
+    pub const CONCURRENT_MODIFICATION: i32 = 103;
+    /// The storage server rejected our token, because it expired or our
+    /// clock is off. Syncing again fetches a new one.
+    pub const TOKEN_REJECTED: i32 = 104;
+
+    // 120-139: The user needs to sign in again. See `Error::is_auth_error`.
+
+    /// The FxA credentials are invalid, and should be refreshed.
+    pub const AUTH_INVALID: i32 = 120;
+
+    // 140-159: The user needs to do something else, like upgrading the app.
+    // See `Error::requires_user_action`.
+
+    /// The server needs a newer version of the app.
+    pub const CLIENT_UPGRADE_REQUIRED: i32 = 140;
+    /// The user is over their storage quota.
+    pub const QUOTA_EXCEEDED: i32 = 141;
+
+    // 160-199: Anything else.
+
+    /// An unexpected error occurred, which likely can't be meaningfully
+    /// handled by the app.
+    pub const UNEXPECTED: i32 = 160;
+
+    /// Returns true if `code` means syncing again later might succeed.
+    pub fn is_retryable(code: i32) -> bool {
+        code >= 100 && code < 120
+    }
+
+    /// Returns true if `code` means the user needs to sign in again.
+    pub fn is_auth_error(code: i32) -> bool {
+        code >= 120 && code < 140
+    }
+
+    /// Returns true if `code` means the user needs to do something before
+    /// syncing will succeed, including signing in again.
+    pub fn requires_user_action(code: i32) -> bool {
+        code >= 120 && code < 160
+    }
+}
+
+/// Returns the error code for a sync error.
+pub fn error_code(err: &Error) -> ErrorCode {
+    let code = match err.kind() {
+        ErrorKind::BackoffError(_) => error_codes::BACKOFF,
+        ErrorKind::Interrupted | ErrorKind::BatchInterrupted => error_codes::INTERRUPTED,
+        ErrorKind::ConcurrentModification(_) => error_codes::CONCURRENT_MODIFICATION,
+        ErrorKind::StorageHttpError { code: 401, .. } => error_codes::TOKEN_REJECTED,
+        ErrorKind::ClientUpgradeRequired => error_codes::CLIENT_UPGRADE_REQUIRED,
+        ErrorKind::QuotaExceeded { .. } => error_codes::QUOTA_EXCEEDED,
+        _ if err.is_auth_error() => error_codes::AUTH_INVALID,
+        _ if err.is_network_error() => error_codes::NETWORK,
+        _ => error_codes::UNEXPECTED,
+    };
+    ErrorCode::new(code)
+}
+
+impl From<Error> for ExternError {
+    fn from(e: Error) -> ExternError {
+        ExternError::new_error(error_code(&e), e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    // Every error should land in the range for its classification. There's
+    // no way to make a `RequestError` without sending a request, so we use
+    // server errors for network errors instead.
+    #[test]
+    fn test_error_code_ranges() {
+        let errors = vec![
+            ErrorKind::StorageHttpError { code: 503, route: "".into() },
+            ErrorKind::TokenserverHttpError(500),
+            ErrorKind::BackoffError(SystemTime::now()),
+            ErrorKind::Interrupted,
+            ErrorKind::ConcurrentModification("passwords".into()),
+            ErrorKind::TokenserverHttpError(401),
+            ErrorKind::StorageHttpError { code: 401, route: "".into() },
+            ErrorKind::ClientUpgradeRequired,
+            ErrorKind::QuotaExceeded { route: "".into() },
+            ErrorKind::HmacMismatch,
+            ErrorKind::StorageHttpError { code: 404, route: "".into() },
+        ];
+        for kind in errors {
+            let err = Error::from(kind);
+            let code = error_code(&err).code();
+            assert!(code >= error_codes::FIRST && code <= error_codes::LAST);
+            assert_eq!(error_codes::is_retryable(code), err.is_retryable(), "{}", err);
+            assert_eq!(error_codes::is_auth_error(code), err.is_auth_error(), "{}", err);
+            assert_eq!(error_codes::requires_user_action(code), err.requires_user_action(),
+                       "{}", err);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Stopping a sync that's in progress, like when the OS is about to suspend
+//! the app.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use error::{self, ErrorKind};
+
+/// Interrupts a sync from another thread. Clones share the same flag, so apps
+/// can keep one, and pass another to `SyncManager::sync` or `synchronize`.
+///
+/// The storage client checks the handle before each request, so a sync stops
+/// between HTTP requests, download pages, and upload batches, and fails with
+/// `ErrorKind::Interrupted`. Anything we already downloaded and applied, and
+/// the high-water mark for it, is kept, so the next sync picks up where this
+/// one left off. Stores get the handle in `Store::apply_incoming`, and should
+/// call `err_if_interrupted` as they go if they take a while to apply records.
+///
+/// Once interrupted, a handle stays interrupted, so apps should pass a new
+/// one to each sync. The storage client and stores can be reused.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> InterruptHandle {
+        InterruptHandle::default()
+    }
+
+    /// Asks the sync to stop at the next check.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Returns an `Interrupted` error if `interrupt` was called.
+    pub fn err_if_interrupted(&self) -> error::Result<()> {
+        if self.was_interrupted() {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_interrupt_from_another_thread() {
+        let handle = InterruptHandle::new();
+        assert!(handle.err_if_interrupted().is_ok());
+
+        let other = handle.clone();
+        thread::spawn(move || other.interrupt()).join().unwrap();
+        assert!(handle.was_interrupted());
+        assert!(handle.err_if_interrupted().unwrap_err().is_interrupted());
+    }
+}
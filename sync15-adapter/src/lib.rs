@@ -28,10 +28,15 @@ extern crate serde_json;
 
 extern crate url;
 extern crate base16;
+extern crate time;
+
+#[cfg(feature = "ffi")]
+extern crate ffi_support;
 
 // TODO: Some of these don't need to be pub...
 pub mod key_bundle;
 pub mod error;
+pub mod interrupt;
 pub mod bso_record;
 pub mod record_types;
 pub mod token;
@@ -39,16 +44,37 @@ pub mod collection_keys;
 pub mod util;
 pub mod request;
 pub mod changeset;
+pub mod dry_run;
+pub mod clients;
 pub mod sync;
 pub mod client;
 pub mod state;
+pub mod manager;
+pub mod scheduler;
+pub mod tabs;
+pub mod telemetry;
+pub mod typed_store;
+pub mod transport;
+pub mod validation;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use bso_record::{BsoRecord, EncryptedBso, Payload, CleartextBso};
 pub use changeset::{RecordChangeset, IncomingChangeset, OutgoingChangeset};
+pub use request::{UploadInfo, UploadPolicy};
+pub use dry_run::{dry_run, DryRunReport};
 pub use error::{Result, Error, ErrorKind};
-pub use sync::{synchronize, Store};
+pub use interrupt::InterruptHandle;
+pub use sync::{synchronize, CollectionSyncInfo, RetrySet, Store};
+pub use typed_store::{IncomingKind, IncomingRecord, OutgoingRecord, SyncRecord, TypedStore};
 pub use util::{ServerTimestamp, SERVER_EPOCH};
+pub use validation::{CollectionValidator, ValidationReport};
 pub use key_bundle::KeyBundle;
 pub use client::{Sync15StorageClientInit, Sync15StorageClient};
-pub use state::{GlobalState, SetupStateMachine};
+pub use token::{CredentialsProvider, TokenserverCredentials};
+pub use state::{EngineDeclarations, GlobalState, LoadedState, SetupStateMachine};
+pub use manager::{SyncManager, SyncResult};
+pub use scheduler::{Scheduler, SchedulerConfig, SchedulerState, SyncOutcome};
+pub use transport::{HttpTransport, HttpRequest, HttpResponse, ReqwestTransport};
@@ -0,0 +1,340 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use client::Sync15StorageClient;
+use clients;
+use error::{self, ErrorKind};
+use interrupt::InterruptHandle;
+use key_bundle::KeyBundle;
+use state::{EngineDeclarations, GlobalState, SetupStateMachine};
+use sync::{synchronize, CollectionSyncInfo, Store};
+use tabs;
+use telemetry;
+
+/// How many times we'll run through the state machine and sync engines in one
+/// call to `SyncManager::sync`. A 401 followed by a node reassignment takes
+/// two restarts to recover from: one to fetch a new token, and another to
+/// reset for the new node.
+const MAX_SYNC_ATTEMPTS: usize = 3;
+
+/// The outcome of a call to `SyncManager::sync`.
+#[derive(Debug)]
+pub struct SyncResult<E> {
+    /// The result of syncing each engine, keyed by collection name. An error
+    /// syncing one engine doesn't stop us from syncing the others. Declined
+    /// engines aren't included.
+    pub engine_results: HashMap<String, Result<CollectionSyncInfo, E>>,
+    /// The engines we skipped because the user declined them on another
+    /// device.
+    pub declined: Vec<String>,
+}
+
+/// Syncs a set of stores in one go: advances the setup state machine once,
+/// resets stores whose sync IDs or keys changed, and then syncs each store's
+/// collection in turn, skipping engines declined in `meta/global`, or
+/// declined locally with `declare_engines`. If there's a clients engine, we
+/// sync it before anything else, so that we process commands like
+/// `wipeEngine` before syncing the engines they affect. The tabs engine, if
+/// any, is synced next.
+///
+/// Note that all stores must share an error type. Consumers with stores that
+/// have different error types should wrap them in a common one.
+pub struct SyncManager<'a, E: 'a> {
+    stores: Vec<&'a Store<Error = E>>,
+    declarations: Option<EngineDeclarations>,
+    clients: Option<&'a clients::Engine<'a>>,
+    tabs: Option<&'a tabs::Engine<'a>>,
+}
+
+impl<'a, E> SyncManager<'a, E>
+where
+    E: From<error::Error>,
+{
+    pub fn new(stores: Vec<&'a Store<Error = E>>) -> SyncManager<'a, E> {
+        SyncManager {
+            stores,
+            declarations: None,
+            clients: None,
+            tabs: None,
+        }
+    }
+
+    /// Syncs our client record with `engine`, and processes incoming
+    /// commands. Its result is reported in the `SyncResult` as "clients".
+    pub fn clients_engine(&mut self, engine: &'a clients::Engine<'a>) -> &mut Self {
+        self.clients = Some(engine);
+        self
+    }
+
+    /// Syncs our open tabs with `engine`, unless the user declined the tabs
+    /// engine. Its result is reported in the `SyncResult` as "tabs".
+    pub fn tabs_engine(&mut self, engine: &'a tabs::Engine<'a>) -> &mut Self {
+        self.tabs = Some(engine);
+        self
+    }
+
+    /// Declares the engines this client supports, and the engines the user
+    /// declined on this device, so that we can reflect them in
+    /// `meta/global`. See `SetupStateMachine::declare_engines`.
+    pub fn declare_engines(&mut self, declarations: EngineDeclarations) -> &mut Self {
+        self.declarations = Some(declarations);
+        self
+    }
+
+    /// Syncs all stores. `state` is updated in place, and callers should
+    /// persist it after this returns, even if it returns an error. Likewise,
+    /// `telem` records what happened even if we fail. If `interrupt` is
+    /// interrupted, the engine we're syncing fails with `Interrupted`, and we
+    /// skip the rest; apps should pass a new handle each time.
+    ///
+    /// Returns an error only if we couldn't get to the ready state, or if the
+    /// server asked us to back off and the backoff period hasn't elapsed yet.
+    /// Even then, `state` might have changed: we record any backoff the server
+    /// asked for, and if the tokenserver moved us to a different node, we
+    /// reset `state` for the new node before trying again. Errors syncing
+    /// individual engines are reported in the `SyncResult`.
+    ///
+    /// If the storage server rejects our token, or the tokenserver moves us
+    /// to a different node, we fetch a new token and start over. After a
+    /// node reassignment, we also reset `state` and all engines, so that they
+    /// reupload everything to the new node.
+    pub fn sync(
+        &self,
+        client: &Sync15StorageClient,
+        state: &mut GlobalState,
+        root_key: &KeyBundle,
+        interrupt: &InterruptHandle,
+        telem: &mut telemetry::SyncTelemetry,
+    ) -> Result<SyncResult<E>, E> {
+        client.set_interrupt_handle(interrupt.clone());
+        if let Err(e) = state.check_backoff() {
+            telem.failure(telemetry::SyncFailure::from(&e));
+            telem.finished();
+            return Err(e.into());
+        }
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            let result = self.sync_engines(client, state, root_key, interrupt, telem);
+            let unauthorized = client.take_unauthorized();
+            let reassigned = client.node_reassigned();
+            if !(unauthorized || reassigned) || attempts >= MAX_SYNC_ATTEMPTS {
+                break result;
+            }
+            if reassigned {
+                info!("Node reassigned; resetting all engines and restarting sync");
+                client.drop_token();
+                state.reset_for_new_node();
+            } else {
+                info!("Token rejected; restarting sync with a new token");
+            }
+            telem.restarted();
+        };
+        telem.finished();
+        // Remember if the server asked us to back off, so that we don't sync
+        // again too early, even if the app restarts before then.
+        if let Some(until) = client.backoff_until() {
+            state.backoff_until = cmp::max(state.backoff_until, Some(until));
+        }
+        result
+    }
+
+    fn sync_engines(
+        &self,
+        client: &Sync15StorageClient,
+        state: &mut GlobalState,
+        root_key: &KeyBundle,
+        interrupt: &InterruptHandle,
+        telem: &mut telemetry::SyncTelemetry,
+    ) -> Result<SyncResult<E>, E> {
+        {
+            let mut state_machine = SetupStateMachine::for_full_sync(client, root_key);
+            if let Some(declarations) = &self.declarations {
+                state_machine.declare_engines(declarations.clone());
+            }
+            info!("Advancing state machine to ready (full)");
+            let result = state_machine.to_ready(state.clone());
+            telem.setup_sequence(state_machine.sequence());
+            match result {
+                Ok(new_state) => *state = new_state,
+                Err(e) => {
+                    telem.failure(telemetry::SyncFailure::from(&e));
+                    // The token server can also ask us to back off.
+                    if let ErrorKind::BackoffError(until) = e.kind() {
+                        state.backoff_until = cmp::max(state.backoff_until, Some(*until));
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        // If we rotated keys, but didn't finish wiping the server, finish now,
+        // before our engines upload anything with the new keys.
+        if let Err(e) = state.finish_pending_wipe(client) {
+            telem.failure(telemetry::SyncFailure::from(&e));
+            return Err(e.into());
+        }
+
+        let mut declined = state.global
+            .as_ref()
+            .map(|global| global.declined.iter().cloned().collect::<HashSet<_>>())
+            .unwrap_or_default();
+        if let Some(declarations) = &self.declarations {
+            declined.extend(declarations.declined.iter().cloned());
+        }
+
+        let mut result = SyncResult {
+            engine_results: HashMap::with_capacity(self.stores.len() + 2),
+            declined: Vec::new(),
+        };
+        if let Some(engine) = self.clients {
+            let engine_result = sync_builtin_engine(
+                "clients",
+                client,
+                state,
+                interrupt,
+                telem,
+                |state, needs_reset, telem_engine| {
+                    engine.sync(client, state, needs_reset, telem_engine)
+                },
+            );
+            result.engine_results.insert("clients".into(), engine_result);
+        }
+        if let Some(engine) = self.tabs {
+            if declined.contains("tabs") {
+                info!("Skipping declined engine tabs");
+                result.declined.push("tabs".into());
+            } else {
+                let engine_result = sync_builtin_engine(
+                    "tabs",
+                    client,
+                    state,
+                    interrupt,
+                    telem,
+                    |state, needs_reset, telem_engine| {
+                        engine.sync(client, state, needs_reset, telem_engine)
+                    },
+                );
+                result.engine_results.insert("tabs".into(), engine_result);
+            }
+        }
+        for store in &self.stores {
+            let name = store.collection_name();
+            if declined.contains(name) {
+                info!("Skipping declined engine {}", name);
+                result.declined.push(name.into());
+                continue;
+            }
+            let mut telem_engine = telemetry::Engine::new(name);
+            if let Some(err) = skip_engine(name, client, interrupt) {
+                telem_engine.failure(telemetry::SyncFailure::from(&err));
+                telem_engine.finished();
+                telem.engine(telem_engine);
+                result.engine_results.insert(name.into(), Err(err.into()));
+                continue;
+            }
+            let engine_result = sync_engine(client, state, root_key, *store, interrupt,
+                                            &mut telem_engine);
+            telem_engine.finished();
+            telem.engine(telem_engine);
+            if engine_result.is_err() {
+                // Don't log the error itself, since it might contain
+                // sensitive info.
+                warn!("Syncing engine {} failed", name);
+            }
+            result.engine_results.insert(name.into(), engine_result);
+        }
+        Ok(result)
+    }
+}
+
+// If the server asked us to back off while syncing an earlier engine, or we
+// were interrupted, returns the error to report for `name` instead of
+// syncing it.
+fn skip_engine(
+    name: &str,
+    client: &Sync15StorageClient,
+    interrupt: &InterruptHandle,
+) -> Option<error::Error> {
+    let kind = match client.backoff_until() {
+        Some(until) if until > SystemTime::now() => ErrorKind::BackoffError(until),
+        _ if interrupt.was_interrupted() => ErrorKind::Interrupted,
+        _ => return None,
+    };
+    info!("Not syncing engine {}: {}", name, kind);
+    Some(kind.into())
+}
+
+// Syncs an engine that's built into the adapter, like clients or tabs, and
+// records its telemetry. Like `sync_engine`, we tell the engine if it needs
+// to reset, and only mark the reset as finished if the sync succeeds.
+fn sync_builtin_engine<E, F>(
+    name: &str,
+    client: &Sync15StorageClient,
+    state: &mut GlobalState,
+    interrupt: &InterruptHandle,
+    telem: &mut telemetry::SyncTelemetry,
+    sync: F,
+) -> Result<CollectionSyncInfo, E>
+where
+    E: From<error::Error>,
+    F: FnOnce(&GlobalState, bool, &mut telemetry::Engine) -> error::Result<()>,
+{
+    let mut telem_engine = telemetry::Engine::new(name);
+    if let Some(err) = skip_engine(name, client, interrupt) {
+        telem_engine.failure(telemetry::SyncFailure::from(&err));
+        telem_engine.finished();
+        telem.engine(telem_engine);
+        return Err(err.into());
+    }
+    let needs_reset = state.engines_that_need_local_reset().contains(name);
+    if needs_reset {
+        info!("{} sync ID or keys changed; engine needs local reset", name);
+    }
+    let engine_result = sync(state, needs_reset, &mut telem_engine)
+        .map(|()| {
+            if needs_reset {
+                state.local_reset_finished(name);
+            }
+            CollectionSyncInfo::default()
+        })
+        .map_err(|e| telem_engine.record_error(e).into());
+    telem_engine.finished();
+    telem.engine(telem_engine);
+    if engine_result.is_err() {
+        warn!("Syncing engine {} failed", name);
+    }
+    engine_result
+}
+
+// Resets `store` if its sync ID or keys changed, then syncs it. We check
+// for resets just before syncing each store, since an earlier engine might
+// have refetched the keys. Resets stay pending in `state` until they succeed.
+fn sync_engine<E>(
+    client: &Sync15StorageClient,
+    state: &mut GlobalState,
+    root_key: &KeyBundle,
+    store: &Store<Error = E>,
+    interrupt: &InterruptHandle,
+    telem_engine: &mut telemetry::Engine,
+) -> Result<CollectionSyncInfo, E>
+where
+    E: From<error::Error>,
+{
+    let name = store.collection_name();
+    if state.engines_that_need_local_reset().contains(name) {
+        info!("{} sync ID or keys changed; engine needs local reset", name);
+        store.reset().map_err(|e| telem_engine.record_store_error(e))?;
+        state.local_reset_finished(name);
+    }
+    let last_sync = store
+        .get_last_sync()
+        .map_err(|e| telem_engine.record_store_error(e))?
+        .unwrap_or_default();
+    synchronize(client, state, root_key, store, last_sync, store.upload_policy(), interrupt,
+                telem_engine)
+}
@@ -6,14 +6,14 @@ use std::collections::HashMap;
 
 // Known record formats.
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct MetaGlobalEngine {
     pub version: usize,
     #[serde(rename = "syncID")]
     pub sync_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct MetaGlobalRecord {
     #[serde(rename = "syncID")]
     pub sync_id: String,
@@ -5,6 +5,7 @@
 use util::ServerTimestamp;
 use bso_record::{EncryptedBso};
 
+use serde::de::{Deserialize, Deserializer};
 use serde_json;
 use std::fmt;
 use std::collections::HashMap;
@@ -14,14 +15,16 @@ use std::str::FromStr;
 use url::{Url, UrlQuery, form_urlencoded::Serializer};
 use error::{self, Result, ErrorKind};
 use hyper::{StatusCode};
-use reqwest::Response;
+use transport::HttpResponse;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RequestOrder { Oldest, Newest, Index }
 
 pub const X_IF_UNMODIFIED_SINCE: &str = "X-If-Unmodified-Since";
 pub const X_WEAVE_TIMESTAMP: &str = "X-Weave-Timestamp";
-const X_LAST_MODIFIED: &str = "X-Last-Modified";
+pub const X_LAST_MODIFIED: &str = "X-Last-Modified";
+pub const X_WEAVE_NEXT_OFFSET: &str = "X-Weave-Next-Offset";
+pub const X_WEAVE_QUOTA_REMAINING: &str = "X-Weave-Quota-Remaining";
 
 impl fmt::Display for RequestOrder {
     #[inline]
@@ -37,12 +40,14 @@ impl fmt::Display for RequestOrder {
 #[derive(Debug, Clone, PartialEq)]
 pub struct CollectionRequest {
     pub collection: String,
+    pub record: Option<String>,
     pub full: bool,
     pub ids: Option<Vec<String>>,
     pub limit: usize,
     pub older: Option<ServerTimestamp>,
     pub newer: Option<ServerTimestamp>,
     pub order: Option<RequestOrder>,
+    pub offset: Option<String>,
     pub commit: bool,
     pub batch: Option<String>,
 }
@@ -52,17 +57,27 @@ impl CollectionRequest {
     pub fn new<S>(collection: S) -> CollectionRequest where S: Into<String> {
         CollectionRequest {
             collection: collection.into(),
+            record: None,
             full: false,
             ids: None,
             limit: 0,
             older: None,
             newer: None,
             order: None,
+            offset: None,
             commit: false,
             batch: None,
         }
     }
 
+    /// Targets a single record in the collection, instead of the whole
+    /// collection.
+    #[inline]
+    pub fn record<S>(&mut self, id: S) -> &mut CollectionRequest where S: Into<String> {
+        self.record = Some(id.into());
+        self
+    }
+
     #[inline]
     pub fn ids<V>(&mut self, v: V) -> &mut CollectionRequest where V: Into<Vec<String>> {
         self.ids = Some(v.into());
@@ -99,6 +114,15 @@ impl CollectionRequest {
         self
     }
 
+    /// Sets the offset token to fetch the next page of a paged request. This
+    /// should be the value of the `X-Weave-Next-Offset` header from the
+    /// previous page.
+    #[inline]
+    pub fn offset(&mut self, offset: Option<String>) -> &mut CollectionRequest {
+        self.offset = offset;
+        self
+    }
+
     #[inline]
     pub fn batch(&mut self, batch: Option<String>) -> &mut CollectionRequest {
         self.batch = batch;
@@ -136,13 +160,22 @@ impl CollectionRequest {
         if let Some(o) = self.order {
             pairs.append_pair("sort", &format!("{}", o));
         }
+        if let &Some(ref offset) = &self.offset {
+            pairs.append_pair("offset", &offset);
+        }
         pairs.finish();
     }
 
     pub fn build_url(&self, mut base_url: Url) -> Result<Url> {
-        base_url.path_segments_mut()
-                .map_err(|_| ErrorKind::UnacceptableUrl("Storage server URL is not a base".into()))?
-                .extend(&["storage", &self.collection]);
+        {
+            let mut segments = base_url.path_segments_mut().map_err(|_| {
+                ErrorKind::UnacceptableUrl("Storage server URL is not a base".into())
+            })?;
+            segments.extend(&["storage", &self.collection]);
+            if let Some(ref record) = self.record {
+                segments.push(record);
+            }
+        }
         self.build_query(&mut base_url.query_pairs_mut());
         // This is strange but just accessing query_pairs_mut makes you have
         // a trailing question mark on your url. I don't think anything bad
@@ -265,6 +298,52 @@ impl Deref for InfoCollections {
     }
 }
 
+/// The response to `info/quota`: how much space the user's data takes up on
+/// the server, and their quota, both in KB. `quota_kb` is `None` if the server
+/// doesn't enforce a quota.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InfoQuota {
+    pub usage_kb: f64,
+    pub quota_kb: Option<f64>,
+}
+
+// The server sends `[usage, quota]`.
+impl<'de> Deserialize<'de> for InfoQuota {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<InfoQuota, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (usage_kb, quota_kb) = <(f64, Option<f64>)>::deserialize(deserializer)?;
+        Ok(InfoQuota { usage_kb, quota_kb })
+    }
+}
+
+/// The response to `info/collection_usage`: how much space each collection
+/// takes up on the server, in KB.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InfoCollectionUsage(HashMap<String, f64>);
+
+impl Deref for InfoCollectionUsage {
+    type Target = HashMap<String, f64>;
+
+    fn deref(&self) -> &HashMap<String, f64> {
+        &self.0
+    }
+}
+
+/// The response to `info/collection_counts`: how many records are in each
+/// collection.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InfoCollectionCounts(HashMap<String, u64>);
+
+impl Deref for InfoCollectionCounts {
+    type Target = HashMap<String, u64>;
+
+    fn deref(&self) -> &HashMap<String, u64> {
+        &self.0
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct UploadResult {
     batch: Option<String>,
@@ -285,12 +364,12 @@ pub struct PostResponse {
 }
 
 impl PostResponse {
-    pub fn from_response(r: &mut Response) -> Result<PostResponse> {
+    pub fn from_response(r: &HttpResponse) -> Result<PostResponse> {
         let result: UploadResult = r.json()?;
         // TODO Can this happen in error cases?
-        let last_modified = r.headers().get(X_LAST_MODIFIED).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
+        let last_modified = r.headers.get(X_LAST_MODIFIED).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
             ErrorKind::MissingServerTimestamp)?;
-        let status = r.status();
+        let status = r.status;
         Ok(PostResponse { status, result, last_modified })
     }
 }
@@ -335,23 +414,36 @@ pub trait PostResponseHandler {
 }
 
 
+/// What to do if we can't upload some of our records, because they're too
+/// large, or the server rejects them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadPolicy {
+    /// Fail the upload, without committing the batch: with
+    /// `RecordTooLargeError` if a record is too large to upload, or with
+    /// `RecordUploadFailed` if the server rejected one.
+    Atomic,
+    /// Upload the records that fit and that the server accepts, and report
+    /// the rest, with the reason they failed, in `UploadInfo::failed`.
+    AllowFailures,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct NormalResponseHandler {
-    pub failed_ids: Vec<String>,
+    pub failed: HashMap<String, String>,
     pub successful_ids: Vec<String>,
-    pub allow_failed: bool,
-    pub pending_failed: Vec<String>,
+    pub policy: UploadPolicy,
+    pub pending_failed: HashMap<String, String>,
     pub pending_success: Vec<String>,
 }
 
 impl NormalResponseHandler {
-    pub fn new(allow_failed: bool) -> NormalResponseHandler {
+    pub fn new(policy: UploadPolicy) -> NormalResponseHandler {
         NormalResponseHandler {
-            failed_ids: vec![],
+            failed: HashMap::new(),
             successful_ids: vec![],
-            pending_failed: vec![],
+            pending_failed: HashMap::new(),
             pending_success: vec![],
-            allow_failed,
+            policy,
         }
     }
 }
@@ -369,18 +461,18 @@ impl PostResponseHandler for NormalResponseHandler {
                 }.into());
             }
         }
-        if r.result.failed.len() > 0 && !self.allow_failed {
+        if r.result.failed.len() > 0 && self.policy == UploadPolicy::Atomic {
             return Err(ErrorKind::RecordUploadFailed.into());
         }
         for id in r.result.success.iter() {
             self.pending_success.push(id.clone());
         }
-        for kv in r.result.failed.iter() {
-            self.pending_failed.push(kv.0.clone());
+        for (id, reason) in r.result.failed.iter() {
+            self.pending_failed.insert(id.clone(), reason.clone());
         }
         if !mid_batch {
             self.successful_ids.append(&mut self.pending_success);
-            self.failed_ids.append(&mut self.pending_failed);
+            self.failed.extend(self.pending_failed.drain());
         }
         Ok(())
     }
@@ -580,7 +672,8 @@ where
 #[derive(Clone)]
 pub struct UploadInfo {
     pub successful_ids: Vec<String>,
-    pub failed_ids: Vec<String>,
+    /// Maps the IDs of records that we didn't upload to the reason why.
+    pub failed: HashMap<String, String>,
     pub modified_timestamp: ServerTimestamp,
 }
 
@@ -589,7 +682,7 @@ impl<Poster> PostQueue<Poster, NormalResponseHandler> {
     pub fn completed_upload_info(&mut self) -> UploadInfo {
         let mut result = UploadInfo {
             successful_ids: Vec::with_capacity(self.on_response.successful_ids.len()),
-            failed_ids: Vec::with_capacity(self.on_response.failed_ids.len() +
+            failed: HashMap::with_capacity(self.on_response.failed.len() +
                                            self.on_response.pending_failed.len() +
                                            self.on_response.pending_success.len()),
             modified_timestamp: self.last_modified
@@ -597,9 +690,11 @@ impl<Poster> PostQueue<Poster, NormalResponseHandler> {
 
         result.successful_ids.append(&mut self.on_response.successful_ids);
 
-        result.failed_ids.append(&mut self.on_response.failed_ids);
-        result.failed_ids.append(&mut self.on_response.pending_failed);
-        result.failed_ids.append(&mut self.on_response.pending_success);
+        result.failed.extend(self.on_response.failed.drain());
+        result.failed.extend(self.on_response.pending_failed.drain());
+        for id in self.on_response.pending_success.drain(..) {
+            result.failed.insert(id, "Batch wasn't committed".into());
+        }
 
         result
     }
@@ -637,6 +732,15 @@ mod test {
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
 
+        let paged = CollectionRequest::new("paged").full().limit(100).sort_by(RequestOrder::Oldest)
+                                                   .offset(Some("abc123".into()))
+                                                   .build_url(base.clone()).unwrap();
+        assert_eq!(paged.as_str(),
+            "https://example.com/sync/storage/paged?full=1&limit=100&sort=oldest&offset=abc123");
+
+        let record = CollectionRequest::new("single").record("abc/123")
+                                                     .build_url(base.clone()).unwrap();
+        assert_eq!(record.as_str(), "https://example.com/sync/storage/single/abc%2F123");
     }
 
     #[derive(Debug, Clone)]
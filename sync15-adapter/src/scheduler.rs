@@ -0,0 +1,291 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Decides when to sync next, like desktop's `SyncScheduler`, so that apps
+//! don't need to reimplement this around `SyncManager::sync`. We sync more
+//! often when there are other clients to sync with, and sooner still when we
+//! have local changes to upload. After errors, we back off exponentially,
+//! and honor the server's backoff. After auth errors, or if the server
+//! needs a newer client, we stop syncing until the app tells us otherwise.
+
+use std::cmp;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::{self, ErrorKind};
+use serde_json;
+
+/// How often to sync. The defaults match desktop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerConfig {
+    /// How long to wait between syncs if there are no other clients.
+    pub single_device_interval: Duration,
+    /// How long to wait between syncs if there are other clients.
+    pub multi_device_interval: Duration,
+    /// How long to wait if we have local changes to upload, and there are
+    /// other clients.
+    pub local_changes_interval: Duration,
+    /// How long to wait after the first error. We double this for every
+    /// error after that.
+    pub min_error_interval: Duration,
+    /// The longest we'll wait after errors.
+    pub max_error_interval: Duration,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            single_device_interval: Duration::from_secs(24 * 60 * 60),
+            multi_device_interval: Duration::from_secs(10 * 60),
+            local_changes_interval: Duration::from_secs(90),
+            min_error_interval: Duration::from_secs(5 * 60),
+            max_error_interval: Duration::from_secs(8 * 60 * 60),
+        }
+    }
+}
+
+/// The outcome of a sync, for `Scheduler::sync_finished`. Errors can be
+/// converted with `SyncOutcome::from`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncOutcome {
+    Success,
+    /// We couldn't reach the server, or it had a problem.
+    NetworkError,
+    /// The tokenserver rejected our credentials.
+    AuthError,
+    /// The server asked us to back off until this time.
+    ServerBackoff(SystemTime),
+    /// The server's storage version is newer than we support.
+    ClientUpgradeRequired,
+    /// The sync was interrupted, like when the app went into the background.
+    Interrupted,
+    /// Anything else, like a store error.
+    OtherError,
+}
+
+impl<'a> From<&'a error::Error> for SyncOutcome {
+    fn from(e: &'a error::Error) -> SyncOutcome {
+        match e.kind() {
+            ErrorKind::BackoffError(until) => SyncOutcome::ServerBackoff(*until),
+            ErrorKind::ClientUpgradeRequired => SyncOutcome::ClientUpgradeRequired,
+            ErrorKind::Interrupted => SyncOutcome::Interrupted,
+            // The storage server rejected our token, but the next sync
+            // fetches a new one, so the user doesn't need to sign in again.
+            ErrorKind::StorageHttpError { code: 401, .. } => SyncOutcome::OtherError,
+            _ if e.is_auth_error() => SyncOutcome::AuthError,
+            _ if e.is_network_error() => SyncOutcome::NetworkError,
+            _ => SyncOutcome::OtherError,
+        }
+    }
+}
+
+/// Why we stopped syncing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Blocked {
+    /// Until the user signs in again; see `Scheduler::unblock`.
+    AuthError,
+    /// Until the app is upgraded; see `Scheduler::unblock`.
+    ClientUpgradeRequired,
+}
+
+/// The scheduler's persistable state. Apps should persist it with
+/// `to_persistable_string` after every sync, and load it on startup.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerState {
+    /// When the last sync finished, successfully or not.
+    pub last_sync: Option<SystemTime>,
+    /// The number of errors since the last successful sync.
+    pub consecutive_errors: u32,
+    /// If the server asked us to back off, the time before which we
+    /// shouldn't sync again.
+    pub backoff_until: Option<SystemTime>,
+    pub blocked: Option<Blocked>,
+    /// The number of other clients, as of the last sync.
+    pub num_clients: usize,
+    /// Whether we have local changes waiting to be uploaded.
+    pub local_changes: bool,
+}
+
+impl SchedulerState {
+    pub fn to_persistable_string(&self) -> String {
+        serde_json::to_string(self)
+            .expect("Should only fail for recursive types (this is not recursive)")
+    }
+
+    pub fn from_persisted_string(data: &str) -> error::Result<Self> {
+        Ok(serde_json::from_str(data)?)
+    }
+}
+
+/// Decides when the next sync should happen.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    config: SchedulerConfig,
+    state: SchedulerState,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig, state: SchedulerState) -> Scheduler {
+        Scheduler { config, state }
+    }
+
+    #[inline]
+    pub fn state(&self) -> &SchedulerState {
+        &self.state
+    }
+
+    /// Records the outcome of a sync that finished at `now`.
+    pub fn sync_finished(&mut self, now: SystemTime, outcome: &SyncOutcome) {
+        info!("Scheduling next sync after outcome {:?}", outcome);
+        self.state.last_sync = Some(now);
+        match outcome {
+            SyncOutcome::Success => self.state.consecutive_errors = 0,
+            SyncOutcome::NetworkError | SyncOutcome::OtherError => {
+                self.state.consecutive_errors = self.state.consecutive_errors.saturating_add(1);
+            }
+            SyncOutcome::AuthError => self.state.blocked = Some(Blocked::AuthError),
+            SyncOutcome::ServerBackoff(until) => self.set_server_backoff(*until),
+            SyncOutcome::ClientUpgradeRequired => {
+                self.state.blocked = Some(Blocked::ClientUpgradeRequired);
+            }
+            // Try again as if nothing happened.
+            SyncOutcome::Interrupted => {}
+        }
+    }
+
+    /// Records a server backoff. The server can ask us to back off even if
+    /// the sync succeeds, so apps should also pass `GlobalState::backoff_until`
+    /// here after every sync.
+    pub fn set_server_backoff(&mut self, until: SystemTime) {
+        self.state.backoff_until = cmp::max(self.state.backoff_until, Some(until));
+    }
+
+    /// Records the number of other clients, like the size of
+    /// `clients::Engine::recent_clients`.
+    pub fn set_num_clients(&mut self, num_clients: usize) {
+        self.state.num_clients = num_clients;
+    }
+
+    /// Records whether we have local changes to upload. Apps should call
+    /// this when the user changes something, and after every sync.
+    pub fn set_local_changes(&mut self, local_changes: bool) {
+        self.state.local_changes = local_changes;
+    }
+
+    /// Starts syncing again after an auth error, once the user signs in
+    /// again, or after `ClientUpgradeRequired`, once the app is upgraded.
+    pub fn unblock(&mut self) {
+        self.state.blocked = None;
+        self.state.consecutive_errors = 0;
+    }
+
+    /// Returns when the next sync should happen, or `None` if we shouldn't
+    /// sync until we're unblocked. A time in the past means we should sync
+    /// now.
+    pub fn next_sync_at(&self) -> Option<SystemTime> {
+        if self.state.blocked.is_some() {
+            return None;
+        }
+        let last_sync = match self.state.last_sync {
+            Some(last_sync) => last_sync,
+            None => return Some(UNIX_EPOCH),
+        };
+        let next = last_sync + self.interval();
+        Some(match self.state.backoff_until {
+            Some(until) => cmp::max(next, until),
+            None => next,
+        })
+    }
+
+    fn interval(&self) -> Duration {
+        if self.state.consecutive_errors > 0 {
+            // `min_error_interval * 2^(errors - 1)`, without overflowing.
+            let doublings = cmp::min(self.state.consecutive_errors - 1, 31);
+            let interval = self.config.min_error_interval.checked_mul(1 << doublings)
+                .unwrap_or(self.config.max_error_interval);
+            return cmp::min(interval, self.config.max_error_interval);
+        }
+        if self.state.num_clients == 0 {
+            self.config.single_device_interval
+        } else if self.state.local_changes {
+            self.config.local_changes_interval
+        } else {
+            self.config.multi_device_interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mins(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn test_intervals() {
+        let mut scheduler = Scheduler::default();
+        assert_eq!(scheduler.next_sync_at(), Some(UNIX_EPOCH));
+
+        let now = UNIX_EPOCH + mins(1000);
+        scheduler.sync_finished(now, &SyncOutcome::Success);
+        assert_eq!(scheduler.next_sync_at(), Some(now + mins(24 * 60)));
+
+        scheduler.set_num_clients(2);
+        assert_eq!(scheduler.next_sync_at(), Some(now + mins(10)));
+
+        scheduler.set_local_changes(true);
+        assert_eq!(scheduler.next_sync_at(), Some(now + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_num_clients(1);
+        let now = UNIX_EPOCH + mins(1000);
+        let err = error::Error::from(ErrorKind::StorageHttpError {
+            code: 503,
+            route: "info/collections".into(),
+        });
+        for expected in &[5, 10, 20, 40, 80, 160, 320, 480, 480] {
+            scheduler.sync_finished(now, &SyncOutcome::from(&err));
+            assert_eq!(scheduler.next_sync_at(), Some(now + mins(*expected)));
+        }
+        scheduler.sync_finished(now, &SyncOutcome::Interrupted);
+        assert_eq!(scheduler.next_sync_at(), Some(now + mins(480)));
+        scheduler.sync_finished(now, &SyncOutcome::Success);
+        assert_eq!(scheduler.next_sync_at(), Some(now + mins(10)));
+
+        // The server's backoff wins if it's later than our next sync...
+        let err = error::Error::from(ErrorKind::BackoffError(now + mins(30)));
+        scheduler.sync_finished(now, &SyncOutcome::from(&err));
+        assert_eq!(scheduler.next_sync_at(), Some(now + mins(30)));
+        // ...But not if it's earlier.
+        let later = now + mins(60);
+        scheduler.sync_finished(later, &SyncOutcome::Success);
+        assert_eq!(scheduler.next_sync_at(), Some(later + mins(10)));
+
+        let err = error::Error::from(ErrorKind::StorageHttpError {
+            code: 401,
+            route: "".into(),
+        });
+        scheduler.sync_finished(later, &SyncOutcome::from(&err));
+        assert_eq!(scheduler.state().blocked, None);
+        scheduler.sync_finished(later, &SyncOutcome::Success);
+
+        let err = error::Error::from(ErrorKind::TokenserverHttpError(401));
+        scheduler.sync_finished(later, &SyncOutcome::from(&err));
+        assert_eq!(scheduler.next_sync_at(), None);
+        scheduler.unblock();
+        assert_eq!(scheduler.next_sync_at(), Some(later + mins(10)));
+
+        let err = error::Error::from(ErrorKind::ClientUpgradeRequired);
+        scheduler.sync_finished(later, &SyncOutcome::from(&err));
+        assert_eq!(scheduler.state().blocked, Some(Blocked::ClientUpgradeRequired));
+        let persisted = scheduler.state().to_persistable_string();
+        let state = SchedulerState::from_persisted_string(&persisted).unwrap();
+        assert_eq!(&state, scheduler.state());
+        assert_eq!(Scheduler::new(SchedulerConfig::default(), state).next_sync_at(), None);
+    }
+}
@@ -3,14 +3,17 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::time::SystemTime;
 
 use bso_record::BsoRecord;
-use client::SetupStorageClient;
+use client::{SetupStorageClient, Sync15StorageClient};
 use collection_keys::CollectionKeys;
 use error::{self, ErrorKind};
 use key_bundle::KeyBundle;
 use record_types::{MetaGlobalEngine, MetaGlobalRecord};
 use request::{InfoCollections, InfoConfiguration};
+use typed_store::SyncRecord;
 use util::{random_guid, ServerTimestamp, SERVER_EPOCH};
 use serde_json;
 
@@ -18,6 +21,10 @@ use self::SetupState::*;
 
 const STORAGE_VERSION: usize = 5;
 
+/// How many times we'll try to upload `meta/global` with our declared engines
+/// before giving up, if other clients keep changing it.
+const MAX_META_GLOBAL_UPDATES: usize = 2;
+
 lazy_static! {
     /// Maps names to storage versions for engines to include in a fresh
     /// `meta/global` record. We include engines that we don't implement
@@ -40,10 +47,140 @@ lazy_static! {
     static ref DEFAULT_DECLINED: Vec<&'static str> = vec![];
 }
 
+/// The versions of `GlobalState` that we've persisted. When the format
+/// changes, freeze the old shape in its own struct, add a variant for the
+/// new one, and a migration in `GlobalState::from_persisted_string`.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "schema_version")]
 enum PersistedState {
-    V1(GlobalState),
+    V1(GlobalStateV1),
+    V2(GlobalStateV2),
+    V3(GlobalState),
+}
+
+/// `GlobalState` as we persisted it in V1. V2 added `backoff_until`.
+#[derive(Debug, Serialize, Deserialize)]
+struct GlobalStateV1 {
+    config: InfoConfiguration,
+    collections: InfoCollections,
+    global: Option<BsoRecord<MetaGlobalRecord>>,
+    keys: Option<CollectionKeys>,
+    engine_state_changes: Vec<EngineStateChange>,
+}
+
+impl From<GlobalStateV1> for GlobalStateV2 {
+    fn from(state: GlobalStateV1) -> Self {
+        GlobalStateV2 {
+            config: state.config,
+            collections: state.collections,
+            global: state.global,
+            keys: state.keys,
+            engine_state_changes: state.engine_state_changes,
+            backoff_until: None,
+        }
+    }
+}
+
+/// `GlobalState` as we persisted it in V2. V3 added `pending_wipe`.
+#[derive(Debug, Serialize, Deserialize)]
+struct GlobalStateV2 {
+    config: InfoConfiguration,
+    collections: InfoCollections,
+    global: Option<BsoRecord<MetaGlobalRecord>>,
+    keys: Option<CollectionKeys>,
+    engine_state_changes: Vec<EngineStateChange>,
+    backoff_until: Option<SystemTime>,
+}
+
+impl From<GlobalStateV2> for GlobalState {
+    fn from(state: GlobalStateV2) -> Self {
+        GlobalState {
+            config: state.config,
+            collections: state.collections,
+            global: state.global,
+            keys: state.keys,
+            engine_state_changes: state.engine_state_changes,
+            backoff_until: state.backoff_until,
+            pending_wipe: None,
+        }
+    }
+}
+
+/// State loaded with `GlobalState::load_persisted`.
+#[derive(Debug, Clone, Default)]
+pub struct LoadedState {
+    pub state: GlobalState,
+    /// True if we had persisted state, but it was corrupt, or written by a
+    /// newer version, so we started over with the default state. We'll
+    /// fetch `meta/global` and `crypto/keys` again, but can't tell whether
+    /// they changed while we weren't looking, so stores should reset their
+    /// high-water marks, and download everything again.
+    pub discarded: bool,
+}
+
+/// The engines this client syncs, and the engines the user declined on this
+/// device. The state machine merges these into `meta/global`, and uploads it
+/// if anything changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineDeclarations {
+    /// Maps the names of engines this client syncs to their storage versions.
+    /// Engines missing from `meta/global` are added with fresh sync IDs,
+    /// unless another device declined them.
+    pub supported: HashMap<String, usize>,
+    /// Engines the user declined on this device. These are added to the
+    /// `declined` list in `meta/global`, and removed from its engines.
+    /// Engines declined on other devices stay declined, unless they're in
+    /// `undeclined`.
+    pub declined: HashSet<String>,
+    /// Supported engines the user enabled again on this device, after they
+    /// were declined here or on another device. These are removed from the
+    /// `declined` list in `meta/global`, and added back with fresh sync IDs.
+    /// Apps should only include engines the user enabled since the last
+    /// sync, so that we don't undo another device declining them later.
+    pub undeclined: HashSet<String>,
+}
+
+impl EngineDeclarations {
+    /// Declares that we sync records of type `R`, using its collection name
+    /// and storage version.
+    pub fn support<R: SyncRecord>(&mut self) -> &mut Self {
+        self.supported.insert(R::COLLECTION.into(), R::STORAGE_VERSION);
+        self
+    }
+
+    /// Returns a copy of `global` with our declarations merged in, or `None`
+    /// if `global` already reflects them.
+    fn merge_into(&self, global: &MetaGlobalRecord) -> error::Result<Option<MetaGlobalRecord>> {
+        let mut new_global = global.clone();
+        new_global.declined.retain(|name| {
+            !self.undeclined.contains(name) || !self.supported.contains_key(name)
+        });
+        let mut declined = self.declined.iter().collect::<Vec<_>>();
+        declined.sort();
+        for name in declined {
+            new_global.engines.remove(name);
+            if !new_global.declined.contains(name) {
+                new_global.declined.push(name.clone());
+            }
+        }
+        for (name, version) in &self.supported {
+            if new_global.engines.contains_key(name) || new_global.declined.contains(name) {
+                continue;
+            }
+            new_global.engines.insert(
+                name.clone(),
+                MetaGlobalEngine {
+                    version: *version,
+                    sync_id: random_guid()?,
+                },
+            );
+        }
+        Ok(if new_global == *global {
+            None
+        } else {
+            Some(new_global)
+        })
+    }
 }
 
 /// Holds global Sync state, including server upload limits, and the
@@ -56,18 +193,89 @@ pub struct GlobalState {
     pub global: Option<BsoRecord<MetaGlobalRecord>>,
     pub keys: Option<CollectionKeys>,
     pub engine_state_changes: Vec<EngineStateChange>,
+    /// If the server asked us to back off, the time before which we shouldn't
+    /// sync again.
+    pub backoff_until: Option<SystemTime>,
+    /// If we rotated the keys, but didn't finish wiping the server, the
+    /// modified time of the `meta/global` that we uploaded with the new sync
+    /// IDs. The next sync finishes the wipe.
+    pub pending_wipe: Option<ServerTimestamp>,
 }
 
 impl GlobalState {
     pub fn to_persistable_string(&self) -> String {
-        let state = PersistedState::V1(self.clone());
+        let state = PersistedState::V3(self.clone());
         serde_json::to_string(&state)
             .expect("Should only fail for recursive types (this is not recursive)")
     }
 
+    /// Parses and migrates a persisted state, and checks that it makes sense.
+    /// Fails with `InvalidPersistedState` if it doesn't.
     pub fn from_persisted_string(data: &str) -> error::Result<Self> {
-        match serde_json::from_str(data)? {
-            PersistedState::V1(global_state) => Ok(global_state)
+        let state = match serde_json::from_str(data)? {
+            PersistedState::V1(state) => {
+                info!("Migrating persisted state from V1 to V3");
+                GlobalState::from(GlobalStateV2::from(state))
+            }
+            PersistedState::V2(state) => {
+                info!("Migrating persisted state from V2 to V3");
+                GlobalState::from(state)
+            }
+            PersistedState::V3(state) => state,
+        };
+        state.validate()?;
+        Ok(state)
+    }
+
+    /// Loads a persisted state, if there is one. Unlike
+    /// `from_persisted_string`, this doesn't fail: if the state is corrupt,
+    /// we start over with the default state, and set `discarded`, so that
+    /// stores know to reset their high-water marks.
+    pub fn load_persisted(data: Option<&str>) -> LoadedState {
+        let data = match data {
+            Some(data) => data,
+            None => {
+                info!("No previously persisted global state, using default");
+                return LoadedState::default();
+            }
+        };
+        match GlobalState::from_persisted_string(data) {
+            Ok(state) => LoadedState { state, discarded: false },
+            Err(e) => {
+                // Don't log JSON errors, since they might contain sensitive
+                // info like keys.
+                match e.kind() {
+                    ErrorKind::InvalidPersistedState(reason) => {
+                        error!("Discarding invalid persisted global state: {}", reason);
+                    }
+                    _ => error!("Failed to parse persisted global state; discarding it"),
+                }
+                LoadedState { state: GlobalState::default(), discarded: true }
+            }
+        }
+    }
+
+    fn validate(&self) -> error::Result<()> {
+        if let Some(ref global) = self.global {
+            if global.payload.storage_version > STORAGE_VERSION {
+                return Err(ErrorKind::InvalidPersistedState(
+                    "meta/global has a newer storage version").into());
+            }
+        } else if self.keys.is_some() {
+            return Err(ErrorKind::InvalidPersistedState(
+                "crypto/keys without meta/global").into());
+        }
+        Ok(())
+    }
+
+    /// Returns a `BackoffError` if the server asked us to back off, and the
+    /// backoff period hasn't elapsed yet.
+    pub fn check_backoff(&self) -> error::Result<()> {
+        match self.backoff_until {
+            Some(until) if until > SystemTime::now() => {
+                Err(ErrorKind::BackoffError(until).into())
+            }
+            _ => Ok(()),
         }
     }
 
@@ -78,11 +286,148 @@ impl GlobalState {
             .key_for_collection(collection))
     }
 
+    /// Refetches `crypto/keys` from the server, and replaces our cached keys
+    /// if they changed. This is useful if we see an HMAC mismatch, which
+    /// usually means another client changed the keys since we last fetched
+    /// them. Like the state machine, we record engine state changes for any
+    /// keys that changed. Returns `true` if the keys changed.
+    pub fn refresh_keys(
+        &mut self,
+        client: &SetupStorageClient,
+        root_key: &KeyBundle,
+    ) -> error::Result<bool> {
+        let new_keys = CollectionKeys::from_encrypted_bso(client.fetch_crypto_keys()?, root_key)?;
+        if self.keys.as_ref() == Some(&new_keys) {
+            return Ok(false);
+        }
+        info!("Keys changed on the server; updating our cached keys");
+        let previous_state = mem::replace(self, GlobalState::default());
+        *self = resolve_keys(previous_state, new_keys);
+        Ok(true)
+    }
+
+    /// Replaces the keys on the server with new random ones, like after a
+    /// password reset, or if we think the keys were compromised. Each
+    /// collection in `separate_collections` gets its own key; the others
+    /// use the new default key. `self` must already be ready.
+    ///
+    /// Other clients can't decrypt existing records with the new keys, so we
+    /// give each engine a new sync ID in `meta/global`, and wipe every
+    /// collection on the server. That tells other clients to reset, and
+    /// reupload their records with the new keys. Like a fresh start, we
+    /// forget our cached `meta/global` and `crypto/keys`, so that the next
+    /// sync fetches the new ones, and resets our stores, too.
+    ///
+    /// We upload the keys first, so that we don't change anything if another
+    /// client changed them since we fetched them, and wipe last, so that we
+    /// never delete records without also changing the sync IDs. This fails
+    /// with `ConcurrentModification` if another client changed the keys or
+    /// `meta/global`. If it fails after uploading the keys, we still forget
+    /// our cached state; callers should advance the state machine to fetch
+    /// the new keys and `meta/global`, then call this again to finish
+    /// rotating. If it fails while wiping, we record a `pending_wipe`, which
+    /// `SyncManager` finishes on the next sync.
+    pub fn rotate_keys(
+        &mut self,
+        client: &Sync15StorageClient,
+        root_key: &KeyBundle,
+        separate_collections: &[&str],
+    ) -> error::Result<()> {
+        let (global, keys_modified) = match (&self.global, &self.keys) {
+            (Some(global), Some(keys)) => (global.clone(), keys.timestamp),
+            (_, None) => return Err(ErrorKind::NoCryptoKeys.into()),
+            (None, _) => return Err(ErrorKind::NoMetaGlobal.into()),
+        };
+
+        let mut new_keys = CollectionKeys::new_random()?;
+        for collection in separate_collections {
+            new_keys.collections.insert((*collection).into(), KeyBundle::new_random()?);
+        }
+        let mut new_global = global.payload.clone();
+        for engine in new_global.engines.values_mut() {
+            engine.sync_id = random_guid()?;
+        }
+        let new_global = BsoRecord::new_record("global".into(), "meta".into(), new_global);
+
+        info!("Rotating keys, with separate keys for {:?}", separate_collections);
+        client.put_crypto_keys(&new_keys.to_encrypted_bso(root_key)?, Some(keys_modified))
+              .map_err(|e| concurrent_modification(e, "crypto"))?;
+        // The server has the new keys now, so our cached state is stale even
+        // if the rest fails.
+        self.collections = InfoCollections::default();
+        self.global = None;
+        self.keys = None;
+        let global_modified = client.put_meta_global(&new_global, Some(global.modified))
+                                    .map_err(|e| concurrent_modification(e, "meta"))?;
+        self.pending_wipe = Some(global_modified);
+        self.finish_pending_wipe(client)
+    }
+
+    /// Finishes wiping the server after `rotate_keys`, if there's a
+    /// `pending_wipe`. Once the new sync IDs are in `meta/global`, other
+    /// clients can reset and reupload their records with the new keys, so we
+    /// only wipe collections that haven't changed since then.
+    pub fn finish_pending_wipe(&mut self, client: &Sync15StorageClient) -> error::Result<()> {
+        let global_modified = match self.pending_wipe {
+            Some(global_modified) => global_modified,
+            None => return Ok(()),
+        };
+        // Wipe every collection that has records encrypted with the old
+        // keys, including ones for engines that aren't in `meta/global`.
+        let collections = client.fetch_info_collections()?;
+        let mut names = collections.keys()
+                                   .filter(|name| *name != "meta" && *name != "crypto")
+                                   .collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            if collections[name] > global_modified {
+                info!("Not wiping {}, since it changed after we rotated keys", name);
+                continue;
+            }
+            info!("Wiping {}", name);
+            match client.delete_collection(name, Some(global_modified)) {
+                Ok(_) => {}
+                // Another client uploaded records with the new keys since we
+                // fetched `info/collections`.
+                Err(ref e) if is_precondition_failed(e) => {
+                    info!("Not wiping {}, since it changed after we rotated keys", name);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.pending_wipe = None;
+        Ok(())
+    }
+
+    /// Refetches `info/collections`, after we find out that a collection
+    /// changed since we last fetched it.
+    pub fn refresh_collections(&mut self, client: &SetupStorageClient) -> error::Result<()> {
+        self.collections = client.fetch_info_collections()?;
+        Ok(())
+    }
+
+    /// Forgets everything we cached about the server, after we've been
+    /// reassigned to a different storage node. The new node won't have any of
+    /// our data, so the next time we advance the state machine, we'll fetch
+    /// or upload a fresh `meta/global` and `crypto/keys`. We also record an
+    /// `EngineStateChange::ResetAll`, which stays pending until each engine
+    /// resets locally, even if the next sync fails before it gets that far.
+    /// Server limits and backoffs are kept.
+    pub fn reset_for_new_node(&mut self) {
+        self.collections = InfoCollections::default();
+        self.global = None;
+        self.keys = None;
+        self.engine_state_changes = vec![EngineStateChange::ResetAll];
+        // The new node doesn't have any records to wipe.
+        self.pending_wipe = None;
+    }
+
     pub fn last_modified_or_zero(&self, coll: &str) -> ServerTimestamp {
         self.collections.get(coll).cloned().unwrap_or(SERVER_EPOCH)
     }
 
-    /// Returns a set of all engine names that should be reset locally.
+    /// Returns a set of all engine names that should be reset locally. Resets
+    /// stay pending across syncs until `local_reset_finished` is called.
     pub fn engines_that_need_local_reset(&self) -> HashSet<String> {
         let all_engines = self.global
             .as_ref()
@@ -116,6 +461,18 @@ impl GlobalState {
         }
         engines_to_reset
     }
+
+    /// Records that we reset `engine` locally, so that we don't reset it
+    /// again. Resets for other engines stay pending.
+    pub fn local_reset_finished(&mut self, engine: &str) {
+        let mut remaining = self.engines_that_need_local_reset()
+                                .into_iter()
+                                .filter(|name| name != engine)
+                                .collect::<Vec<_>>();
+        remaining.sort();
+        self.engine_state_changes.retain(|change| !change.is_reset());
+        self.engine_state_changes.extend(remaining.into_iter().map(EngineStateChange::Reset));
+    }
 }
 
 fn resolve_global(
@@ -146,6 +503,8 @@ fn resolve_global(
         global: Some(new_global),
         keys: previous_keys,
         engine_state_changes: changes,
+        backoff_until: previous_state.backoff_until,
+        pending_wipe: previous_state.pending_wipe,
     }
 }
 
@@ -225,13 +584,62 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
         global: previous_state.global,
         keys: Some(new_keys),
         engine_state_changes: changes,
+        backoff_until: previous_state.backoff_until,
+        pending_wipe: previous_state.pending_wipe,
+    }
+}
+
+fn is_precondition_failed(err: &error::Error) -> bool {
+    match err.kind() {
+        ErrorKind::StorageHttpError { code: 412, .. } => true,
+        _ => false,
+    }
+}
+
+/// Turns a 412 from a write to `collection` into a `ConcurrentModification`.
+fn concurrent_modification(err: error::Error, collection: &str) -> error::Error {
+    if is_precondition_failed(&err) {
+        ErrorKind::ConcurrentModification(collection.into()).into()
+    } else {
+        err
+    }
+}
+
+/// Replaces our `meta/global` with one that we just uploaded, and records
+/// changes for the engines we enabled or disabled. Engines that we added
+/// have fresh sync IDs, so they also need a local reset.
+fn resolve_uploaded_global(
+    previous_state: GlobalState,
+    new_global: BsoRecord<MetaGlobalRecord>,
+) -> GlobalState {
+    let mut changes = previous_state.engine_state_changes;
+    if let Some(previous_global) = &previous_state.global {
+        for change in engine_state_changes_from_new_global(previous_global, &new_global) {
+            if let EngineStateChange::Enable(name) = &change {
+                changes.push(EngineStateChange::Reset(name.clone()));
+            }
+            changes.push(change);
+        }
+    }
+    let mut collections = (*previous_state.collections).clone();
+    collections.insert("meta".into(), new_global.modified);
+    GlobalState {
+        config: previous_state.config,
+        collections: InfoCollections::new(collections),
+        global: Some(new_global),
+        keys: previous_state.keys,
+        engine_state_changes: changes,
+        backoff_until: previous_state.backoff_until,
+        pending_wipe: previous_state.pending_wipe,
     }
 }
 
 /// Creates a fresh `meta/global` record, using the default engine selections,
-/// and declined engines from the previous record.
+/// and declined engines from the previous record, merged with our
+/// declarations.
 fn new_global_from_previous(
     previous_global: Option<BsoRecord<MetaGlobalRecord>>,
+    declarations: Option<&EngineDeclarations>,
 ) -> error::Result<MetaGlobalRecord> {
     let sync_id = random_guid()?;
     let mut engines: HashMap<String, _> = HashMap::new();
@@ -245,7 +653,7 @@ fn new_global_from_previous(
             },
         );
     }
-    Ok(MetaGlobalRecord {
+    let new_global = MetaGlobalRecord {
         sync_id,
         storage_version: STORAGE_VERSION,
         engines,
@@ -258,6 +666,10 @@ fn new_global_from_previous(
                     .map(|name| name.to_string())
                     .collect()
             }),
+    };
+    Ok(match declarations {
+        Some(declarations) => declarations.merge_into(&new_global)?.unwrap_or(new_global),
+        None => new_global,
     })
 }
 
@@ -266,6 +678,7 @@ pub struct SetupStateMachine<'client, 'keys> {
     root_key: &'keys KeyBundle,
     allowed_states: Vec<&'static str>,
     sequence: Vec<&'static str>,
+    declarations: Option<EngineDeclarations>,
 }
 
 impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
@@ -286,6 +699,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 "NeedsFreshMetaGlobal",
                 "HasMetaGlobal",
                 "ResolveMetaGlobal",
+                "NeedsMetaGlobalUpdate",
                 "NeedsFreshCryptoKeys",
                 "Ready",
                 "FreshStartRequired",
@@ -348,9 +762,19 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             root_key,
             sequence: Vec::new(),
             allowed_states,
+            declarations: None,
         }
     }
 
+    /// Declares the engines this client supports, and the engines the user
+    /// declined on this device. If `meta/global` doesn't reflect them, and
+    /// the state machine is allowed to upload, we merge them in and upload a
+    /// new `meta/global`.
+    pub fn declare_engines(&mut self, declarations: EngineDeclarations) -> &mut Self {
+        self.declarations = Some(declarations);
+        self
+    }
+
     fn advance(&self, from: SetupState) -> error::Result<SetupState> {
         match from {
             // Fetch `info/configuration` with current server limits, and
@@ -359,12 +783,19 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 let config = self.client
                     .fetch_info_configuration()
                     .unwrap_or(state.config);
+                // Keep resets that we haven't applied yet, like ones from a
+                // sync that was interrupted, or a key change while we were
+                // syncing. Other changes are recomputed from `meta/global`.
+                let mut engine_state_changes = state.engine_state_changes;
+                engine_state_changes.retain(EngineStateChange::is_reset);
                 Ok(InitialWithLiveTokenAndConfig(GlobalState {
                     config,
                     collections: state.collections,
                     global: state.global,
                     keys: state.keys,
-                    engine_state_changes: Vec::new(),
+                    engine_state_changes,
+                    backoff_until: state.backoff_until,
+                    pending_wipe: state.pending_wipe,
                 }))
             }
 
@@ -376,6 +807,8 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: state.engine_state_changes,
+                    backoff_until: state.backoff_until,
+                    pending_wipe: state.pending_wipe,
                 }))
             }
 
@@ -407,6 +840,8 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: None,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        backoff_until: state.backoff_until,
+                        pending_wipe: state.pending_wipe,
                     }),
                 })
             }
@@ -442,8 +877,19 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             // Check if our locally cached `crypto/keys` collection is
             // up-to-date.
             HasMetaGlobal(state) => {
-                // TODO(lina): Check if we've enabled or disabled any engines
-                // locally, and update `m/g` to reflect that.
+                // If we declared engines that `m/g` doesn't reflect yet,
+                // update it first.
+                let updated_global = match (&self.declarations, &state.global) {
+                    (Some(declarations), Some(global))
+                        if self.allowed_states.contains(&"NeedsMetaGlobalUpdate") =>
+                    {
+                        declarations.merge_into(global)?
+                    }
+                    _ => None,
+                };
+                if let Some(new_global) = updated_global {
+                    return Ok(NeedsMetaGlobalUpdate(state, new_global));
+                }
                 let action = {
                     let local = state.keys.as_ref().map(|keys| &keys.timestamp);
                     let remote = state.collections.get("crypto");
@@ -463,10 +909,35 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: state.global,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        backoff_until: state.backoff_until,
+                        pending_wipe: state.pending_wipe,
                     }),
                 })
             }
 
+            // Upload a `meta/global` with our declared engines merged in, as
+            // long as no one else changed it since we fetched it.
+            NeedsMetaGlobalUpdate(state, new_global) => {
+                let xius = state.global.as_ref().map(|global| global.modified);
+                let mut new_global =
+                    BsoRecord::new_record("global".into(), "meta".into(), new_global);
+                match self.client.put_meta_global(&new_global, xius) {
+                    Ok(modified) => {
+                        new_global.modified = modified;
+                        let new_state = resolve_uploaded_global(state, new_global);
+                        Ok(HasMetaGlobal(new_state))
+                    }
+                    Err(err) => match err.kind() {
+                        // We lost the race with another client, so fetch
+                        // its `meta/global`, and merge into that instead.
+                        ErrorKind::StorageHttpError { code: 412, .. } => {
+                            Ok(NeedsFreshMetaGlobal(state))
+                        }
+                        _ => Err(err),
+                    },
+                }
+            }
+
             NeedsFreshCryptoKeys(state) => {
                 match self.client.fetch_crypto_keys() {
                     Ok(encrypted_bso) => {
@@ -494,15 +965,15 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 let new_global = BsoRecord::new_record(
                     "global".into(),
                     "meta".into(),
-                    new_global_from_previous(state.global)?,
+                    new_global_from_previous(state.global, self.declarations.as_ref())?,
                 );
-                self.client.put_meta_global(&new_global)?;
+                self.client.put_meta_global(&new_global, None)?;
 
                 // ...And a fresh `crypto/keys`. Note that we'll update the
                 // global state when we go around the state machine again,
                 // not here.
                 let new_keys = CollectionKeys::new_random()?.to_encrypted_bso(&self.root_key)?;
-                self.client.put_crypto_keys(&new_keys)?;
+                self.client.put_crypto_keys(&new_keys, None)?;
 
                 // TODO(lina): Can we pass along server timestamps from the PUTs
                 // above, and avoid re-fetching the `m/g` and `c/k` we just
@@ -513,11 +984,23 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: None,
                     keys: None,
                     engine_state_changes: vec![EngineStateChange::ResetAll],
+                    backoff_until: state.backoff_until,
+                    pending_wipe: state.pending_wipe,
                 }))
             }
         }
     }
 
+    /// Returns the states we went through in the last call to `to_ready`,
+    /// for telemetry.
+    pub fn sequence(&self) -> &[&'static str] {
+        &self.sequence
+    }
+
+    fn times_visited(&self, label: &str) -> usize {
+        self.sequence.iter().filter(|s| **s == label).count()
+    }
+
     /// Runs through the state machine to the ready state.
     pub fn to_ready(&mut self, state: GlobalState) -> error::Result<GlobalState> {
         let mut s = InitialWithLiveToken(state);
@@ -536,6 +1019,13 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 FreshStartRequired(_) if self.sequence.contains(&label) => {
                     return Err(ErrorKind::SetupStateCycleError.into());
                 }
+                // Likewise, if we keep losing the race to update
+                // `meta/global`, give up until the next sync.
+                NeedsMetaGlobalUpdate(..)
+                    if self.times_visited(label) >= MAX_META_GLOBAL_UPDATES =>
+                {
+                    return Err(ErrorKind::SetupStateCycleError.into());
+                }
                 previous_s => {
                     if !self.allowed_states.contains(&label) {
                         return Err(ErrorKind::DisallowedStateError(&label).into());
@@ -558,6 +1048,7 @@ enum SetupState {
     NeedsFreshMetaGlobal(GlobalState),
     HasMetaGlobal(GlobalState),
     ResolveMetaGlobal(GlobalState, BsoRecord<MetaGlobalRecord>),
+    NeedsMetaGlobalUpdate(GlobalState, MetaGlobalRecord),
     NeedsFreshCryptoKeys(GlobalState),
     Ready(GlobalState),
     FreshStartRequired(GlobalState),
@@ -572,6 +1063,7 @@ impl SetupState {
             NeedsFreshMetaGlobal(_) => "NeedsFreshMetaGlobal",
             HasMetaGlobal(_) => "HasMetaGlobal",
             ResolveMetaGlobal(_, _) => "ResolveMetaGlobal",
+            NeedsMetaGlobalUpdate(_, _) => "NeedsMetaGlobalUpdate",
             NeedsFreshCryptoKeys(_) => "NeedsFreshCryptoKeys",
             Ready(_) => "Ready",
             FreshStartRequired(_) => "FreshStartRequired",
@@ -618,6 +1110,18 @@ pub enum EngineStateChange {
     Reset(String),
 }
 
+impl EngineStateChange {
+    /// Returns true if this change resets one or more engines locally.
+    pub fn is_reset(&self) -> bool {
+        match self {
+            EngineStateChange::ResetAll |
+            EngineStateChange::ResetAllExcept(_) |
+            EngineStateChange::Reset(_) => true,
+            _ => false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -664,7 +1168,11 @@ mod tests {
             }
         }
 
-        fn put_meta_global(&self, _global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
+        fn put_meta_global(
+            &self,
+            _global: &BsoRecord<MetaGlobalRecord>,
+            _xius: Option<ServerTimestamp>,
+        ) -> error::Result<ServerTimestamp> {
             Err(ErrorKind::StorageHttpError {
                 code: 500,
                 route: "meta/global".to_string(),
@@ -682,7 +1190,11 @@ mod tests {
             }
         }
 
-        fn put_crypto_keys(&self, _keys: &EncryptedBso) -> error::Result<()> {
+        fn put_crypto_keys(
+            &self,
+            _keys: &EncryptedBso,
+            _xius: Option<ServerTimestamp>,
+        ) -> error::Result<ServerTimestamp> {
             Err(ErrorKind::StorageHttpError {
                 code: 500,
                 route: "crypto/keys".to_string(),
@@ -757,4 +1269,158 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    #[test]
+    fn test_merge_declarations() {
+        let global = MetaGlobalRecord {
+            sync_id: "syncIDAAAAAA".to_owned(),
+            storage_version: 5usize,
+            engines: vec![
+                ("bookmarks", 2usize, "syncIDBBBBBB"),
+                ("passwords", 1usize, "syncIDCCCCCC"),
+                ("tabs", 1usize, "syncIDDDDDDD"),
+            ].into_iter()
+                .map(|(name, version, sync_id)| {
+                    (name.to_owned(), MetaGlobalEngine { version, sync_id: sync_id.to_owned() })
+                })
+                .collect(),
+            declined: vec!["history".to_owned(), "forms".to_owned()],
+        };
+
+        let declarations = EngineDeclarations {
+            supported: vec![("passwords", 1usize), ("history", 1usize), ("addresses", 1usize)]
+                .into_iter()
+                .map(|(name, version)| (name.to_owned(), version))
+                .collect(),
+            declined: vec!["tabs".to_owned()].into_iter().collect(),
+            undeclined: HashSet::new(),
+        };
+        let merged = declarations.merge_into(&global).unwrap().expect("Should change m/g");
+        // We should decline tabs, and add addresses, but not re-enable
+        // history, since another device declined it.
+        let mut names = merged.engines.keys().map(|name| name.as_str()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["addresses", "bookmarks", "passwords"]);
+        assert_eq!(merged.engines["passwords"], global.engines["passwords"]);
+        assert_eq!(merged.declined,
+                   vec!["history".to_owned(), "forms".to_owned(), "tabs".to_owned()]);
+
+        // Merging again shouldn't change anything.
+        assert!(declarations.merge_into(&merged).unwrap().is_none());
+
+        // The user enables history again on this device, so we should add
+        // it back with a fresh sync ID. We don't support forms, so it stays
+        // declined.
+        let declarations = EngineDeclarations {
+            undeclined: vec!["history".to_owned(), "forms".to_owned()].into_iter().collect(),
+            ..declarations
+        };
+        let undeclined = declarations.merge_into(&merged).unwrap().expect("Should change m/g");
+        assert_eq!(undeclined.declined, vec!["forms".to_owned(), "tabs".to_owned()]);
+        assert_eq!(undeclined.engines["history"].version, 1);
+        assert_eq!(undeclined.engines["passwords"], merged.engines["passwords"]);
+        assert!(declarations.merge_into(&undeclined).unwrap().is_none());
+    }
+
+    fn persisted_meta_global(storage_version: usize) -> BsoRecord<MetaGlobalRecord> {
+        BsoRecord {
+            id: "global".into(),
+            modified: ServerTimestamp(999.0),
+            collection: "meta".into(),
+            sortindex: None,
+            ttl: None,
+            payload: MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".to_owned(),
+                storage_version,
+                engines: HashMap::new(),
+                declined: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_persisted_state() {
+        let mut state = GlobalState::default();
+        state.global = Some(persisted_meta_global(STORAGE_VERSION));
+        state.keys = Some(CollectionKeys::new_random().unwrap());
+        state.backoff_until = Some(SystemTime::now());
+        state.pending_wipe = Some(ServerTimestamp(123.0));
+
+        let persisted = state.to_persistable_string();
+        let loaded = GlobalState::load_persisted(Some(&persisted));
+        assert!(!loaded.discarded);
+        assert_eq!(loaded.state.keys, state.keys);
+        assert_eq!(loaded.state.backoff_until, state.backoff_until);
+        assert_eq!(loaded.state.pending_wipe, state.pending_wipe);
+
+        // V1 states don't have a backoff.
+        let mut v1 = serde_json::to_value(&state).unwrap();
+        v1["schema_version"] = "V1".into();
+        v1.as_object_mut().unwrap().remove("backoff_until");
+        v1.as_object_mut().unwrap().remove("pending_wipe");
+        let migrated = GlobalState::from_persisted_string(&v1.to_string()).unwrap();
+        assert_eq!(migrated.keys, state.keys);
+        assert_eq!(migrated.backoff_until, None);
+        assert!(migrated.to_persistable_string().contains(r#""schema_version":"V3""#));
+
+        // V2 states don't have a pending wipe.
+        let mut v2 = serde_json::to_value(&state).unwrap();
+        v2["schema_version"] = "V2".into();
+        v2.as_object_mut().unwrap().remove("pending_wipe");
+        let migrated = GlobalState::from_persisted_string(&v2.to_string()).unwrap();
+        assert_eq!(migrated.backoff_until, state.backoff_until);
+        assert_eq!(migrated.pending_wipe, None);
+
+        // A state persisted by a V1 client should load.
+        let v1 = r#"{
+            "schema_version": "V1",
+            "config": { "max_request_bytes": 1024, "max_record_payload_bytes": 512 },
+            "collections": { "meta": 999.0 },
+            "global": null,
+            "keys": null,
+            "engine_state_changes": ["ResetAll"]
+        }"#;
+        let loaded = GlobalState::load_persisted(Some(v1));
+        assert!(!loaded.discarded);
+        assert_eq!(loaded.state.config.max_request_bytes, 1024);
+        assert_eq!(loaded.state.config.max_record_payload_bytes, 512);
+        assert_eq!(loaded.state.last_modified_or_zero("meta"), ServerTimestamp(999.0));
+        match loaded.state.engine_state_changes.as_slice() {
+            [EngineStateChange::ResetAll] => {}
+            other => panic!("Unexpected engine state changes {:?}", other),
+        }
+        assert_eq!(loaded.state.backoff_until, None);
+
+        // A state persisted by a newer version, with a schema we don't know,
+        // should be discarded.
+        let mut v4 = serde_json::to_value(&state).unwrap();
+        v4["schema_version"] = "V4".into();
+        let loaded = GlobalState::load_persisted(Some(&v4.to_string()));
+        assert!(loaded.discarded);
+        assert!(loaded.state.keys.is_none());
+
+        let loaded = GlobalState::load_persisted(None);
+        assert!(!loaded.discarded);
+        assert!(loaded.state.global.is_none());
+
+        for data in &["", "{}", r#"{"schema_version":"V4"}"#, "not json"] {
+            let loaded = GlobalState::load_persisted(Some(data));
+            assert!(loaded.discarded, "Should discard {:?}", data);
+            assert!(loaded.state.global.is_none());
+        }
+
+        let mut newer = state.clone();
+        newer.global = Some(persisted_meta_global(STORAGE_VERSION + 1));
+        let err = GlobalState::from_persisted_string(&newer.to_persistable_string())
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::InvalidPersistedState(_) => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert!(GlobalState::load_persisted(Some(&newer.to_persistable_string())).discarded);
+
+        let mut keys_only = state.clone();
+        keys_only.global = None;
+        assert!(GlobalState::load_persisted(Some(&keys_only.to_persistable_string())).discarded);
+    }
 }
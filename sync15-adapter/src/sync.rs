@@ -2,11 +2,32 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::collections::{HashMap, HashSet};
+
+use bso_record::Payload;
 use changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
 use client::Sync15StorageClient;
-use error;
+use dry_run::DryRunChanges;
+use error::{self, ErrorKind};
+use interrupt::InterruptHandle;
+use key_bundle::KeyBundle;
+use request::{UploadInfo, UploadPolicy};
 use state::GlobalState;
-use util::ServerTimestamp;
+use telemetry;
+use util::{ServerTimestamp, SERVER_EPOCH};
+
+/// The maximum number of records to download per request. Large collections
+/// are downloaded in pages of this size, oldest first.
+pub const DOWNLOAD_PAGE_SIZE: usize = 1000;
+
+/// How many times we'll restart downloading a collection because another
+/// client wrote to it at the same time, before giving up until the next sync.
+const MAX_DOWNLOAD_RESTARTS: usize = 2;
+
+/// How many syncs in a row we'll try to upload a record from a `RetrySet`
+/// that the server keeps rejecting, before giving up on it until it changes
+/// locally.
+pub const MAX_UPLOAD_ATTEMPTS: u32 = 3;
 
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
@@ -18,46 +39,326 @@ use util::ServerTimestamp;
 pub trait Store {
     type Error;
 
+    /// The name of the collection this store syncs, like "passwords". This
+    /// is also the engine name in `meta/global`.
+    fn collection_name(&self) -> &'static str;
+
+    /// Returns the server timestamp of the last successful sync, or `None`
+    /// if we've never synced (or reset since).
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>, Self::Error>;
+
+    /// Resets local sync metadata, so that the next sync downloads all
+    /// records and reuploads all local records. Called when the engine's
+    /// sync ID or collection key changes.
+    fn reset(&self) -> Result<(), Self::Error>;
+
+    /// Applies a page of incoming records, and returns all of the store's
+    /// outgoing changes. Large collections are downloaded in pages, so this
+    /// may be called several times per sync; we only upload the outgoing
+    /// changes returned for the last page. Stores that take a while to apply
+    /// records should check `interrupt` as they go. Stores should add the
+    /// number of records they applied, reconciled, or failed to apply to
+    /// `incoming_telemetry`.
     fn apply_incoming(
         &self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        interrupt: &InterruptHandle,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset, Self::Error>;
 
+    /// Like `apply_incoming`, but for `dry_run`: returns the records that
+    /// would change locally, and the outgoing changes, without changing
+    /// anything. Stores that keep their records in a database can apply the
+    /// records in a transaction, and roll it back. `inbound` has all incoming
+    /// records, not just a page. The default returns `None`, meaning the
+    /// store doesn't support dry runs.
+    fn dry_run_incoming(
+        &self,
+        _inbound: IncomingChangeset,
+        _incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<Option<DryRunChanges>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Called after each page of incoming records is applied, with a
+    /// timestamp that the next sync can safely pass as `since` if this one is
+    /// interrupted before `sync_finished`. Stores typically persist this as
+    /// their last sync time, so that the next sync resumes the download
+    /// instead of starting over.
+    fn set_high_water_mark(
+        &self,
+        high_water_mark: ServerTimestamp,
+    ) -> Result<(), Self::Error>;
+
+    /// Called after uploading, with the IDs of the records that the server
+    /// accepted, and a map of the IDs of the records that we failed to upload
+    /// to the reason why. Records only fail this way if the store's
+    /// `upload_policy` allows it; otherwise, a record that's too large to
+    /// upload, even after `shrink_record`, or that the server rejects, fails
+    /// the sync. Stores should keep failed records marked as changed, so that
+    /// they're uploaded again on the next sync, or keep a `RetrySet`.
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
         records_synced: &[String],
+        records_failed: &HashMap<String, String>,
     ) -> Result<(), Self::Error>;
+
+    /// Returns what to do if the server rejects some of our outgoing records.
+    /// The default fails the sync.
+    fn upload_policy(&self) -> UploadPolicy {
+        UploadPolicy::Atomic
+    }
+
+    /// Called with an outgoing record that's too large to upload, and the
+    /// server's `max_record_payload_bytes`. Stores can return a smaller
+    /// version of the record to upload instead, like a history entry with
+    /// fewer visits; if that's still too large, this is called again with the
+    /// smaller version. The default returns `None`, giving up on the record.
+    fn shrink_record(&self, _payload: Payload, _max_payload_bytes: usize) -> Option<Payload> {
+        None
+    }
+
+    /// Returns the records that failed to upload on earlier syncs, if the
+    /// store keeps a retry set. These are uploaded again along with the
+    /// outgoing changes, unless they changed locally or remotely since. The
+    /// default doesn't keep one.
+    fn get_retry_set(&self) -> Result<Option<RetrySet>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Persists the retry set after uploading. Only called if
+    /// `get_retry_set` returned a set.
+    fn set_retry_set(&self, _retry_set: RetrySet) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Outgoing records that failed to upload, so that we can upload them again on
+/// the next sync even if they don't change locally. Stores that don't track
+/// which records changed since the last sync can keep one of these, and
+/// persist it as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetrySet {
+    records: HashMap<String, RetryRecord>,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RetryRecord {
+    payload: Payload,
+    reason: String,
+    attempts: u32,
+}
+
+impl RetrySet {
+    pub fn new() -> RetrySet {
+        RetrySet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns why the record with the given ID failed to upload the last
+    /// time we tried, or `None` if it's not in the set.
+    pub fn reason(&self, id: &str) -> Option<&str> {
+        self.records.get(id).map(|record| record.reason.as_str())
+    }
+
+    /// Adds the records to retry to `outgoing`. Records that changed remotely
+    /// since were already reconciled by the store, and records that changed
+    /// locally have a newer version in `outgoing`, so we drop those instead.
+    fn add_to_outgoing(&mut self, outgoing: &mut OutgoingChangeset,
+                       incoming_ids: &HashSet<String>) {
+        for payload in &outgoing.changes {
+            self.records.remove(&payload.id);
+        }
+        self.records.retain(|id, _| !incoming_ids.contains(id));
+        outgoing.changes.extend(self.records.values().map(|record| record.payload.clone()));
+    }
+
+    /// Updates the set after uploading `sent`: records that failed are added,
+    /// and everything else is removed.
+    fn update(&mut self, sent: Vec<Payload>, upload_info: &UploadInfo) {
+        for payload in sent {
+            let reason = match upload_info.failed.get(&payload.id) {
+                Some(reason) => reason.clone(),
+                None => {
+                    self.records.remove(&payload.id);
+                    continue;
+                }
+            };
+            let attempts = self.records.get(&payload.id).map_or(0, |record| record.attempts) + 1;
+            if attempts >= MAX_UPLOAD_ATTEMPTS {
+                warn!("Giving up on uploading {} after {} attempts: {}", payload.id, attempts,
+                      reason);
+                self.records.remove(&payload.id);
+                continue;
+            }
+            self.records.insert(payload.id.clone(), RetryRecord { payload, reason, attempts });
+        }
+    }
+}
+
+/// Details about a collection that synced successfully.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CollectionSyncInfo {
+    /// IDs of incoming records that we skipped because we couldn't decrypt
+    /// them, even after refetching `crypto/keys`.
+    pub bad_incoming_ids: Vec<String>,
+}
+
+/// Syncs a single collection. `state` is updated if we need to refetch
+/// `crypto/keys` with `root_key`, or `info/collections` because another
+/// client wrote to the collection while we were downloading it, so callers
+/// should persist it afterward, even if this fails. `policy` decides whether
+/// records that the server rejects fail the sync. If `interrupt` is
+/// interrupted, we stop at the next request, or after the store applies the
+/// current page. Incoming and outgoing counts, and the reason for any
+/// failure, are recorded in `telem_engine`.
 pub fn synchronize<E>(client: &Sync15StorageClient,
-                   state: &GlobalState,
+                   state: &mut GlobalState,
+                   root_key: &KeyBundle,
                    store: &Store<Error=E>,
-                   collection: String,
                    timestamp: ServerTimestamp,
-                   fully_atomic: bool) -> Result<(), E>
+                   policy: UploadPolicy,
+                   interrupt: &InterruptHandle,
+                   telem_engine: &mut telemetry::Engine) -> Result<CollectionSyncInfo, E>
 where E: From<error::Error>
 {
+    client.set_interrupt_handle(interrupt.clone());
 
+    let collection: String = store.collection_name().into();
     info!("Syncing collection {}", collection);
-    let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(), timestamp)?;
-    let last_changed_remote = incoming_changes.timestamp;
+    let mut outgoing;
+    let mut info = CollectionSyncInfo::default();
+    let mut num_incoming = 0;
+    let mut since = timestamp;
+    let mut resume_from = timestamp;
+    let mut offset = None;
+    let mut restarts = 0;
+    let mut incoming_ids = HashSet::new();
+    loop {
+        let result = IncomingChangeset::fetch_page(client, state, root_key, collection.clone(),
+                                                   since, DOWNLOAD_PAGE_SIZE, offset.take());
+        let mut page = match result {
+            Ok(page) => page,
+            Err(ref e) if is_concurrent_modification(e) && restarts < MAX_DOWNLOAD_RESTARTS => {
+                // The rest of the pages might skip records that moved, so
+                // start over from the last page we know we applied in full.
+                restarts += 1;
+                since = resume_from;
+                warn!("{} changed while downloading; restarting from {}", collection, since);
+                state.refresh_collections(client).map_err(|e| telem_engine.record_error(e))?;
+                continue;
+            }
+            Err(e) => return Err(telem_engine.record_error(e).into()),
+        };
+        if page.keys_changed && state.engines_that_need_local_reset().contains(&collection) {
+            // The records we already applied, and our high-water mark, are
+            // from before the keys changed, and we might have skipped records
+            // that we can decrypt now. Reset, and download everything again.
+            warn!("Keys for {} changed while downloading; resetting and restarting",
+                  collection);
+            store.reset().map_err(|e| telem_engine.record_store_error(e))?;
+            state.local_reset_finished(&collection);
+            since = SERVER_EPOCH;
+            resume_from = since;
+            num_incoming = 0;
+            incoming_ids.clear();
+            info.bad_incoming_ids.clear();
+            continue;
+        }
+        num_incoming += page.changeset.changes.len();
+        incoming_ids.extend(page.changeset.changes.iter().map(|(payload, _)| payload.id.clone()));
+        telem_engine.incoming().failed(page.bad_record_ids.len() as u32);
+        info.bad_incoming_ids.append(&mut page.bad_record_ids);
+        info!("Downloaded {} remote changes ({} so far)", page.changeset.changes.len(),
+              num_incoming);
+
+        outgoing = store.apply_incoming(page.changeset, interrupt, telem_engine.incoming())
+            .map_err(|e| telem_engine.record_store_error(e))?;
+
+        if let Some(high_water_mark) = page.high_water_mark {
+            store.set_high_water_mark(high_water_mark)
+                .map_err(|e| telem_engine.record_store_error(e))?;
+            resume_from = high_water_mark;
+        }
+
+        offset = page.next_offset;
+        if offset.is_none() {
+            break;
+        }
+    }
+
+    // Don't bother encrypting and uploading if we were interrupted while the
+    // store was applying the last page.
+    interrupt.err_if_interrupted().map_err(|e| telem_engine.record_error(e))?;
 
-    info!("Downloaded {} remote changes", incoming_changes.changes.len());
-    let mut outgoing = store.apply_incoming(incoming_changes)?;
+    // This is after downloading, since we might have refetched
+    // `info/collections` along the way.
+    outgoing.timestamp = state.last_modified_or_zero(&collection);
 
-    outgoing.timestamp = last_changed_remote;
+    let mut retry_set = store.get_retry_set().map_err(|e| telem_engine.record_store_error(e))?;
+    let sent = match retry_set {
+        Some(ref mut retry_set) => {
+            if !retry_set.is_empty() {
+                info!("Retrying {} records that failed to upload", retry_set.len());
+            }
+            retry_set.add_to_outgoing(&mut outgoing, &incoming_ids);
+            outgoing.changes.clone()
+        }
+        None => Vec::new(),
+    };
 
     info!("Uploading {} outgoing changes", outgoing.changes.len());
-    let upload_info =
-        CollectionUpdate::new_from_changeset(client, state, outgoing, fully_atomic)?.upload()?;
+    let upload_info = CollectionUpdate::new_from_changeset(client, state, outgoing, policy)
+        .and_then(|mut update| {
+            update.shrink_oversized(|payload, max_payload_bytes| {
+                store.shrink_record(payload, max_payload_bytes)
+            });
+            update.upload()
+        })
+        .map_err(|e| telem_engine.record_error(e))?;
 
     info!("Upload success ({} records success, {} records failed)",
           upload_info.successful_ids.len(),
-          upload_info.failed_ids.len());
+          upload_info.failed.len());
+    for (id, reason) in &upload_info.failed {
+        warn!("Failed to upload {}: {}", id, reason);
+    }
+    let num_sent = upload_info.successful_ids.len() + upload_info.failed.len();
+    if num_sent > 0 {
+        telem_engine.outgoing(telemetry::EngineOutgoing::new(
+            num_sent as u32,
+            upload_info.failed.len() as u32,
+        ));
+    }
 
-    store.sync_finished(upload_info.modified_timestamp, &upload_info.successful_ids)?;
+    if let Some(mut retry_set) = retry_set {
+        retry_set.update(sent, &upload_info);
+        store.set_retry_set(retry_set).map_err(|e| telem_engine.record_store_error(e))?;
+    }
+
+    store.sync_finished(upload_info.modified_timestamp, &upload_info.successful_ids,
+                        &upload_info.failed)
+        .map_err(|e| telem_engine.record_store_error(e))?;
+
+    if !info.bad_incoming_ids.is_empty() {
+        warn!("Skipped {} incoming records that failed to decrypt", info.bad_incoming_ids.len());
+    }
 
     info!("Sync finished!");
-    Ok(())
+    Ok(info)
+}
+
+fn is_concurrent_modification(e: &error::Error) -> bool {
+    match e.kind() {
+        ErrorKind::ConcurrentModification(_) => true,
+        _ => false,
+    }
 }
@@ -0,0 +1,427 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The tabs engine. Every client uploads a single record with its open tabs
+//! to the `tabs` collection, keyed by its client ID, so that other devices can
+//! show "tabs from other devices".
+//!
+//! Tabs are joined with client records to get device names, so the tabs
+//! engine is built on top of the clients engine, and should be synced after
+//! it.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+use serde_json::{self, Map, Value as JsonValue};
+
+use bso_record::EncryptedPayload;
+use changeset::{CollectionUpdate, IncomingChangeset};
+use client::Sync15StorageClient;
+use clients::{self, DeviceType};
+use error;
+use request::UploadPolicy;
+use state::GlobalState;
+use telemetry;
+use typed_store::{IncomingKind, IncomingRecord, OutgoingRecord, SyncRecord};
+use util::{ServerTimestamp, SERVER_EPOCH};
+
+const COLLECTION_NAME: &str = "tabs";
+
+/// How long our record lives on the server, in seconds, so that tabs from
+/// devices that stopped syncing eventually disappear.
+const TABS_TTL: u32 = 21 * 24 * 60 * 60;
+
+/// How often we reupload our record if our tabs haven't changed, so that it
+/// doesn't expire while we're still syncing, in seconds.
+const TABS_TTL_REFRESH: u64 = 7 * 24 * 60 * 60;
+
+/// An open tab.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tab {
+    pub title: String,
+
+    /// The tab's back history, newest first. The first URL is the one the tab
+    /// is showing.
+    #[serde(rename = "urlHistory")]
+    pub url_history: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+
+    /// When the tab was last used, in seconds since the epoch.
+    #[serde(rename = "lastUsed", default)]
+    pub last_used: u64,
+}
+
+/// A tabs record, as stored in the `tabs` collection. The ID is the client ID
+/// of the device with the tabs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TabsRecord {
+    pub id: String,
+
+    #[serde(rename = "clientName")]
+    pub client_name: String,
+
+    pub tabs: Vec<Tab>,
+}
+
+impl SyncRecord for TabsRecord {
+    const COLLECTION: &'static str = COLLECTION_NAME;
+    const STORAGE_VERSION: usize = 1;
+
+    fn record_id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// The open tabs on another device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteTabs {
+    pub client_id: String,
+    pub client_name: String,
+    /// `None` if the client's device type is one we don't know about.
+    pub device_type: Option<DeviceType>,
+    /// When the client last uploaded its tabs.
+    pub last_modified: ServerTimestamp,
+    /// Most recently used first.
+    pub tabs: Vec<Tab>,
+}
+
+/// Uploads our open tabs, and fetches tabs from other devices.
+pub struct Engine<'a> {
+    clients: &'a clients::Engine<'a>,
+    local_tabs: RefCell<Option<Vec<Tab>>>,
+    remote_records: RefCell<Vec<(TabsRecord, ServerTimestamp)>>,
+}
+
+impl<'a> Engine<'a> {
+    /// Creates a tabs engine that uploads tabs for the local client described
+    /// by `clients`, and uses its list of other clients to name their tabs.
+    pub fn new(clients: &'a clients::Engine<'a>) -> Engine<'a> {
+        Engine {
+            clients,
+            local_tabs: RefCell::new(None),
+            remote_records: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Sets the tabs to upload on the next sync. We don't upload anything
+    /// until this is called, so that we don't replace our tabs on the server
+    /// with an empty list before the app has told us about them.
+    pub fn set_local_tabs(&self, tabs: Vec<Tab>) {
+        *self.local_tabs.borrow_mut() = Some(tabs);
+    }
+
+    /// Returns the tabs of every other client with an unexpired client
+    /// record, as of the last sync, most recently uploaded first. Tabs
+    /// records for clients we don't know about are skipped, since they're
+    /// likely from devices that stopped syncing.
+    pub fn remote_tabs(&self) -> Vec<RemoteTabs> {
+        let recent_clients = self.clients.recent_clients();
+        let mut remote_tabs = self.remote_records
+            .borrow()
+            .iter()
+            .filter_map(|(record, modified)| {
+                let client = recent_clients.get(&record.id)?;
+                Some(RemoteTabs {
+                    client_id: record.id.clone(),
+                    client_name: client.name.clone(),
+                    device_type: client.device_type,
+                    last_modified: *modified,
+                    tabs: record.tabs.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        remote_tabs.sort_by(|a, b| {
+            b.last_modified.partial_cmp(&a.last_modified).expect("Server timestamps are never NaN")
+        });
+        remote_tabs
+    }
+
+    /// Fetches all tabs records, and uploads ours if our tabs changed, or if
+    /// it's about to expire. `state` must be ready. If `needs_reset` is true,
+    /// like for the clients engine, we forget other clients' tabs, and
+    /// always upload ours.
+    ///
+    /// Like the clients collection, the tabs collection is small, and we
+    /// need every other client's record, so we always fetch all of it.
+    pub fn sync(
+        &self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        needs_reset: bool,
+        telem_engine: &mut telemetry::Engine,
+    ) -> error::Result<()> {
+        if needs_reset {
+            info!("Resetting tabs engine");
+            self.remote_records.borrow_mut().clear();
+        }
+        let settings = self.clients.settings();
+        let inbound = IncomingChangeset::fetch(
+            client,
+            state,
+            COLLECTION_NAME.into(),
+            SERVER_EPOCH,
+        )?;
+
+        let mut xius = inbound.timestamp;
+        let mut current = None;
+        let mut remote_records = Vec::new();
+        for (payload, modified) in inbound.changes {
+            if modified > xius {
+                xius = modified;
+            }
+            match IncomingRecord::<TabsRecord>::from_payload(payload, modified).kind {
+                IncomingKind::Record { mut record, unknown_fields } => {
+                    telem_engine.incoming().applied(1);
+                    if record.id == settings.client_id {
+                        current = Some((record, unknown_fields, modified));
+                    } else {
+                        sort_most_recent_first(&mut record.tabs);
+                        remote_records.push((record, modified));
+                    }
+                }
+                IncomingKind::Tombstone => {}
+                IncomingKind::Malformed { payload, .. } => {
+                    warn!("Skipping malformed tabs record {}", payload.id);
+                    telem_engine.incoming().failed(1);
+                }
+            }
+        }
+        *self.remote_records.borrow_mut() = remote_records;
+
+        let mut tabs = match &*self.local_tabs.borrow() {
+            Some(tabs) => tabs.clone(),
+            None => {
+                info!("No local tabs to upload yet");
+                return Ok(());
+            }
+        };
+        sort_most_recent_first(&mut tabs);
+        let mut local = TabsRecord {
+            id: settings.client_id.clone(),
+            client_name: settings.name.clone(),
+            tabs,
+        };
+        let (unknown_fields, remote) = match current {
+            Some((remote, unknown_fields, modified)) => (unknown_fields, Some((remote, modified))),
+            None => (Map::new(), None),
+        };
+
+        truncate_to_fit(&mut local, &unknown_fields, state.config.max_record_payload_bytes)?;
+        let remote = remote.as_ref().map(|(remote, modified)| (remote, *modified));
+        if !needs_reset && !needs_upload(&local, remote, client.last_server_time()) {
+            info!("Our tabs record is up to date");
+            return Ok(());
+        }
+
+        info!("Uploading {} tabs", local.tabs.len());
+        let mut bso = OutgoingRecord::Record {
+            record: local,
+            unknown_fields,
+        }.into_payload()?.into_bso(COLLECTION_NAME.into());
+        bso.ttl = Some(TABS_TTL);
+        let encrypted = bso.encrypt(state.key_for_collection(COLLECTION_NAME)?)?;
+        let info = CollectionUpdate::new(
+            client,
+            state,
+            COLLECTION_NAME.into(),
+            xius,
+            vec![encrypted],
+            UploadPolicy::Atomic,
+        ).upload()?;
+        telem_engine.outgoing(telemetry::EngineOutgoing::new(
+            info.successful_ids.len() as u32,
+            info.failed.len() as u32,
+        ));
+        Ok(())
+    }
+}
+
+fn sort_most_recent_first(tabs: &mut [Tab]) {
+    tabs.sort_by_key(|tab| Reverse(tab.last_used));
+}
+
+/// If `record` has too many tabs to fit in `max_payload_bytes` once it's
+/// encrypted, drops the least recently used ones until it fits. Tabs must
+/// already be sorted most recent first. We estimate the encrypted size from
+/// the cleartext, and binary search for the number of tabs to keep, so that
+/// we only need to encrypt the record once.
+fn truncate_to_fit(
+    record: &mut TabsRecord,
+    unknown_fields: &Map<String, JsonValue>,
+    max_payload_bytes: usize,
+) -> error::Result<()> {
+    let count = {
+        let fits = |tabs: &[Tab]| -> error::Result<bool> {
+            let payload = OutgoingRecord::Record {
+                record: TabsRecord {
+                    id: record.id.clone(),
+                    client_name: record.client_name.clone(),
+                    tabs: tabs.to_vec(),
+                },
+                unknown_fields: unknown_fields.clone(),
+            }.into_payload()?;
+            let cleartext_len = serde_json::to_string(&payload)?.len();
+            Ok(EncryptedPayload::serialized_len_for_cleartext(cleartext_len) <= max_payload_bytes)
+        };
+        if fits(&record.tabs)? {
+            return Ok(());
+        }
+        // Keeping `low` tabs fits, or `low` is 0; keeping `high` doesn't.
+        let (mut low, mut high) = (0, record.tabs.len());
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if fits(&record.tabs[..mid])? {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    };
+    record.tabs.truncate(count);
+    Ok(())
+}
+
+/// Returns true if we should upload `local`: if we don't have a record on
+/// the server yet, if it's different, or if it's old enough that we should
+/// refresh its TTL. `remote` is our record on the server, and when it was
+/// last modified.
+fn needs_upload(
+    local: &TabsRecord,
+    remote: Option<(&TabsRecord, ServerTimestamp)>,
+    now: ServerTimestamp,
+) -> bool {
+    match remote {
+        Some((remote, modified)) => {
+            let is_stale = now
+                .duration_since(modified)
+                .map(|age| age.as_secs() >= TABS_TTL_REFRESH)
+                .unwrap_or(false);
+            is_stale || *remote != *local
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bso_record::Payload;
+    use key_bundle::KeyBundle;
+
+    fn tab(index: u64) -> Tab {
+        Tab {
+            title: format!("Tab {}", index),
+            url_history: vec![format!("https://example.com/{}", index)],
+            icon: None,
+            last_used: 1_500_000_000 + index,
+        }
+    }
+
+    fn record(tabs: Vec<Tab>) -> TabsRecord {
+        TabsRecord {
+            id: "aaaaaaaaaaaa".into(),
+            client_name: "Desktop".into(),
+            tabs,
+        }
+    }
+
+    fn encrypted_len(record: &TabsRecord, unknown_fields: &Map<String, JsonValue>) -> usize {
+        let key = KeyBundle::new_random().unwrap();
+        OutgoingRecord::Record {
+            record: record.clone(),
+            unknown_fields: unknown_fields.clone(),
+        }.into_payload()
+            .unwrap()
+            .into_bso(COLLECTION_NAME.into())
+            .encrypt(&key)
+            .unwrap()
+            .payload
+            .serialized_len()
+    }
+
+    #[test]
+    fn test_tabs_record() {
+        let payload = Payload::from_json(json!({
+            "id": "aaaaaaaaaaaa",
+            "clientName": "Desktop",
+            "tabs": [{
+                "title": "Example",
+                "urlHistory": ["https://example.com/b", "https://example.com/a"],
+                "icon": null,
+                "lastUsed": 1500000000,
+            }, {
+                "title": "No last used",
+                "urlHistory": ["https://example.org"],
+            }],
+        })).unwrap();
+        let record = payload.into_record::<TabsRecord>().unwrap();
+        assert_eq!(record.client_name, "Desktop");
+        assert_eq!(record.tabs, vec![Tab {
+            title: "Example".into(),
+            url_history: vec!["https://example.com/b".into(), "https://example.com/a".into()],
+            icon: None,
+            last_used: 1_500_000_000,
+        }, Tab {
+            title: "No last used".into(),
+            url_history: vec!["https://example.org".into()],
+            icon: None,
+            last_used: 0,
+        }]);
+    }
+
+    #[test]
+    fn test_truncate_to_fit() {
+        let all_tabs = (0..50).rev().map(tab).collect::<Vec<_>>();
+
+        // Records that already fit are left alone.
+        let mut small = record(all_tabs[..2].to_vec());
+        truncate_to_fit(&mut small, &Map::new(), 2048).unwrap();
+        assert_eq!(small.tabs, all_tabs[..2].to_vec());
+
+        // We keep as many of the most recently used tabs as fit.
+        let mut large = record(all_tabs.clone());
+        truncate_to_fit(&mut large, &Map::new(), 2048).unwrap();
+        let count = large.tabs.len();
+        assert!(count > 0 && count < 50);
+        assert_eq!(large.tabs, all_tabs[..count].to_vec());
+        assert!(encrypted_len(&large, &Map::new()) <= 2048);
+        assert!(encrypted_len(&record(all_tabs[..count + 1].to_vec()), &Map::new()) > 2048);
+
+        // Unknown fields take up space, too.
+        let mut unknown_fields = Map::new();
+        unknown_fields.insert("extra".into(), "x".repeat(1024).into());
+        let mut with_unknown = record(all_tabs.clone());
+        truncate_to_fit(&mut with_unknown, &unknown_fields, 2048).unwrap();
+        assert!(with_unknown.tabs.len() < count);
+        assert!(encrypted_len(&with_unknown, &unknown_fields) <= 2048);
+
+        // If not even one tab fits, we upload none.
+        let mut huge = record(all_tabs.clone());
+        truncate_to_fit(&mut huge, &Map::new(), 100).unwrap();
+        assert!(huge.tabs.is_empty());
+    }
+
+    #[test]
+    fn test_needs_upload() {
+        let now = ServerTimestamp(1_500_000_000.0);
+        let day = 24.0 * 60.0 * 60.0;
+        let local = record(vec![tab(2), tab(1)]);
+        assert!(needs_upload(&local, None, now));
+        assert!(!needs_upload(&local, Some((&local, now)), now));
+        let changed = record(vec![tab(1)]);
+        assert!(needs_upload(&local, Some((&changed, now)), now));
+
+        // We refresh our record before it expires, even if it hasn't changed.
+        let recent = ServerTimestamp(now.0 - 6.0 * day);
+        assert!(!needs_upload(&local, Some((&local, recent)), now));
+        let stale = ServerTimestamp(now.0 - 7.0 * day);
+        assert!(needs_upload(&local, Some((&local, stale)), now));
+        // But not if our clock is behind the server's.
+        let future = ServerTimestamp(now.0 + day);
+        assert!(!needs_upload(&local, Some((&local, future)), now));
+    }
+}
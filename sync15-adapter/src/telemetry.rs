@@ -0,0 +1,478 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Records telemetry about syncs. The types here serialize to the JSON shape
+//! of the Firefox "sync" ping, so that apps can submit them as-is.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use error::{self, ErrorKind};
+
+fn millis_since_epoch(when: SystemTime) -> u64 {
+    duration_as_millis(when.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+fn duration_as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000
+}
+
+fn is_zero(v: &u32) -> bool {
+    *v == 0
+}
+
+/// Records how long something took, in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+struct Took {
+    took: u64,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl Took {
+    fn new() -> Took {
+        Took {
+            took: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn finished(&mut self) {
+        self.took = duration_as_millis(self.start.elapsed());
+    }
+}
+
+/// Why a sync, or an engine, failed. Serializes to a `failureReason`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "name")]
+pub enum SyncFailure {
+    #[serde(rename = "shutdownerror")]
+    Shutdown,
+
+    #[serde(rename = "autherror")]
+    Auth { from: &'static str },
+
+    #[serde(rename = "httperror")]
+    Http { code: u16 },
+
+    #[serde(rename = "othererror")]
+    Other { error: String },
+
+    #[serde(rename = "unexpectederror")]
+    Unexpected { error: String },
+}
+
+impl<'a> From<&'a error::Error> for SyncFailure {
+    fn from(e: &'a error::Error) -> SyncFailure {
+        match e.kind() {
+            ErrorKind::TokenserverHttpError(401) | ErrorKind::TokenserverHttpError(403) => {
+                SyncFailure::Auth { from: "tokenserver" }
+            }
+            ErrorKind::TokenserverHttpError(code) => SyncFailure::Http { code: *code },
+            ErrorKind::StorageHttpError { code, .. } => SyncFailure::Http { code: *code },
+            ErrorKind::QuotaExceeded { .. } => SyncFailure::Http { code: 507 },
+            ErrorKind::Interrupted => SyncFailure::Shutdown,
+            ErrorKind::BackoffError(_) => SyncFailure::Other { error: "backoff".into() },
+            ErrorKind::RequestError(_) => SyncFailure::Other { error: "network".into() },
+            // The URL might identify the user.
+            ErrorKind::UnacceptableUrl(_) => SyncFailure::Unexpected {
+                error: "unacceptable url".into(),
+            },
+            _ => SyncFailure::Unexpected { error: e.to_string() },
+        }
+    }
+}
+
+/// Counts of incoming records for an engine. Stores update these as they
+/// apply each page of incoming records.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EngineIncoming {
+    #[serde(skip_serializing_if = "is_zero")]
+    applied: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    failed: u32,
+    #[serde(rename = "newFailed", skip_serializing_if = "is_zero")]
+    new_failed: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    reconciled: u32,
+}
+
+impl EngineIncoming {
+    pub fn new() -> EngineIncoming {
+        EngineIncoming::default()
+    }
+
+    /// Records that we applied `n` incoming records without conflicts.
+    pub fn applied(&mut self, n: u32) {
+        self.applied += n;
+    }
+
+    /// Records that we couldn't apply `n` incoming records.
+    pub fn failed(&mut self, n: u32) {
+        self.failed += n;
+    }
+
+    /// Records that `n` incoming records failed for the first time.
+    pub fn new_failed(&mut self, n: u32) {
+        self.new_failed += n;
+    }
+
+    /// Records that `n` incoming records conflicted with local changes, and
+    /// were merged.
+    pub fn reconciled(&mut self, n: u32) {
+        self.reconciled += n;
+    }
+
+    pub fn get_applied(&self) -> u32 {
+        self.applied
+    }
+
+    pub fn get_failed(&self) -> u32 {
+        self.failed
+    }
+
+    pub fn get_new_failed(&self) -> u32 {
+        self.new_failed
+    }
+
+    pub fn get_reconciled(&self) -> u32 {
+        self.reconciled
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == EngineIncoming::default()
+    }
+}
+
+/// Counts of records we uploaded in a single batch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EngineOutgoing {
+    #[serde(skip_serializing_if = "is_zero")]
+    sent: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    failed: u32,
+}
+
+impl EngineOutgoing {
+    pub fn new(sent: u32, failed: u32) -> EngineOutgoing {
+        EngineOutgoing { sent, failed }
+    }
+
+    pub fn get_sent(&self) -> u32 {
+        self.sent
+    }
+
+    pub fn get_failed(&self) -> u32 {
+        self.failed
+    }
+}
+
+/// Telemetry for syncing a single engine.
+#[derive(Debug, Clone, Serialize)]
+pub struct Engine {
+    name: String,
+    #[serde(flatten)]
+    took: Took,
+    #[serde(skip_serializing_if = "EngineIncoming::is_empty")]
+    incoming: EngineIncoming,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    outgoing: Vec<EngineOutgoing>,
+    #[serde(rename = "failureReason", skip_serializing_if = "Option::is_none")]
+    failure: Option<SyncFailure>,
+}
+
+impl Engine {
+    /// Starts recording telemetry for the engine `name`.
+    pub fn new<S: Into<String>>(name: S) -> Engine {
+        Engine {
+            name: name.into(),
+            took: Took::new(),
+            incoming: EngineIncoming::default(),
+            outgoing: Vec::new(),
+            failure: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The incoming counts, for stores to update as they apply records.
+    pub fn incoming(&mut self) -> &mut EngineIncoming {
+        &mut self.incoming
+    }
+
+    pub fn get_incoming(&self) -> &EngineIncoming {
+        &self.incoming
+    }
+
+    pub fn outgoing(&mut self, outgoing: EngineOutgoing) {
+        self.outgoing.push(outgoing);
+    }
+
+    pub fn get_outgoing(&self) -> &[EngineOutgoing] {
+        &self.outgoing
+    }
+
+    /// Records why the engine failed to sync. Only the first failure is
+    /// kept, since later ones are usually fallout from it.
+    pub fn failure(&mut self, failure: SyncFailure) {
+        if self.failure.is_none() {
+            self.failure = Some(failure);
+        }
+    }
+
+    pub fn get_failure(&self) -> Option<&SyncFailure> {
+        self.failure.as_ref()
+    }
+
+    /// Records `err` as the reason the engine failed, and returns it. Handy
+    /// for `map_err`.
+    pub fn record_error(&mut self, err: error::Error) -> error::Error {
+        self.failure(SyncFailure::from(&err));
+        err
+    }
+
+    /// Records that the engine failed because of an error from its store.
+    /// Store errors can be any type, so we don't try to classify them.
+    pub fn record_store_error<E>(&mut self, err: E) -> E {
+        self.failure(SyncFailure::Other { error: "store".into() });
+        err
+    }
+
+    pub fn finished(&mut self) {
+        self.took.finished();
+    }
+}
+
+/// The `status` of a sync that wasn't OK. Desktop also reports a `sync`
+/// reason here, but we only know the `service` status.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncStatus {
+    service: &'static str,
+}
+
+impl SyncStatus {
+    pub fn get_service(&self) -> &'static str {
+        self.service
+    }
+}
+
+/// Telemetry for a single sync of one or more engines.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncTelemetry {
+    when: u64,
+    #[serde(flatten)]
+    took: Took,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    engines: Vec<Engine>,
+    /// The setup state machine states we went through to get to ready. This
+    /// isn't part of the ping, but is useful for logging.
+    #[serde(skip)]
+    setup_sequence: Vec<&'static str>,
+    #[serde(skip_serializing_if = "::std::ops::Not::not")]
+    restarted: bool,
+    #[serde(rename = "failureReason", skip_serializing_if = "Option::is_none")]
+    failure: Option<SyncFailure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<SyncStatus>,
+}
+
+impl SyncTelemetry {
+    /// Starts recording telemetry for a sync.
+    pub fn new() -> SyncTelemetry {
+        SyncTelemetry {
+            when: millis_since_epoch(SystemTime::now()),
+            took: Took::new(),
+            engines: Vec::new(),
+            setup_sequence: Vec::new(),
+            restarted: false,
+            failure: None,
+            status: None,
+        }
+    }
+
+    pub fn engine(&mut self, engine: Engine) {
+        self.engines.push(engine);
+    }
+
+    pub fn get_engines(&self) -> &[Engine] {
+        &self.engines
+    }
+
+    pub fn setup_sequence(&mut self, sequence: &[&'static str]) {
+        self.setup_sequence = sequence.to_vec();
+    }
+
+    pub fn get_setup_sequence(&self) -> &[&'static str] {
+        &self.setup_sequence
+    }
+
+    /// Forgets the engines and failure from an earlier attempt, when we
+    /// restart a sync after getting a new token.
+    pub fn restarted(&mut self) {
+        self.engines.clear();
+        self.setup_sequence.clear();
+        self.failure = None;
+        self.restarted = true;
+    }
+
+    pub fn failure(&mut self, failure: SyncFailure) {
+        if self.failure.is_none() {
+            self.failure = Some(failure);
+        }
+    }
+
+    pub fn get_failure(&self) -> Option<&SyncFailure> {
+        self.failure.as_ref()
+    }
+
+    /// The status of the sync, if it wasn't OK. Set by `finished`.
+    pub fn get_status(&self) -> Option<&SyncStatus> {
+        self.status.as_ref()
+    }
+
+    /// Records how long the sync took, and its status. Like desktop, we say
+    /// the sync failed if it failed outright, or partially failed if any of
+    /// its engines did.
+    pub fn finished(&mut self) {
+        self.took.finished();
+        let service = match self.failure {
+            Some(SyncFailure::Auth { .. }) => Some("error.login.failed"),
+            Some(_) => Some("error.sync.failed"),
+            None if self.engines.iter().any(|e| e.failure.is_some()) => {
+                Some("error.sync.failed_partial")
+            }
+            None => None,
+        };
+        self.status = service.map(|service| SyncStatus { service });
+    }
+}
+
+impl Default for SyncTelemetry {
+    fn default() -> SyncTelemetry {
+        SyncTelemetry::new()
+    }
+}
+
+/// A "sync" ping, containing one or more syncs for the same user.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncTelemetryPing {
+    version: u32,
+    uid: String,
+    syncs: Vec<SyncTelemetry>,
+}
+
+impl SyncTelemetryPing {
+    /// Creates an empty ping for the user with the hashed FxA uid `uid`,
+    /// which is available from `Sync15StorageClient::hashed_uid`.
+    pub fn new<S: Into<String>>(uid: S) -> SyncTelemetryPing {
+        SyncTelemetryPing {
+            version: 1,
+            uid: uid.into(),
+            syncs: Vec::new(),
+        }
+    }
+
+    pub fn sync(&mut self, sync: SyncTelemetry) {
+        self.syncs.push(sync);
+    }
+
+    pub fn get_syncs(&self) -> &[SyncTelemetry] {
+        &self.syncs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_failure_from_error() {
+        let err = error::Error::from(ErrorKind::TokenserverHttpError(401));
+        assert_eq!(SyncFailure::from(&err), SyncFailure::Auth { from: "tokenserver" });
+        let err = error::Error::from(ErrorKind::StorageHttpError {
+            code: 500,
+            route: "/1.5/123/storage/passwords".into(),
+        });
+        assert_eq!(SyncFailure::from(&err), SyncFailure::Http { code: 500 });
+        let err = error::Error::from(ErrorKind::UnacceptableUrl("https://example.com/123".into()));
+        assert_eq!(SyncFailure::from(&err), SyncFailure::Unexpected {
+            error: "unacceptable url".into(),
+        });
+        let err = error::Error::from(ErrorKind::Interrupted);
+        assert_eq!(SyncFailure::from(&err), SyncFailure::Shutdown);
+    }
+
+    #[test]
+    fn test_ping_json() {
+        let mut engine = Engine::new("passwords");
+        engine.incoming().applied(5);
+        engine.incoming().reconciled(1);
+        engine.outgoing(EngineOutgoing::new(3, 1));
+        engine.finished();
+        let mut failed = Engine::new("tabs");
+        failed.record_error(ErrorKind::StorageHttpError { code: 503, route: "".into() }.into());
+        failed.finished();
+
+        let mut sync = SyncTelemetry::new();
+        sync.setup_sequence(&["InitialWithLiveToken", "Ready"]);
+        sync.engine(engine);
+        sync.engine(failed);
+        sync.finished();
+
+        let mut ping = SyncTelemetryPing::new("hashed-uid");
+        ping.sync(sync);
+
+        let mut json = serde_json::to_value(&ping).unwrap();
+        // Timestamps vary, so check them separately.
+        {
+            let sync = &mut json["syncs"][0];
+            assert!(sync["when"].as_u64().unwrap() > 0);
+            sync["when"] = json!(0);
+            sync["took"] = json!(0);
+            for engine in sync["engines"].as_array_mut().unwrap() {
+                engine["took"] = json!(0);
+            }
+        }
+        assert_eq!(json, json!({
+            "version": 1,
+            "uid": "hashed-uid",
+            "syncs": [{
+                "when": 0,
+                "took": 0,
+                "engines": [{
+                    "name": "passwords",
+                    "took": 0,
+                    "incoming": { "applied": 5, "reconciled": 1 },
+                    "outgoing": [{ "sent": 3, "failed": 1 }],
+                }, {
+                    "name": "tabs",
+                    "took": 0,
+                    "failureReason": { "name": "httperror", "code": 503 },
+                }],
+                "status": { "service": "error.sync.failed_partial" },
+            }],
+        }));
+    }
+
+    #[test]
+    fn test_sync_status() {
+        let mut sync = SyncTelemetry::new();
+        sync.engine(Engine::new("passwords"));
+        sync.finished();
+        assert_eq!(sync.get_status(), None);
+        assert!(serde_json::to_value(&sync).unwrap().get("status").is_none());
+
+        let mut sync = SyncTelemetry::new();
+        sync.failure(SyncFailure::Auth { from: "tokenserver" });
+        sync.finished();
+        assert_eq!(sync.get_status().map(|s| s.get_service()), Some("error.login.failed"));
+
+        let mut sync = SyncTelemetry::new();
+        sync.failure(SyncFailure::Http { code: 500 });
+        sync.finished();
+        assert_eq!(sync.get_status().map(|s| s.get_service()), Some("error.sync.failed"));
+    }
+}
@@ -4,15 +4,16 @@
 
 use hawk;
 
-use reqwest::{Client, Request, Url};
+use reqwest::Url;
 use hyper::header::AUTHORIZATION;
 use error::{self, Result, ErrorKind};
+use transport::{HttpRequest, HttpTransport, HeaderValue, Method};
 use std::fmt;
 use std::borrow::{Borrow, Cow};
 use std::str::FromStr;
 use std::time::{SystemTime, Duration};
 use std::cell::{RefCell};
-use util::ServerTimestamp;
+use util::{self, ServerTimestamp};
 
 /// Tokenserver's timestamp is X-Timestamp and not X-Weave-Timestamp.
 const RETRY_AFTER: &str = "Retry-After";
@@ -23,6 +24,9 @@ const X_TIMESTAMP: &str = "X-Timestamp";
 /// OAuth tokenserver api uses this instead of X-Client-State.
 const X_KEY_ID: &str = "X-KeyID";
 
+/// BrowserID tokenserver api uses this to identify the sync key.
+const X_CLIENT_STATE: &str = "X-Client-State";
+
 const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
 
 // The TokenserverToken is the token as received directly from the token server
@@ -47,53 +51,122 @@ struct TokenFetchResult {
 // The trait for fetching tokens - we'll provide a "real" implementation but
 // tests will re-implement it.
 trait TokenFetcher {
-    fn fetch_token(&self, request_client: &Client) -> super::Result<TokenFetchResult>;
+    fn fetch_token(&self, transport: &HttpTransport) -> super::Result<TokenFetchResult>;
     // We allow the trait to tell us what the time is so tests can get funky.
     fn now(&self) -> SystemTime;
 }
 
+/// The credentials we present to the tokenserver to fetch a token.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TokenserverCredentials {
+    /// An OAuth access token with the sync scope, and the key ID of the
+    /// scoped sync key.
+    OAuth {
+        access_token: String,
+        key_id: String,
+    },
+    /// A BrowserID assertion for the tokenserver audience (as returned by
+    /// fxa-client's `generate_assertion`), and the hex-encoded client state
+    /// derived from kB. Older accounts and some self-hosted servers only
+    /// support this flow. Assertions expire, so long-lived clients should
+    /// generate a new one for each token with a `CredentialsProvider`.
+    BrowserID {
+        assertion: String,
+        client_state: String,
+    },
+}
+
+impl TokenserverCredentials {
+    // Adds the headers the tokenserver expects for these credentials.
+    fn add_headers(&self, req: &mut HttpRequest) -> Result<()> {
+        let (authorization, header, value) = match self {
+            TokenserverCredentials::OAuth { access_token, key_id } => {
+                (format!("Bearer {}", access_token), X_KEY_ID, key_id)
+            }
+            TokenserverCredentials::BrowserID { assertion, client_state } => {
+                (format!("BrowserID {}", assertion), X_CLIENT_STATE, client_state)
+            }
+        };
+        req.headers.insert(AUTHORIZATION, HeaderValue::from_str(&authorization)?);
+        req.headers.insert(header, HeaderValue::from_str(value)?);
+        Ok(())
+    }
+}
+
+/// Returns the credentials to use each time we fetch a token. Tokens expire,
+/// and so do BrowserID assertions, so a client that's used for longer than an
+/// assertion lasts needs new credentials for each token. Closures that return
+/// credentials implement this, and so do `TokenserverCredentials`, for
+/// credentials that don't change.
+pub trait CredentialsProvider {
+    fn credentials(&self) -> Result<TokenserverCredentials>;
+}
+
+impl CredentialsProvider for TokenserverCredentials {
+    fn credentials(&self) -> Result<TokenserverCredentials> {
+        Ok(self.clone())
+    }
+}
+
+impl<F> CredentialsProvider for F
+where
+    F: Fn() -> Result<TokenserverCredentials>,
+{
+    fn credentials(&self) -> Result<TokenserverCredentials> {
+        self()
+    }
+}
+
 // Our "real" token fetcher, implementing the TokenFetcher trait, which hits
 // the token server
-#[derive(Debug)]
 struct TokenServerFetcher {
     // The stuff needed to fetch a token.
     server_url: Url,
-    access_token: String,
-    key_id: String,
+    credentials: Box<CredentialsProvider>,
+}
+
+// Credentials providers aren't required to implement Debug.
+impl fmt::Debug for TokenServerFetcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> ::std::result::Result<(), fmt::Error> {
+        f.debug_struct("TokenServerFetcher")
+         .field("server_url", &self.server_url)
+         .field("credentials", &"(omitted)")
+         .finish()
+    }
 }
 
 impl TokenServerFetcher {
-    fn new(server_url: Url, access_token: String, key_id: String) -> TokenServerFetcher {
-        TokenServerFetcher { server_url, access_token, key_id }
+    fn new(server_url: Url, credentials: Box<CredentialsProvider>) -> TokenServerFetcher {
+        TokenServerFetcher { server_url, credentials }
     }
 }
 
 impl TokenFetcher for TokenServerFetcher {
-    fn fetch_token(&self, request_client: &Client) -> Result<TokenFetchResult> {
-        let mut resp = request_client.get(self.server_url.clone())
-                                     .header(AUTHORIZATION, format!("Bearer {}", self.access_token))
-                                     .header(X_KEY_ID, self.key_id.clone())
-                                     .send()?;
-
-        if !resp.status().is_success() {
-            warn!("Non-success status when fetching token: {}", resp.status());
+    fn fetch_token(&self, transport: &HttpTransport) -> Result<TokenFetchResult> {
+        let mut req = HttpRequest::new(Method::GET, self.server_url.clone());
+        self.credentials.credentials()?.add_headers(&mut req)?;
+        let resp = transport.execute(req)?;
+
+        if !resp.is_success() {
+            warn!("Non-success status when fetching token: {}", resp.status);
             // TODO: the body should be JSON and contain a status parameter we might need?
-            debug!("  Response body {}", resp.text().unwrap_or_else(|_| "???".into()));
+            debug!("  Response body {}", resp.text());
             // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
             // have a TokenserverHttpError as its cause?
-            if let Some(header) = resp.headers().get(RETRY_AFTER) {
+            if let Some(header) = resp.headers.get(RETRY_AFTER) {
                 // XXX - We are silently dropping parsing errors here.
-                let ms = header.to_str().ok().and_then(|s| s.parse::<f64>().ok())
-                    .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
-                let when = self.now() + Duration::from_millis(ms);
+                let now = self.now();
+                let when = header.to_str().ok()
+                    .and_then(|s| util::parse_backoff(s, now))
+                    .unwrap_or_else(|| now + Duration::from_millis(RETRY_AFTER_DEFAULT_MS));
                 return Err(ErrorKind::BackoffError(when).into());
             }
-            let status = resp.status().as_u16();
+            let status = resp.status.as_u16();
             return Err(ErrorKind::TokenserverHttpError(status).into());
         }
 
         let token: TokenserverToken = resp.json()?;
-        let server_timestamp = resp.headers()
+        let server_timestamp = resp.headers
                     .get(X_TIMESTAMP)
                     .and_then(|v| v.to_str().ok())
                     .and_then(|s| ServerTimestamp::from_str(s).ok())
@@ -143,8 +216,8 @@ impl TokenContext {
         now < self.valid_until
     }
 
-    fn authorization(&self, req: &Request) -> Result<String> {
-        let url = req.url();
+    fn authorization(&self, req: &HttpRequest) -> Result<String> {
+        let url = &req.url;
 
         let path_and_query = match url.query() {
             None => Cow::from(url.path()),
@@ -160,7 +233,7 @@ impl TokenContext {
                 "Storage URL has no port and no default port is known for the protocol".into()))?;
 
         let header = hawk::RequestBuilder::new(
-            req.method().as_ref(),
+            req.method.as_ref(),
             host,
             port,
             path_and_query.borrow()
@@ -185,8 +258,13 @@ enum TokenState {
     // elt is the api_endpoint we had before we hit the backoff error.
     // XXX - should we roll Backoff and Failed together?
     Backoff(SystemTime, Option<String>),
+    // We dropped our token, usually because the server rejected it. The
+    // elt is the api_endpoint for the dropped token, so that we can tell if
+    // the next token is for a different node.
+    Dropped(String),
     // api_endpoint changed - we are never going to get a token nor move out
-    // of this state.
+    // of this state, until the caller acknowledges the new node by calling
+    // `drop_token`.
     NodeReassigned,
 }
 
@@ -209,8 +287,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
 
     // Uses our fetcher to grab a new token and if successfull, derives other
     // info from that token into a usable TokenContext.
-    fn fetch_context(&self, request_client: &Client) -> Result<TokenContext> {
-        let result = self.fetcher.fetch_token(request_client)?;
+    fn fetch_context(&self, transport: &HttpTransport) -> Result<TokenContext> {
+        let result = self.fetcher.fetch_token(transport)?;
         let token = result.token;
         let valid_until = SystemTime::now() + Duration::from_secs(token.duration);
 
@@ -225,8 +303,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Attempt to fetch a new token and return a new state reflecting that
     // operation. If it worked a TokenState will be returned, but errors may
     // cause other states.
-    fn fetch_token(&self, request_client: &Client, previous_endpoint: Option<&str>) -> TokenState {
-        match self.fetch_context(request_client) {
+    fn fetch_token(&self, transport: &HttpTransport, previous_endpoint: Option<&str>) -> TokenState {
+        match self.fetch_context(transport) {
             Ok(tc) => {
                 // We got a new token - check that the endpoint is the same
                 // as a previous endpoint we saw (if any)
@@ -259,19 +337,19 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Returns None if the current state should be used (eg, if we are
     // holding a token that remains valid) or Some() if the state has changed
     // (which may have changed to a state with a token or an error state)
-    fn advance_state(&self, request_client: &Client, state: &TokenState) -> Option<TokenState> {
+    fn advance_state(&self, transport: &HttpTransport, state: &TokenState) -> Option<TokenState> {
         match state {
             TokenState::NoToken => {
-                Some(self.fetch_token(request_client, None))
+                Some(self.fetch_token(transport, None))
             },
             TokenState::Failed(_, existing_endpoint) => {
-                Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                Some(self.fetch_token(transport, existing_endpoint.as_ref().map(|e| e.as_str())))
             },
             TokenState::Token(existing_context) => {
                 if existing_context.is_valid(self.fetcher.now()) {
                     None
                 } else {
-                    Some(self.fetch_token(request_client, Some(existing_context.token.api_endpoint.as_str())))
+                    Some(self.fetch_token(transport, Some(existing_context.token.api_endpoint.as_str())))
                 }
             },
             TokenState::Backoff(ref until, ref existing_endpoint) => {
@@ -280,23 +358,26 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                     None
                 } else {
                     // backoff period is over
-                    Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                    Some(self.fetch_token(transport, existing_endpoint.as_ref().map(|e| e.as_str())))
                 }
             },
+            TokenState::Dropped(ref existing_endpoint) => {
+                Some(self.fetch_token(transport, Some(existing_endpoint.as_str())))
+            },
             TokenState::NodeReassigned => {
-                // We never leave this state.
+                // We never leave this state on our own.
                 None
             }
         }
     }
 
-    fn with_token<T, F>(&self, request_client: &Client, func: F) -> Result<T>
+    fn with_token<T, F>(&self, transport: &HttpTransport, func: F) -> Result<T>
             where F: FnOnce(&TokenContext) -> Result<T> {
 
         // first get a mutable ref to our existing state, advance to the
         // state we will use, then re-stash that state for next time.
         let state: &mut TokenState = &mut self.current_state.borrow_mut();
-        match self.advance_state(request_client, state) {
+        match self.advance_state(transport, state) {
             Some(new_state) => *state = new_state,
             None => ()
         }
@@ -304,9 +385,9 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         // Now re-fetch the state we should use for this call - if it's
         // anything other than TokenState::Token we will fail.
         match state {
-            TokenState::NoToken => {
+            TokenState::NoToken | TokenState::Dropped(_) => {
                 // it should be impossible to get here.
-                panic!("Can't be in NoToken state after advancing");
+                panic!("Can't be in NoToken or Dropped state after advancing");
             }
             TokenState::Token(ref token_context) => {
                 // make the call.
@@ -326,16 +407,45 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         }
     }
 
-    fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.with_token(http_client, |ctx| ctx.authorization(req))
+    fn authorization(&self, transport: &HttpTransport, req: &HttpRequest) -> Result<String> {
+        self.with_token(transport, |ctx| ctx.authorization(req))
     }
 
-    fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.with_token(http_client, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    fn api_endpoint(&self, transport: &HttpTransport) -> Result<String> {
+        self.with_token(transport, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    }
+
+    // Forces us to fetch a new token for the next request. This is used when
+    // the storage server rejects a token that we think is still valid. If we
+    // were reassigned to a new node, this also forgets the old node, so that
+    // we can fetch a token for the new one. Backoffs are left alone.
+    fn drop_token(&self) {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        let new_state = match state {
+            TokenState::Token(ref existing_context) => {
+                TokenState::Dropped(existing_context.token.api_endpoint.clone())
+            }
+            TokenState::NodeReassigned => TokenState::NoToken,
+            _ => return,
+        };
+        *state = new_state;
+    }
+
+    fn is_node_reassigned(&self) -> bool {
+        match *self.current_state.borrow() {
+            TokenState::NodeReassigned => true,
+            _ => false,
+        }
+    }
+
+    // The hashed FxA uid from our current token, if we have one. Unlike
+    // `api_endpoint`, this never fetches a new token.
+    fn hashed_fxa_uid(&self) -> Option<String> {
+        match *self.current_state.borrow() {
+            TokenState::Token(ref context) => Some(context.token.hashed_fxa_uid.clone()),
+            _ => None,
+        }
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
 }
 
 // The public concrete object exposed by this module
@@ -345,19 +455,31 @@ pub struct TokenProvider {
 }
 
 impl TokenProvider {
-    pub fn new(url: Url, access_token: String, key_id: String) -> Self {
-        let fetcher = TokenServerFetcher::new(url, access_token, key_id);
+    pub fn new(url: Url, credentials: Box<CredentialsProvider>) -> Self {
+        let fetcher = TokenServerFetcher::new(url, credentials);
         Self {
             imp: TokenProviderImpl::new(fetcher),
         }
     }
 
-    pub fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.imp.authorization(http_client, req)
+    pub fn authorization(&self, transport: &HttpTransport, req: &HttpRequest) -> Result<String> {
+        self.imp.authorization(transport, req)
+    }
+
+    pub fn api_endpoint(&self, transport: &HttpTransport) -> Result<String> {
+        self.imp.api_endpoint(transport)
+    }
+
+    pub fn drop_token(&self) {
+        self.imp.drop_token()
+    }
+
+    pub fn is_node_reassigned(&self) -> bool {
+        self.imp.is_node_reassigned()
     }
 
-    pub fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.imp.api_endpoint(http_client)
+    pub fn hashed_fxa_uid(&self) -> Option<String> {
+        self.imp.hashed_fxa_uid()
     }
 }
 
@@ -365,10 +487,14 @@ impl TokenProvider {
 mod tests {
     use super::*;
     use std::cell::Cell;
-    use reqwest::Client;
+    use transport::HttpResponse;
 
-    fn make_client() -> Client {
-        Client::builder().timeout(Duration::from_secs(30)).build().expect("can't build client")
+    // The test fetchers never hit the network, so this should never be used.
+    struct NoTransport;
+    impl HttpTransport for NoTransport {
+        fn execute(&self, _: HttpRequest) -> Result<HttpResponse> {
+            panic!("Unexpected network request");
+        }
     }
 
     struct TestFetcher<FF, FN>
@@ -380,7 +506,7 @@ mod tests {
     impl<FF, FN> TokenFetcher for TestFetcher<FF, FN>
         where FF: Fn() -> Result<TokenFetchResult>,
               FN: Fn() -> SystemTime {
-        fn fetch_token(&self, _: &Client) -> Result<TokenFetchResult> {
+        fn fetch_token(&self, _: &HttpTransport) -> Result<TokenFetchResult> {
             (self.fetch)()
         }
         fn now(&self) -> SystemTime {
@@ -419,16 +545,65 @@ mod tests {
 
         let tsc = make_tsc(fetch, || {SystemTime::now()});
 
-        let e = tsc.api_endpoint(&make_client()).expect("should work");
+        let e = tsc.api_endpoint(&NoTransport).expect("should work");
         assert_eq!(e, "api_endpoint".to_string());
         assert_eq!(counter.get(), 1);
 
-        let e2 = tsc.api_endpoint(&make_client()).expect("should work");
+        let e2 = tsc.api_endpoint(&NoTransport).expect("should work");
         assert_eq!(e2, "api_endpoint".to_string());
         // should not have re-fetched.
         assert_eq!(counter.get(), 1);
     }
 
+    #[test]
+    fn test_drop_token() {
+        let counter: Cell<u32> = Cell::new(0);
+        let endpoint: RefCell<String> = RefCell::new("api_endpoint".to_string());
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: endpoint.borrow().clone(),
+                    uid: 1,
+                    duration: 1000,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+
+        let tsc = make_tsc(fetch, || {SystemTime::now()});
+
+        tsc.api_endpoint(&NoTransport).expect("should work");
+        assert_eq!(counter.get(), 1);
+
+        // Dropping the token should re-fetch, even though the old one is
+        // still valid.
+        tsc.drop_token();
+        let e = tsc.api_endpoint(&NoTransport).expect("should work");
+        assert_eq!(e, "api_endpoint".to_string());
+        assert_eq!(counter.get(), 2);
+        assert!(!tsc.is_node_reassigned());
+
+        // If the new token is for a different node, we should stay
+        // reassigned until the token is dropped again.
+        *endpoint.borrow_mut() = "new_api_endpoint".to_string();
+        tsc.drop_token();
+        tsc.api_endpoint(&NoTransport).expect_err("should be reassigned");
+        assert_eq!(counter.get(), 3);
+        assert!(tsc.is_node_reassigned());
+        tsc.api_endpoint(&NoTransport).expect_err("should still be reassigned");
+        assert_eq!(counter.get(), 3);
+
+        tsc.drop_token();
+        assert!(!tsc.is_node_reassigned());
+        let e = tsc.api_endpoint(&NoTransport).expect("should work");
+        assert_eq!(e, "new_api_endpoint".to_string());
+        assert_eq!(counter.get(), 4);
+    }
+
     #[test]
     fn test_backoff() {
         let counter: Cell<u32> = Cell::new(0);
@@ -440,12 +615,12 @@ mod tests {
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
 
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint(&NoTransport).expect_err("should bail");
         // XXX - check error type.
         assert_eq!(counter.get(), 1);
         // try and get another token - should not re-fetch as backoff is still
         // in progress.
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint(&NoTransport).expect_err("should bail");
         assert_eq!(counter.get(), 1);
 
         // Advance the clock.
@@ -453,7 +628,7 @@ mod tests {
 
         // Our token fetch mock is still returning a backoff error, so we
         // still fail, but should have re-hit the fetch function.
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint(&NoTransport).expect_err("should bail");
         assert_eq!(counter.get(), 2);
     }
 
@@ -477,19 +652,19 @@ mod tests {
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
 
-        tsc.api_endpoint(&make_client()).expect("should get a valid token");
+        tsc.api_endpoint(&NoTransport).expect("should get a valid token");
         assert_eq!(counter.get(), 1);
 
         // try and get another token - should not re-fetch as the old one
         // remains valid.
-        tsc.api_endpoint(&make_client()).expect("should reuse existing token");
+        tsc.api_endpoint(&NoTransport).expect("should reuse existing token");
         assert_eq!(counter.get(), 1);
 
         // Advance the clock.
         now.set(now.get() + Duration::new(20, 0));
 
         // We should discard our token and fetch a new one.
-        tsc.api_endpoint(&make_client()).expect("should re-fetch");
+        tsc.api_endpoint(&NoTransport).expect("should re-fetch");
         assert_eq!(counter.get(), 2);
     }
 }
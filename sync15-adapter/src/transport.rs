@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use reqwest::Client;
+use serde;
+use serde_json;
+
+use error;
+
+pub use reqwest::{Method, StatusCode, Url};
+pub use reqwest::header::{HeaderMap, HeaderValue};
+
+/// An HTTP request, as handed to an `HttpTransport`.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    #[inline]
+    pub fn new(method: Method, url: Url) -> HttpRequest {
+        HttpRequest {
+            method,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+}
+
+/// The response to an `HttpRequest`. Unlike `reqwest::Response`, the body
+/// has already been read in its entirety.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// The URL of the request that produced this response.
+    pub url: Url,
+}
+
+impl HttpResponse {
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    pub fn json<T>(&self) -> error::Result<T>
+    where
+        for<'a> T: serde::de::Deserialize<'a>,
+    {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Executes HTTP requests on behalf of the storage client and the token
+/// provider. Implement this to route sync traffic through the embedding
+/// application's networking stack, or to talk to an in-process fake server.
+///
+/// Important: Implementations should only return an error if no response was
+/// received. Non-success HTTP statuses must be returned as responses!
+pub trait HttpTransport {
+    fn execute(&self, request: HttpRequest) -> error::Result<HttpResponse>;
+}
+
+/// The default transport, backed by `reqwest`.
+#[derive(Debug)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new() -> error::Result<ReqwestTransport> {
+        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+        Ok(ReqwestTransport { client })
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn execute(&self, request: HttpRequest) -> error::Result<HttpResponse> {
+        let HttpRequest { method, url, headers, body } = request;
+        let mut builder = self.client.request(method, url).headers(headers);
+        if let Some(bytes) = body {
+            builder = builder.body(bytes);
+        }
+        let mut resp = builder.send()?;
+        let mut body = Vec::new();
+        resp.copy_to(&mut body)?;
+        Ok(HttpResponse {
+            status: resp.status(),
+            headers: resp.headers().clone(),
+            body,
+            url: resp.url().clone(),
+        })
+    }
+}
@@ -0,0 +1,430 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A typed layer over `Store`, for stores that would rather work with their
+//! own record types than with `Payload` JSON.
+
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_json::{self, Map, Value as JsonValue};
+
+use bso_record::Payload;
+use changeset::{IncomingChangeset, OutgoingChangeset};
+use dry_run::{DryRunChanges, LocalChange};
+use error;
+use interrupt::InterruptHandle;
+use request::UploadPolicy;
+use sync::{RetrySet, Store};
+use telemetry;
+use util::ServerTimestamp;
+
+/// A record type that can be synced. Records are converted to and from
+/// `Payload`s with serde, so the serialized form must include the record's
+/// `id`.
+pub trait SyncRecord: Serialize + DeserializeOwned {
+    /// The collection that holds records of this type, like "passwords". This
+    /// is also the engine name in `meta/global`.
+    const COLLECTION: &'static str;
+
+    /// The storage version of the engine that syncs this type, for
+    /// `meta/global`.
+    const STORAGE_VERSION: usize;
+
+    fn record_id(&self) -> &str;
+}
+
+/// What we found in an incoming record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncomingKind<T> {
+    /// A record we understood. `unknown_fields` holds any fields that `T`
+    /// doesn't know about, which should be passed back in the matching
+    /// `OutgoingRecord`, so that we don't drop fields that newer clients
+    /// added.
+    Record {
+        record: T,
+        unknown_fields: Map<String, JsonValue>,
+    },
+    /// The record was deleted on another device.
+    Tombstone,
+    /// We couldn't deserialize the record. `payload` is the record as we
+    /// received it, and `reason` describes what went wrong.
+    Malformed { payload: Payload, reason: String },
+}
+
+/// An incoming record, with its id and server modified time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncomingRecord<T> {
+    pub id: String,
+    pub modified: ServerTimestamp,
+    pub kind: IncomingKind<T>,
+}
+
+impl<T: SyncRecord> IncomingRecord<T> {
+    pub fn from_payload(payload: Payload, modified: ServerTimestamp) -> IncomingRecord<T> {
+        let id = payload.id.clone();
+        let kind = if payload.is_tombstone() {
+            IncomingKind::Tombstone
+        } else {
+            match payload.clone().into_record::<T>() {
+                Ok(record) => {
+                    let unknown_fields = unknown_fields(&record, payload.data);
+                    IncomingKind::Record { record, unknown_fields }
+                }
+                Err(e) => IncomingKind::Malformed {
+                    payload,
+                    reason: e.to_string(),
+                },
+            }
+        };
+        IncomingRecord { id, modified, kind }
+    }
+}
+
+// Returns the fields in `data` that don't show up when we serialize `record`.
+fn unknown_fields<T: Serialize>(
+    record: &T,
+    data: Map<String, JsonValue>,
+) -> Map<String, JsonValue> {
+    match serde_json::to_value(record) {
+        Ok(JsonValue::Object(known)) => data
+            .into_iter()
+            .filter(|(name, _)| !known.contains_key(name))
+            .collect(),
+        _ => data,
+    }
+}
+
+/// A record to upload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutgoingRecord<T> {
+    Record {
+        record: T,
+        unknown_fields: Map<String, JsonValue>,
+    },
+    Tombstone { id: String },
+}
+
+impl<T: SyncRecord> OutgoingRecord<T> {
+    /// A record with no unknown fields, like one created locally.
+    pub fn record(record: T) -> OutgoingRecord<T> {
+        OutgoingRecord::Record {
+            record,
+            unknown_fields: Map::new(),
+        }
+    }
+
+    pub fn tombstone<S: Into<String>>(id: S) -> OutgoingRecord<T> {
+        OutgoingRecord::Tombstone { id: id.into() }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            OutgoingRecord::Record { record, .. } => record.record_id(),
+            OutgoingRecord::Tombstone { id } => id,
+        }
+    }
+
+    pub fn into_payload(self) -> error::Result<Payload> {
+        Ok(match self {
+            OutgoingRecord::Record { record, unknown_fields } => {
+                let mut payload = Payload::from_record(record)?;
+                for (name, value) in unknown_fields {
+                    payload.data.entry(name).or_insert(value);
+                }
+                payload
+            }
+            OutgoingRecord::Tombstone { id } => Payload::new_tombstone(id),
+        })
+    }
+}
+
+/// Like `Store`, but with typed records. Every `TypedStore` is also a
+/// `Store`, so it can be passed to `synchronize` or a `SyncManager`.
+pub trait TypedStore {
+    type Record: SyncRecord;
+    type Error: From<error::Error>;
+
+    /// See `Store::get_last_sync`.
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>, Self::Error>;
+
+    /// See `Store::reset`.
+    fn reset(&self) -> Result<(), Self::Error>;
+
+    /// Applies a page of incoming records, and returns all of the store's
+    /// outgoing changes, like `Store::apply_incoming`. Records that we
+    /// couldn't deserialize are passed as `IncomingKind::Malformed`, and
+    /// already counted as failed in `incoming_telemetry`.
+    fn apply_incoming_records(
+        &self,
+        inbound: Vec<IncomingRecord<Self::Record>>,
+        interrupt: &InterruptHandle,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<Vec<OutgoingRecord<Self::Record>>, Self::Error>;
+
+    /// Like `Store::dry_run_incoming`, but with typed records. The default
+    /// returns `None`.
+    fn dry_run_incoming_records(
+        &self,
+        _inbound: Vec<IncomingRecord<Self::Record>>,
+        _incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<Option<(Vec<LocalChange>, Vec<OutgoingRecord<Self::Record>>)>, Self::Error> {
+        Ok(None)
+    }
+
+    /// See `Store::set_high_water_mark`.
+    fn set_high_water_mark(
+        &self,
+        high_water_mark: ServerTimestamp,
+    ) -> Result<(), Self::Error>;
+
+    /// See `Store::sync_finished`.
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+        records_failed: &HashMap<String, String>,
+    ) -> Result<(), Self::Error>;
+
+    /// See `Store::upload_policy`.
+    fn upload_policy(&self) -> UploadPolicy {
+        UploadPolicy::Atomic
+    }
+
+    /// Like `Store::shrink_record`, but with typed records. Unknown fields
+    /// are kept.
+    fn shrink_record(
+        &self,
+        _record: Self::Record,
+        _max_payload_bytes: usize,
+    ) -> Option<Self::Record> {
+        None
+    }
+
+    /// See `Store::get_retry_set`.
+    fn get_retry_set(&self) -> Result<Option<RetrySet>, Self::Error> {
+        Ok(None)
+    }
+
+    /// See `Store::set_retry_set`.
+    fn set_retry_set(&self, _retry_set: RetrySet) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<S: TypedStore> Store for S {
+    type Error = S::Error;
+
+    fn collection_name(&self) -> &'static str {
+        S::Record::COLLECTION
+    }
+
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>, Self::Error> {
+        TypedStore::get_last_sync(self)
+    }
+
+    fn reset(&self) -> Result<(), Self::Error> {
+        TypedStore::reset(self)
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        interrupt: &InterruptHandle,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset, Self::Error> {
+        let records = incoming_records(inbound.changes, incoming_telemetry);
+        let outgoing = self.apply_incoming_records(records, interrupt, incoming_telemetry)?;
+        Ok(outgoing_changeset(inbound.collection, inbound.timestamp, outgoing)?)
+    }
+
+    fn dry_run_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<Option<DryRunChanges>, Self::Error> {
+        let records = incoming_records(inbound.changes, incoming_telemetry);
+        Ok(match self.dry_run_incoming_records(records, incoming_telemetry)? {
+            Some((local, outgoing)) => Some(DryRunChanges {
+                local,
+                outgoing: outgoing_changeset(inbound.collection, inbound.timestamp, outgoing)?,
+            }),
+            None => None,
+        })
+    }
+
+    fn set_high_water_mark(
+        &self,
+        high_water_mark: ServerTimestamp,
+    ) -> Result<(), Self::Error> {
+        TypedStore::set_high_water_mark(self, high_water_mark)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+        records_failed: &HashMap<String, String>,
+    ) -> Result<(), Self::Error> {
+        TypedStore::sync_finished(self, new_timestamp, records_synced, records_failed)
+    }
+
+    fn upload_policy(&self) -> UploadPolicy {
+        TypedStore::upload_policy(self)
+    }
+
+    fn shrink_record(&self, payload: Payload, max_payload_bytes: usize) -> Option<Payload> {
+        let modified = ServerTimestamp::default();
+        let (record, unknown_fields) = match IncomingRecord::from_payload(payload, modified).kind {
+            IncomingKind::Record { record, unknown_fields } => (record, unknown_fields),
+            _ => return None,
+        };
+        let record = TypedStore::shrink_record(self, record, max_payload_bytes)?;
+        match (OutgoingRecord::Record { record, unknown_fields }).into_payload() {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                warn!("Failed to serialize shrunk record: {}", e);
+                None
+            }
+        }
+    }
+
+    fn get_retry_set(&self) -> Result<Option<RetrySet>, Self::Error> {
+        TypedStore::get_retry_set(self)
+    }
+
+    fn set_retry_set(&self, retry_set: RetrySet) -> Result<(), Self::Error> {
+        TypedStore::set_retry_set(self, retry_set)
+    }
+}
+
+fn incoming_records<R: SyncRecord>(
+    changes: Vec<(Payload, ServerTimestamp)>,
+    incoming_telemetry: &mut telemetry::EngineIncoming,
+) -> Vec<IncomingRecord<R>> {
+    let records = changes
+        .into_iter()
+        .map(|(payload, modified)| IncomingRecord::from_payload(payload, modified))
+        .collect::<Vec<IncomingRecord<R>>>();
+    let num_malformed = records.iter().filter(|record| match record.kind {
+        IncomingKind::Malformed { .. } => true,
+        _ => false,
+    }).count();
+    if num_malformed > 0 {
+        warn!("Failed to deserialize {} incoming records", num_malformed);
+        incoming_telemetry.failed(num_malformed as u32);
+    }
+    records
+}
+
+fn outgoing_changeset<R: SyncRecord>(
+    collection: String,
+    timestamp: ServerTimestamp,
+    outgoing: Vec<OutgoingRecord<R>>,
+) -> error::Result<OutgoingChangeset> {
+    let mut changeset = OutgoingChangeset::new(collection, timestamp);
+    changeset.changes = outgoing
+        .into_iter()
+        .map(OutgoingRecord::into_payload)
+        .collect::<error::Result<_>>()?;
+    Ok(changeset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::EngineDeclarations;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Tab {
+        id: String,
+        title: String,
+        #[serde(rename = "lastUsed")]
+        last_used: u64,
+    }
+
+    impl SyncRecord for Tab {
+        const COLLECTION: &'static str = "tabs";
+        const STORAGE_VERSION: usize = 1;
+
+        fn record_id(&self) -> &str {
+            &self.id
+        }
+    }
+
+    fn payload(value: JsonValue) -> Payload {
+        Payload::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn test_incoming_record() {
+        let record = IncomingRecord::<Tab>::from_payload(payload(json!({
+            "id": "aaaaaaaaaaaa",
+            "title": "A tab",
+            "lastUsed": 123,
+            "newField": [1, 2, 3],
+        })), ServerTimestamp(1.0));
+        assert_eq!(record.id, "aaaaaaaaaaaa");
+        let unknown_fields = match record.kind {
+            IncomingKind::Record { record, unknown_fields } => {
+                assert_eq!(record, Tab {
+                    id: "aaaaaaaaaaaa".into(),
+                    title: "A tab".into(),
+                    last_used: 123,
+                });
+                unknown_fields
+            }
+            other => panic!("Unexpected incoming record {:?}", other),
+        };
+        assert_eq!(JsonValue::Object(unknown_fields.clone()), json!({ "newField": [1, 2, 3] }));
+
+        // Unknown fields should round-trip.
+        let outgoing = OutgoingRecord::Record {
+            record: Tab {
+                id: "aaaaaaaaaaaa".into(),
+                title: "A renamed tab".into(),
+                last_used: 456,
+            },
+            unknown_fields,
+        };
+        assert_eq!(outgoing.id(), "aaaaaaaaaaaa");
+        assert_eq!(JsonValue::from(outgoing.into_payload().unwrap()), json!({
+            "id": "aaaaaaaaaaaa",
+            "title": "A renamed tab",
+            "lastUsed": 456,
+            "newField": [1, 2, 3],
+        }));
+    }
+
+    #[test]
+    fn test_incoming_tombstone_and_malformed() {
+        let tombstone = IncomingRecord::<Tab>::from_payload(
+            Payload::new_tombstone("bbbbbbbbbbbb".into()),
+            ServerTimestamp(1.0),
+        );
+        assert_eq!(tombstone.kind, IncomingKind::Tombstone);
+
+        let malformed = IncomingRecord::<Tab>::from_payload(payload(json!({
+            "id": "cccccccccccc",
+            "title": 123,
+        })), ServerTimestamp(1.0));
+        assert_eq!(malformed.id, "cccccccccccc");
+        match malformed.kind {
+            IncomingKind::Malformed { payload, .. } => assert_eq!(payload.id, "cccccccccccc"),
+            other => panic!("Unexpected incoming record {:?}", other),
+        }
+
+        let outgoing = OutgoingRecord::<Tab>::tombstone("bbbbbbbbbbbb");
+        assert_eq!(outgoing.into_payload().unwrap(),
+                   Payload::new_tombstone("bbbbbbbbbbbb".into()));
+    }
+
+    #[test]
+    fn test_declarations_support() {
+        let mut declarations = EngineDeclarations::default();
+        declarations.support::<Tab>();
+        assert_eq!(declarations.supported.get("tabs"), Some(&1));
+    }
+}
@@ -3,11 +3,12 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::convert::From;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fmt, num};
 use std::str::FromStr;
 use openssl;
 use base64;
+use time;
 
 pub fn random_guid() -> Result<String, openssl::error::ErrorStack> {
     let mut bytes = vec![0u8; 9];
@@ -80,6 +81,26 @@ impl ServerTimestamp {
     }
 }
 
+/// Parses a backoff header, like `X-Weave-Backoff` or `Retry-After`, and
+/// returns the time before which we shouldn't make any more requests. The
+/// value is usually a number of seconds after `now`, but `Retry-After` can
+/// also be an HTTP date (RFC 7231, section 7.1.1.1).
+pub fn parse_backoff(value: &str, now: SystemTime) -> Option<SystemTime> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        if !secs.is_finite() || secs < 0.0 {
+            return None;
+        }
+        return Some(now + Duration::from_millis((secs * 1000.0) as u64));
+    }
+    let date = time::strptime(value, "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    let secs = date.to_timespec().sec;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -96,6 +117,18 @@ mod test {
         assert_eq!(dur.subsec_nanos(), 100_000_000);
     }
 
+    #[test]
+    fn test_parse_backoff() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_445_412_000);
+        assert_eq!(parse_backoff("300", now), Some(now + Duration::from_secs(300)));
+        assert_eq!(parse_backoff(" 1.5 ", now), Some(now + Duration::from_millis(1500)));
+        assert_eq!(parse_backoff("Wed, 21 Oct 2015 07:28:00 GMT", now),
+                   Some(UNIX_EPOCH + Duration::from_secs(1_445_412_480)));
+        assert_eq!(parse_backoff("-1", now), None);
+        assert_eq!(parse_backoff("soon", now), None);
+        assert_eq!(parse_backoff("Wed, 21 Oct 2015 07:28:00 PST", now), None);
+    }
+
     #[test]
     fn test_gen_guid() {
         let mut set = HashSet::new();
@@ -0,0 +1,451 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An offline validator for server data, like desktop's bookmark validator.
+//! It checks the records in a collection for structural problems, like
+//! duplicate IDs, records that don't deserialize, or bookmarks whose parents
+//! and children don't agree, and returns a report that we can use to triage
+//! server data corruption.
+//!
+//! Records can be downloaded with `fetch_and_validate`, or loaded from a JSON
+//! dump: a dump of the server's BSOs deserializes into a `Vec<EncryptedBso>`
+//! for `validate_encrypted`, and a dump of decrypted records (like the ones
+//! about:sync shows) deserializes into a `Vec<Payload>` for `validate`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::marker::PhantomData;
+
+use serde::Deserialize;
+
+use bso_record::{EncryptedBso, Payload};
+use client::Sync15StorageClient;
+use error;
+use key_bundle::KeyBundle;
+use state::GlobalState;
+use util::SERVER_EPOCH;
+
+/// A problem with the records in a collection. Serializes with a `kind` tag,
+/// like `{"kind": "missingParent", "id": "...", "parent": "..."}`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Problem {
+    /// More than one record has this ID.
+    DuplicateId { id: String },
+    /// The record couldn't be decrypted, like if it fails HMAC verification.
+    Undecryptable { id: String, reason: String },
+    /// The record didn't deserialize, or failed a collection-specific check.
+    InvalidRecord { id: String, reason: String },
+    /// The bookmark's parent isn't on the server.
+    MissingParent { id: String, parent: String },
+    /// The bookmark's parent is a tombstone.
+    DeletedParent { id: String, parent: String },
+    /// The bookmark's parent isn't a folder.
+    ParentNotFolder { id: String, parent: String },
+    /// The folder lists a child that isn't on the server.
+    MissingChild { id: String, child: String },
+    /// The folder lists a child that's a tombstone.
+    DeletedChild { id: String, child: String },
+    /// The bookmark isn't in its parent's children.
+    NotInParent { id: String, parent: String },
+    /// The bookmark is in the children of a folder that isn't its parent.
+    ParentChildMismatch { id: String, parent: String, folder: String },
+    /// The bookmark is in the children of more than one folder.
+    MultipleParents { id: String, folders: Vec<String> },
+}
+
+impl Problem {
+    /// The problem's `kind` tag, for summaries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Problem::DuplicateId { .. } => "duplicateId",
+            Problem::Undecryptable { .. } => "undecryptable",
+            Problem::InvalidRecord { .. } => "invalidRecord",
+            Problem::MissingParent { .. } => "missingParent",
+            Problem::DeletedParent { .. } => "deletedParent",
+            Problem::ParentNotFolder { .. } => "parentNotFolder",
+            Problem::MissingChild { .. } => "missingChild",
+            Problem::DeletedChild { .. } => "deletedChild",
+            Problem::NotInParent { .. } => "notInParent",
+            Problem::ParentChildMismatch { .. } => "parentChildMismatch",
+            Problem::MultipleParents { .. } => "multipleParents",
+        }
+    }
+}
+
+/// The outcome of validating a collection. Serializes to JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub collection: String,
+    /// The number of records we checked, including tombstones and records
+    /// we couldn't decrypt.
+    pub record_count: usize,
+    /// The number of problems of each kind.
+    pub summary: BTreeMap<String, usize>,
+    pub problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, problem: Problem) {
+        *self.summary.entry(problem.kind().into()).or_insert(0) += 1;
+        self.problems.push(problem);
+    }
+}
+
+/// Collection-specific checks for the validator. Tombstones and duplicate
+/// IDs are handled by the validator, so implementations only see live
+/// records, and only the first record with each ID.
+pub trait CollectionValidator {
+    fn collection_name(&self) -> &str;
+
+    /// Checks a single record, returning why it's invalid, if it is.
+    fn check_record(&self, _record: &Payload) -> Option<String> {
+        None
+    }
+
+    /// Checks how the records fit together, like the bookmark tree.
+    /// `tombstones` are the IDs of deleted records.
+    fn check_structure(
+        &self,
+        _records: &[Payload],
+        _tombstones: &HashSet<String>,
+        _problems: &mut Vec<Problem>,
+    ) {
+    }
+}
+
+/// A validator that only checks that records deserialize as `T`.
+pub struct TypedValidator<T> {
+    collection: String,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedValidator<T> {
+    pub fn new(collection: impl Into<String>) -> Self {
+        TypedValidator { collection: collection.into(), _marker: PhantomData }
+    }
+}
+
+impl<T> CollectionValidator for TypedValidator<T> where for<'a> T: Deserialize<'a> {
+    fn collection_name(&self) -> &str {
+        &self.collection
+    }
+
+    fn check_record(&self, record: &Payload) -> Option<String> {
+        record.clone().into_record::<T>().err().map(|e| e.to_string())
+    }
+}
+
+/// Validates decrypted records.
+pub fn validate(validator: &CollectionValidator, records: Vec<Payload>) -> ValidationReport {
+    let mut report = ValidationReport {
+        collection: validator.collection_name().into(),
+        record_count: records.len(),
+        ..ValidationReport::default()
+    };
+    check_records(validator, records, &mut report);
+    report
+}
+
+/// Decrypts records with `key`, and validates them. Records that we can't
+/// decrypt are reported as `Undecryptable`.
+pub fn validate_encrypted(
+    validator: &CollectionValidator,
+    key: &KeyBundle,
+    records: Vec<EncryptedBso>,
+) -> ValidationReport {
+    let mut report = ValidationReport {
+        collection: validator.collection_name().into(),
+        record_count: records.len(),
+        ..ValidationReport::default()
+    };
+    let mut decrypted = Vec::with_capacity(records.len());
+    for record in records {
+        let id = record.id.clone();
+        match record.decrypt(key) {
+            Ok(record) => decrypted.push(record.payload),
+            Err(e) => report.add(Problem::Undecryptable { id, reason: e.to_string() }),
+        }
+    }
+    check_records(validator, decrypted, &mut report);
+    report
+}
+
+/// Downloads every record in the validator's collection, and validates
+/// them. `state` must already be ready.
+pub fn fetch_and_validate(
+    client: &Sync15StorageClient,
+    state: &GlobalState,
+    validator: &CollectionValidator,
+) -> error::Result<ValidationReport> {
+    let collection = validator.collection_name();
+    info!("Validating collection {}", collection);
+    let records = client.get_encrypted_records(collection, SERVER_EPOCH)?;
+    let key = state.key_for_collection(collection)?;
+    let report = validate_encrypted(validator, key, records);
+    info!("Found {} problems in {} records", report.problems.len(), report.record_count);
+    Ok(report)
+}
+
+fn check_records(
+    validator: &CollectionValidator,
+    records: Vec<Payload>,
+    report: &mut ValidationReport,
+) {
+    let mut seen = HashSet::new();
+    let mut duplicates = HashSet::new();
+    let mut live = Vec::with_capacity(records.len());
+    let mut tombstones = HashSet::new();
+    for record in records {
+        if !seen.insert(record.id.clone()) {
+            if duplicates.insert(record.id.clone()) {
+                report.add(Problem::DuplicateId { id: record.id });
+            }
+            continue;
+        }
+        if record.is_tombstone() {
+            tombstones.insert(record.id);
+            continue;
+        }
+        match validator.check_record(&record) {
+            Some(reason) => report.add(Problem::InvalidRecord { id: record.id, reason }),
+            None => live.push(record),
+        }
+    }
+    let mut problems = Vec::new();
+    validator.check_structure(&live, &tombstones, &mut problems);
+    for problem in problems {
+        report.add(problem);
+    }
+}
+
+/// The bookmark roots' parent. It's never uploaded, so it's never missing.
+const PLACES_ROOT_ID: &str = "places";
+
+#[derive(Debug, Deserialize)]
+struct BookmarkItem {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "parentid")]
+    parent_id: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+impl BookmarkItem {
+    #[inline]
+    fn is_folder(&self) -> bool {
+        self.kind == "folder"
+    }
+}
+
+/// Checks the bookmark tree, like desktop's bookmark validator: that every
+/// bookmark's parent exists and is a folder, and that parents and children
+/// agree.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BookmarksValidator;
+
+impl CollectionValidator for BookmarksValidator {
+    fn collection_name(&self) -> &str {
+        "bookmarks"
+    }
+
+    fn check_record(&self, record: &Payload) -> Option<String> {
+        record.clone().into_record::<BookmarkItem>().err().map(|e| e.to_string())
+    }
+
+    fn check_structure(
+        &self,
+        records: &[Payload],
+        tombstones: &HashSet<String>,
+        problems: &mut Vec<Problem>,
+    ) {
+        // `check_record` already rejected records that don't deserialize.
+        let items = records.iter()
+            .filter_map(|record| {
+                let item = record.clone().into_record::<BookmarkItem>().ok()?;
+                Some((record.id.as_str(), item))
+            })
+            .collect::<HashMap<_, _>>();
+        let mut ids = items.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+
+        // Which folders list each bookmark as a child, in order.
+        let mut listed_by = HashMap::<&str, Vec<&str>>::new();
+        for &id in &ids {
+            let item = &items[id];
+            if !item.is_folder() {
+                continue;
+            }
+            for child in &item.children {
+                if tombstones.contains(child) {
+                    problems.push(Problem::DeletedChild { id: id.into(), child: child.clone() });
+                } else if !items.contains_key(child.as_str()) {
+                    problems.push(Problem::MissingChild { id: id.into(), child: child.clone() });
+                } else {
+                    listed_by.entry(child.as_str()).or_default().push(id);
+                }
+            }
+        }
+
+        for &id in &ids {
+            let parent = match items[id].parent_id {
+                Some(ref parent) if parent != PLACES_ROOT_ID => parent,
+                _ => continue,
+            };
+            let folders = listed_by.get(id).cloned().unwrap_or_default();
+            match items.get(parent.as_str()) {
+                Some(item) if !item.is_folder() => {
+                    problems.push(Problem::ParentNotFolder {
+                        id: id.into(),
+                        parent: parent.clone(),
+                    });
+                }
+                Some(_) if !folders.contains(&parent.as_str()) => {
+                    problems.push(Problem::NotInParent { id: id.into(), parent: parent.clone() });
+                }
+                Some(_) => {}
+                None if tombstones.contains(parent) => {
+                    problems.push(Problem::DeletedParent { id: id.into(), parent: parent.clone() });
+                }
+                None => {
+                    problems.push(Problem::MissingParent { id: id.into(), parent: parent.clone() });
+                }
+            }
+            for folder in folders.iter().filter(|folder| **folder != parent.as_str()) {
+                problems.push(Problem::ParentChildMismatch {
+                    id: id.into(),
+                    parent: parent.clone(),
+                    folder: (*folder).into(),
+                });
+            }
+            if folders.len() > 1 {
+                problems.push(Problem::MultipleParents {
+                    id: id.into(),
+                    folders: folders.iter().map(|folder| (*folder).into()).collect(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn payloads(value: serde_json::Value) -> Vec<Payload> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_duplicate_and_invalid_records() {
+        #[derive(Deserialize)]
+        struct Thing {
+            #[allow(dead_code)]
+            value: u32,
+        }
+        let records = payloads(json!([
+            { "id": "a", "value": 1 },
+            { "id": "a", "value": 2 },
+            { "id": "a", "value": 3 },
+            { "id": "b", "value": "nope" },
+            { "id": "c", "deleted": true },
+        ]));
+        let report = validate(&TypedValidator::<Thing>::new("things"), records);
+        assert_eq!(report.collection, "things");
+        assert_eq!(report.record_count, 5);
+        assert_eq!(report.problems.len(), 2);
+        assert_eq!(report.problems[0], Problem::DuplicateId { id: "a".into() });
+        match report.problems[1] {
+            Problem::InvalidRecord { ref id, .. } => assert_eq!(id, "b"),
+            ref problem => panic!("Unexpected problem {:?}", problem),
+        }
+        assert_eq!(report.summary["duplicateId"], 1);
+        assert_eq!(report.summary["invalidRecord"], 1);
+    }
+
+    #[test]
+    fn test_undecryptable_records() {
+        let key = KeyBundle::new_random().unwrap();
+        let other_key = KeyBundle::new_random().unwrap();
+        let records = vec![
+            Payload::from_json(json!({ "id": "good" })).unwrap()
+                .into_bso("things".into()).encrypt(&key).unwrap(),
+            Payload::from_json(json!({ "id": "bad" })).unwrap()
+                .into_bso("things".into()).encrypt(&other_key).unwrap(),
+        ];
+        let report = validate_encrypted(&TypedValidator::<Payload>::new("things"), &key, records);
+        assert_eq!(report.record_count, 2);
+        assert_eq!(report.problems.len(), 1);
+        match report.problems[0] {
+            Problem::Undecryptable { ref id, .. } => assert_eq!(id, "bad"),
+            ref problem => panic!("Unexpected problem {:?}", problem),
+        }
+    }
+
+    #[test]
+    fn test_bookmark_tree() {
+        let records = payloads(json!([
+            { "id": "menu", "type": "folder", "parentid": "places",
+              "children": ["bm1", "bm2", "gone", "missing", "bm4"] },
+            { "id": "toolbar", "type": "folder", "parentid": "places",
+              "children": ["bm2", "bm3"] },
+            // Fine.
+            { "id": "bm1", "type": "bookmark", "parentid": "menu" },
+            // In both folders.
+            { "id": "bm2", "type": "bookmark", "parentid": "menu" },
+            // Says its parent is the menu, but it's in the toolbar.
+            { "id": "bm3", "type": "bookmark", "parentid": "menu" },
+            // Its parent isn't a folder.
+            { "id": "bm4", "type": "bookmark", "parentid": "bm1" },
+            { "id": "orphan", "type": "bookmark", "parentid": "nowhere" },
+            { "id": "deleted-parent", "type": "bookmark", "parentid": "gone" },
+            { "id": "gone", "deleted": true },
+            { "id": "no-type", "parentid": "menu" },
+        ]));
+        let report = validate(&BookmarksValidator, records);
+        let menu_and_toolbar = vec!["menu".to_string(), "toolbar".to_string()];
+        assert_eq!(report.problems.len(), 11);
+        match report.problems[0] {
+            Problem::InvalidRecord { ref id, .. } => assert_eq!(id, "no-type"),
+            ref problem => panic!("Unexpected problem {:?}", problem),
+        }
+        assert_eq!(report.problems[1..].to_vec(), vec![
+            Problem::DeletedChild { id: "menu".into(), child: "gone".into() },
+            Problem::MissingChild { id: "menu".into(), child: "missing".into() },
+            Problem::ParentChildMismatch {
+                id: "bm2".into(),
+                parent: "menu".into(),
+                folder: "toolbar".into(),
+            },
+            Problem::MultipleParents { id: "bm2".into(), folders: menu_and_toolbar },
+            Problem::NotInParent { id: "bm3".into(), parent: "menu".into() },
+            Problem::ParentChildMismatch {
+                id: "bm3".into(),
+                parent: "menu".into(),
+                folder: "toolbar".into(),
+            },
+            Problem::ParentNotFolder { id: "bm4".into(), parent: "bm1".into() },
+            Problem::ParentChildMismatch {
+                id: "bm4".into(),
+                parent: "bm1".into(),
+                folder: "menu".into(),
+            },
+            Problem::DeletedParent { id: "deleted-parent".into(), parent: "gone".into() },
+            Problem::MissingParent { id: "orphan".into(), parent: "nowhere".into() },
+        ]);
+        assert_eq!(report.summary["parentChildMismatch"], 3);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["problems"][1], json!({
+            "kind": "deletedChild",
+            "id": "menu",
+            "child": "gone",
+        }));
+    }
+}
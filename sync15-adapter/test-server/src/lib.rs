@@ -0,0 +1,928 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An in-memory implementation of the Sync 1.5 storage API and the
+//! tokenserver, for driving `sync15_adapter` end-to-end in tests without
+//! network access.
+//!
+//! `TestServer` implements `HttpTransport`, so a storage client created with
+//! `TestServer::client` sends all of its requests to it. The server is a
+//! cheaply cloneable handle, which lets tests keep inspecting and mutating
+//! server state (simulating other clients, node reassignments, failures, etc)
+//! while the client owns a copy.
+//!
+//! Payloads are stored as the opaque strings the client uploads; the server
+//! never sees cleartext.
+
+extern crate sync15_adapter;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use serde_json::Value as JsonValue;
+
+use sync15_adapter::bso_record::{EncryptedBso, EncryptedPayload};
+use sync15_adapter::error::Result;
+use sync15_adapter::request::{InfoConfiguration, X_IF_UNMODIFIED_SINCE, X_LAST_MODIFIED,
+                              X_WEAVE_NEXT_OFFSET, X_WEAVE_QUOTA_REMAINING, X_WEAVE_TIMESTAMP};
+use sync15_adapter::transport::{HeaderMap, HeaderValue, HttpRequest, HttpResponse, HttpTransport,
+                                Method, StatusCode, Url};
+use sync15_adapter::{ServerTimestamp, Sync15StorageClient, Sync15StorageClientInit,
+                     TokenserverCredentials};
+
+pub const TOKENSERVER_URL: &str = "https://token.example.com/1.0/sync/1.5";
+pub const TOKENSERVER_HOST: &str = "token.example.com";
+pub const STORAGE_HOST: &str = "storage.example.com";
+
+const X_TIMESTAMP: &str = "X-Timestamp";
+const X_WEAVE_RECORDS: &str = "X-Weave-Records";
+
+/// The server's clock starts here, and advances by one second for every
+/// write (and whenever a test calls `advance_time`).
+const INITIAL_TIME: f64 = 1_500_000_000.0;
+
+const MAX_ID_LENGTH: usize = 64;
+
+type Reply = (StatusCode, HeaderMap, JsonValue);
+
+#[derive(Debug, Clone)]
+struct StoredBso {
+    id: String,
+    modified: ServerTimestamp,
+    sortindex: Option<i32>,
+    ttl: Option<u32>,
+    payload: String,
+}
+
+impl StoredBso {
+    fn to_json(&self) -> JsonValue {
+        let mut value = json!({
+            "id": self.id,
+            "modified": self.modified.0,
+            "payload": self.payload,
+        });
+        if let Some(sortindex) = self.sortindex {
+            value["sortindex"] = json!(sortindex);
+        }
+        if let Some(ttl) = self.ttl {
+            value["ttl"] = json!(ttl);
+        }
+        value
+    }
+}
+
+/// A BSO as sent by the client in a POST or PUT. Everything is optional,
+/// since PUTs may partially update an existing record.
+#[derive(Debug, Clone, Deserialize)]
+struct IncomingBso {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    sortindex: Option<i32>,
+    #[serde(default)]
+    ttl: Option<u32>,
+    #[serde(default)]
+    payload: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Collection {
+    records: HashMap<String, StoredBso>,
+    modified: ServerTimestamp,
+}
+
+#[derive(Debug)]
+struct Batch {
+    collection: String,
+    records: Vec<IncomingBso>,
+}
+
+#[derive(Debug)]
+struct InjectedResponse {
+    method: Method,
+    url_suffix: String,
+    status: StatusCode,
+    headers: HeaderMap,
+}
+
+#[derive(Debug)]
+struct PendingWrite {
+    method: Method,
+    url_suffix: String,
+    collection: String,
+    bsos: Vec<IncomingBso>,
+}
+
+#[derive(Debug)]
+struct ServerState {
+    now: f64,
+    uid: u64,
+    config: InfoConfiguration,
+    quota_bytes: Option<usize>,
+    collections: HashMap<String, Collection>,
+    batches: HashMap<String, Batch>,
+    next_batch_id: u64,
+    injected: VecDeque<InjectedResponse>,
+    pending_writes: VecDeque<PendingWrite>,
+    rejections: Vec<(String, String)>,
+    requests: Vec<(Method, Url)>,
+}
+
+/// A handle to an in-memory sync server. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct TestServer {
+    state: Rc<RefCell<ServerState>>,
+}
+
+impl Default for TestServer {
+    fn default() -> TestServer {
+        TestServer::new()
+    }
+}
+
+impl TestServer {
+    pub fn new() -> TestServer {
+        TestServer::with_config(InfoConfiguration::default())
+    }
+
+    /// Creates a server that reports (and enforces the per-record payload
+    /// limit from) `config` in `info/configuration`.
+    pub fn with_config(config: InfoConfiguration) -> TestServer {
+        TestServer {
+            state: Rc::new(RefCell::new(ServerState {
+                now: INITIAL_TIME,
+                uid: 1,
+                config,
+                quota_bytes: None,
+                collections: HashMap::new(),
+                batches: HashMap::new(),
+                next_batch_id: 1,
+                injected: VecDeque::new(),
+                pending_writes: VecDeque::new(),
+                rejections: Vec::new(),
+                requests: Vec::new(),
+            })),
+        }
+    }
+
+    /// Returns init params for a storage client that talks to this server.
+    pub fn storage_init(&self) -> Sync15StorageClientInit {
+        Sync15StorageClientInit {
+            credentials: TokenserverCredentials::OAuth {
+                access_token: "test-access-token".into(),
+                key_id: "test-key-id".into(),
+            },
+            tokenserver_url: Url::parse(TOKENSERVER_URL).unwrap(),
+        }
+    }
+
+    /// Creates a storage client that sends all of its requests to this
+    /// server.
+    pub fn client(&self) -> Sync15StorageClient {
+        Sync15StorageClient::with_transport(self.storage_init(), Box::new(self.clone()))
+    }
+
+    /// The storage endpoint handed out by the tokenserver.
+    pub fn api_endpoint(&self) -> String {
+        self.state.borrow().api_endpoint()
+    }
+
+    #[inline]
+    pub fn now(&self) -> ServerTimestamp {
+        ServerTimestamp(self.state.borrow().now)
+    }
+
+    /// Moves the server clock forward, e.g. to expire records with a `ttl`.
+    pub fn advance_time(&self, seconds: f64) {
+        self.state.borrow_mut().now += seconds;
+    }
+
+    /// Limits the total size of the payloads the user can store. Writes that
+    /// would go over the quota fail with a 507, and successful writes report
+    /// the space left in `X-Weave-Quota-Remaining`.
+    pub fn set_quota(&self, quota_bytes: Option<usize>) {
+        self.state.borrow_mut().quota_bytes = quota_bytes;
+    }
+
+    /// Uploads a record as if another client had written it, bumping the
+    /// collection's last modified time. Returns the new timestamp.
+    pub fn insert_record(&self, collection: &str, record: EncryptedBso) -> ServerTimestamp {
+        self.insert_records(collection, vec![record])
+    }
+
+    /// Like `insert_record`, but writes all `records` with the same
+    /// timestamp, as if another client had uploaded them in one batch.
+    pub fn insert_records(&self, collection: &str, records: Vec<EncryptedBso>) -> ServerTimestamp {
+        self.state.borrow_mut().apply(collection, incoming_bsos(records))
+    }
+
+    /// Like `insert_records`, but waits until just before the next `method`
+    /// request whose URL ends with `url_suffix`, to simulate another client
+    /// writing while we're in the middle of a sync.
+    pub fn insert_records_before_next(
+        &self,
+        method: Method,
+        url_suffix: &str,
+        collection: &str,
+        records: Vec<EncryptedBso>,
+    ) {
+        self.state.borrow_mut().pending_writes.push_back(PendingWrite {
+            method,
+            url_suffix: url_suffix.into(),
+            collection: collection.into(),
+            bsos: incoming_bsos(records),
+        });
+    }
+
+    /// Returns all live records in an encrypted collection, in no particular
+    /// order. Panics for collections with cleartext payloads, like `meta`.
+    pub fn records(&self, collection: &str) -> Vec<EncryptedBso> {
+        let state = self.state.borrow();
+        let now = state.now;
+        state.collections.get(collection).map(|coll| {
+            coll.records.values()
+                .filter(|bso| is_live(bso, now))
+                .map(|bso| EncryptedBso {
+                    id: bso.id.clone(),
+                    collection: collection.into(),
+                    modified: bso.modified,
+                    sortindex: bso.sortindex,
+                    ttl: bso.ttl,
+                    payload: serde_json::from_str::<EncryptedPayload>(&bso.payload)
+                        .expect("Stored payload isn't an EncryptedPayload"),
+                })
+                .collect()
+        }).unwrap_or_default()
+    }
+
+    pub fn collection_modified(&self, collection: &str) -> Option<ServerTimestamp> {
+        self.state.borrow().collections.get(collection).map(|coll| coll.modified)
+    }
+
+    /// Simulates a node reassignment: the user's storage is wiped, requests
+    /// to the old endpoint fail with a 401, and the tokenserver hands out a
+    /// new `api_endpoint`.
+    pub fn reassign_node(&self) {
+        let mut state = self.state.borrow_mut();
+        state.uid += 1;
+        state.collections.clear();
+        state.batches.clear();
+    }
+
+    /// Makes the next `method` request whose URL ends with `url_suffix` fail
+    /// with `status`, without touching server state. The suffix can include
+    /// query parameters, like `"/storage/bookmarks?full=1"`.
+    pub fn fail_next(&self, method: Method, url_suffix: &str, status: StatusCode) {
+        self.fail_next_with_headers(method, url_suffix, status, HeaderMap::new());
+    }
+
+    /// Like `fail_next`, but also includes `headers` (e.g. `Retry-After`) in
+    /// the failed response.
+    pub fn fail_next_with_headers(
+        &self,
+        method: Method,
+        url_suffix: &str,
+        status: StatusCode,
+        headers: HeaderMap,
+    ) {
+        self.state.borrow_mut().injected.push_back(InjectedResponse {
+            method,
+            url_suffix: url_suffix.into(),
+            status,
+            headers,
+        });
+    }
+
+    /// Makes the server reject the next upload of the record with `id`,
+    /// listing it as failed with `reason` instead of storing it. Call this
+    /// more than once to reject several uploads in a row.
+    pub fn reject_next_upload(&self, id: &str, reason: &str) {
+        self.state.borrow_mut().rejections.push((id.into(), reason.into()));
+    }
+
+    /// Every request the server has received, in order.
+    pub fn requests(&self) -> Vec<(Method, Url)> {
+        self.state.borrow().requests.clone()
+    }
+}
+
+impl HttpTransport for TestServer {
+    fn execute(&self, req: HttpRequest) -> Result<HttpResponse> {
+        let mut state = self.state.borrow_mut();
+        state.requests.push((req.method.clone(), req.url.clone()));
+        state.apply_pending_writes(&req);
+        let (status, mut headers, body) = match state.take_injected(&req) {
+            Some(reply) => reply,
+            None => {
+                if req.url.host_str() == Some(TOKENSERVER_HOST) {
+                    state.handle_token_request(&req)
+                } else {
+                    state.handle_storage_request(&req)
+                }
+            }
+        };
+        headers.insert(X_WEAVE_TIMESTAMP, timestamp_header(ServerTimestamp(state.now)));
+        Ok(HttpResponse {
+            status,
+            headers,
+            body: serde_json::to_vec(&body)?,
+            url: req.url,
+        })
+    }
+}
+
+fn incoming_bsos(records: Vec<EncryptedBso>) -> Vec<IncomingBso> {
+    records.into_iter().map(|record| IncomingBso {
+        payload: Some(serde_json::to_string(&record.payload).unwrap()),
+        id: Some(record.id),
+        sortindex: record.sortindex,
+        ttl: record.ttl,
+    }).collect()
+}
+
+fn kilobytes(bytes: usize) -> f64 {
+    bytes as f64 / 1024.0
+}
+
+fn is_live(bso: &StoredBso, now: f64) -> bool {
+    bso.ttl.map_or(true, |ttl| bso.modified.0 + f64::from(ttl) > now)
+}
+
+fn timestamp_header(ts: ServerTimestamp) -> HeaderValue {
+    HeaderValue::from_str(&format!("{:.2}", ts.0)).unwrap()
+}
+
+fn reply(status: StatusCode, body: JsonValue) -> Reply {
+    (status, HeaderMap::new(), body)
+}
+
+fn not_found() -> Reply {
+    reply(StatusCode::NOT_FOUND, json!(0))
+}
+
+fn bad_request() -> Reply {
+    reply(StatusCode::BAD_REQUEST, json!(0))
+}
+
+fn precondition_failed() -> Reply {
+    reply(StatusCode::PRECONDITION_FAILED, json!({}))
+}
+
+fn query_params(url: &Url) -> HashMap<String, String> {
+    url.query_pairs().map(|(k, v)| (k.into_owned(), v.into_owned())).collect()
+}
+
+fn xius(req: &HttpRequest) -> Option<ServerTimestamp> {
+    req.headers.get(X_IF_UNMODIFIED_SINCE)
+       .and_then(|v| v.to_str().ok())
+       .and_then(|s| ServerTimestamp::from_str(s).ok())
+}
+
+fn has_auth_scheme(req: &HttpRequest, scheme: &str) -> bool {
+    req.headers.get("Authorization")
+       .and_then(|v| v.to_str().ok())
+       .map_or(false, |s| s.starts_with(scheme))
+}
+
+fn parse_body<T>(req: &HttpRequest) -> Option<T> where for<'a> T: serde::Deserialize<'a> {
+    req.body.as_ref().and_then(|body| serde_json::from_slice(body).ok())
+}
+
+impl ServerState {
+    fn api_endpoint(&self) -> String {
+        format!("https://{}/1.5/{}", STORAGE_HOST, self.uid)
+    }
+
+    fn tick(&mut self) -> ServerTimestamp {
+        self.now += 1.0;
+        ServerTimestamp(self.now)
+    }
+
+    fn take_injected(&mut self, req: &HttpRequest) -> Option<Reply> {
+        let index = self.injected.iter().position(|injected| {
+            injected.method == req.method && req.url.as_str().ends_with(&injected.url_suffix)
+        })?;
+        let injected = self.injected.remove(index).unwrap();
+        Some((injected.status, injected.headers, json!(0)))
+    }
+
+    fn apply_pending_writes(&mut self, req: &HttpRequest) {
+        while let Some(index) = self.pending_writes.iter().position(|pending| {
+            pending.method == req.method && req.url.as_str().ends_with(&pending.url_suffix)
+        }) {
+            let pending = self.pending_writes.remove(index).unwrap();
+            self.apply(&pending.collection, pending.bsos);
+        }
+    }
+
+    fn collection_modified(&self, name: &str) -> ServerTimestamp {
+        self.collections.get(name).map(|coll| coll.modified).unwrap_or_default()
+    }
+
+    fn handle_token_request(&mut self, req: &HttpRequest) -> Reply {
+        if req.url.path() != Url::parse(TOKENSERVER_URL).unwrap().path() {
+            return not_found();
+        }
+        if !has_auth_scheme(req, "Bearer ") && !has_auth_scheme(req, "BrowserID ") {
+            return reply(StatusCode::UNAUTHORIZED, json!({ "status": "invalid-credentials" }));
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(X_TIMESTAMP, HeaderValue::from_str(&format!("{}", self.now as u64)).unwrap());
+        (StatusCode::OK, headers, json!({
+            "id": format!("token-{}", self.uid),
+            "key": "test-hawk-key",
+            "api_endpoint": self.api_endpoint(),
+            "uid": self.uid,
+            "duration": 3600,
+            "hashed_fxa_uid": "test-hashed-fxa-uid",
+        }))
+    }
+
+    fn handle_storage_request(&mut self, req: &HttpRequest) -> Reply {
+        if req.url.host_str() != Some(STORAGE_HOST) {
+            return not_found();
+        }
+        let segments: Vec<String> = match req.url.path_segments() {
+            Some(segments) => segments.map(|s| s.to_string()).collect(),
+            None => return not_found(),
+        };
+        if segments.len() < 2 || segments[0] != "1.5" {
+            return not_found();
+        }
+        // Requests to the node we used to be on, or without credentials,
+        // look the same to the client.
+        if segments[1] != self.uid.to_string() || !has_auth_scheme(req, "Hawk ") {
+            return reply(StatusCode::UNAUTHORIZED, json!(0));
+        }
+        let rest: Vec<&str> = segments[2..].iter()
+                                           .map(|s| s.as_str())
+                                           .filter(|s| !s.is_empty())
+                                           .collect();
+        let is_write = req.method == Method::POST || req.method == Method::PUT;
+        if is_write {
+            // This overestimates how much space the write takes up, since
+            // the body includes IDs, and might replace existing records.
+            let size = req.body.as_ref().map_or(0, |body| body.len());
+            if self.quota_bytes.map_or(false, |quota| self.usage_bytes() + size > quota) {
+                return reply(StatusCode::INSUFFICIENT_STORAGE, json!(0));
+            }
+        }
+        let mut reply = self.route_storage_request(req, &rest);
+        if let Some(quota) = self.quota_bytes {
+            if is_write && reply.0.is_success() {
+                let remaining = kilobytes(quota.saturating_sub(self.usage_bytes()));
+                reply.1.insert(X_WEAVE_QUOTA_REMAINING,
+                               HeaderValue::from_str(&format!("{:.2}", remaining)).unwrap());
+            }
+        }
+        reply
+    }
+
+    fn route_storage_request(&mut self, req: &HttpRequest, rest: &[&str]) -> Reply {
+        match (req.method.as_str(), rest) {
+            ("GET", ["info", "collections"]) => self.info_collections(),
+            ("GET", ["info", "quota"]) => {
+                let quota = self.quota_bytes.map(kilobytes);
+                reply(StatusCode::OK, json!([kilobytes(self.usage_bytes()), quota]))
+            }
+            ("GET", ["info", "collection_usage"]) => self.info_collection_usage(),
+            ("GET", ["info", "collection_counts"]) => self.info_collection_counts(),
+            ("GET", ["info", "configuration"]) => {
+                reply(StatusCode::OK, serde_json::to_value(&self.config).unwrap())
+            }
+            ("DELETE", []) | ("DELETE", ["storage"]) => {
+                self.collections.clear();
+                self.batches.clear();
+                reply(StatusCode::OK, json!({}))
+            }
+            ("GET", ["storage", coll]) => self.get_collection(coll, req),
+            ("POST", ["storage", coll]) => self.post_collection(coll, req),
+            ("DELETE", ["storage", coll]) => self.delete_collection(coll, req),
+            ("GET", ["storage", coll, id]) => self.get_record(coll, id, req),
+            ("PUT", ["storage", coll, id]) => self.put_record(coll, id, req),
+            ("DELETE", ["storage", coll, id]) => self.delete_record(coll, id, req),
+            _ => not_found(),
+        }
+    }
+
+    fn info_collections(&self) -> Reply {
+        let modified = self.collections.iter()
+                                       .map(|(name, coll)| (name.clone(), json!(coll.modified.0)))
+                                       .collect::<serde_json::Map<_, _>>();
+        reply(StatusCode::OK, JsonValue::Object(modified))
+    }
+
+    fn live_records<'a>(&'a self, coll: &'a Collection) -> impl Iterator<Item = &'a StoredBso> {
+        let now = self.now;
+        coll.records.values().filter(move |bso| is_live(bso, now))
+    }
+
+    fn usage_bytes(&self) -> usize {
+        self.collections.values()
+            .flat_map(|coll| self.live_records(coll))
+            .map(|bso| bso.payload.len())
+            .sum()
+    }
+
+    fn info_collection_usage(&self) -> Reply {
+        let usage = self.collections.iter()
+            .map(|(name, coll)| {
+                let bytes = self.live_records(coll).map(|bso| bso.payload.len()).sum();
+                (name.clone(), json!(kilobytes(bytes)))
+            })
+            .collect::<serde_json::Map<_, _>>();
+        reply(StatusCode::OK, JsonValue::Object(usage))
+    }
+
+    fn info_collection_counts(&self) -> Reply {
+        let counts = self.collections.iter()
+            .map(|(name, coll)| (name.clone(), json!(self.live_records(coll).count())))
+            .collect::<serde_json::Map<_, _>>();
+        reply(StatusCode::OK, JsonValue::Object(counts))
+    }
+
+    fn get_collection(&self, name: &str, req: &HttpRequest) -> Reply {
+        let modified = self.collection_modified(name);
+        if xius(req).map_or(false, |xius| modified > xius) {
+            return precondition_failed();
+        }
+        let params = query_params(&req.url);
+        let newer = params.get("newer").and_then(|s| ServerTimestamp::from_str(s).ok());
+        let older = params.get("older").and_then(|s| ServerTimestamp::from_str(s).ok());
+        let ids = params.get("ids").map(|s| s.split(',').map(|id| id.to_string()).collect::<Vec<_>>());
+
+        let mut records: Vec<&StoredBso> = self.collections.get(name).map(|coll| {
+            coll.records.values()
+                .filter(|bso| is_live(bso, self.now))
+                .filter(|bso| newer.map_or(true, |ts| bso.modified > ts))
+                .filter(|bso| older.map_or(true, |ts| bso.modified < ts))
+                .filter(|bso| ids.as_ref().map_or(true, |ids| ids.contains(&bso.id)))
+                .collect()
+        }).unwrap_or_default();
+
+        // Break ties by ID, so that paging through records is deterministic.
+        match params.get("sort").map(|s| s.as_str()) {
+            Some("oldest") => records.sort_by(|a, b| {
+                a.modified.partial_cmp(&b.modified).unwrap().then_with(|| a.id.cmp(&b.id))
+            }),
+            Some("index") => records.sort_by(|a, b| {
+                b.sortindex.cmp(&a.sortindex).then_with(|| a.id.cmp(&b.id))
+            }),
+            _ => records.sort_by(|a, b| {
+                b.modified.partial_cmp(&a.modified).unwrap().then_with(|| a.id.cmp(&b.id))
+            }),
+        }
+
+        // Our offset tokens are just indices into the sorted list.
+        let total = records.len();
+        let start = match params.get("offset") {
+            Some(offset) => match offset.parse::<usize>() {
+                Ok(offset) => offset.min(total),
+                Err(_) => return bad_request(),
+            },
+            None => 0,
+        };
+        let end = params.get("limit")
+                        .and_then(|s| s.parse::<usize>().ok())
+                        .map_or(total, |limit| (start + limit).min(total));
+        let page = &records[start..end];
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_LAST_MODIFIED, timestamp_header(modified));
+        headers.insert(X_WEAVE_RECORDS, HeaderValue::from_str(&page.len().to_string()).unwrap());
+        if end < total {
+            headers.insert(X_WEAVE_NEXT_OFFSET, HeaderValue::from_str(&end.to_string()).unwrap());
+        }
+        let body = if params.contains_key("full") {
+            JsonValue::Array(page.iter().map(|bso| bso.to_json()).collect())
+        } else {
+            JsonValue::Array(page.iter().map(|bso| json!(bso.id)).collect())
+        };
+        (StatusCode::OK, headers, body)
+    }
+
+    fn post_collection(&mut self, name: &str, req: &HttpRequest) -> Reply {
+        if xius(req).map_or(false, |xius| self.collection_modified(name) > xius) {
+            return precondition_failed();
+        }
+        let incoming: Vec<IncomingBso> = match parse_body(req) {
+            Some(records) => records,
+            None => return bad_request(),
+        };
+
+        let mut success = Vec::new();
+        let mut failed = serde_json::Map::new();
+        let mut valid = Vec::new();
+        for bso in incoming {
+            let id = bso.id.clone().unwrap_or_default();
+            if let Some(index) = self.rejections.iter().position(|(rejected, _)| rejected == &id) {
+                let (_, reason) = self.rejections.remove(index);
+                failed.insert(id, json!(reason));
+            } else if id.is_empty() || id.len() > MAX_ID_LENGTH {
+                failed.insert(id, json!("invalid id"));
+            } else if bso.payload.as_ref().map_or(0, |p| p.len()) > self.config.max_record_payload_bytes {
+                failed.insert(id, json!("retry bytes"));
+            } else {
+                success.push(id);
+                valid.push(bso);
+            }
+        }
+
+        let params = query_params(&req.url);
+        let commit = params.get("commit").map_or(false, |s| s == "true");
+        let batch_id = match params.get("batch") {
+            None => {
+                let modified = self.apply(name, valid);
+                return self.upload_reply(StatusCode::OK, modified, None, success, failed);
+            }
+            Some(batch) if batch == "true" => {
+                let id = self.next_batch_id.to_string();
+                self.next_batch_id += 1;
+                self.batches.insert(id.clone(), Batch {
+                    collection: name.into(),
+                    records: Vec::new(),
+                });
+                id
+            }
+            Some(batch) => match self.batches.get(batch) {
+                Some(existing) if existing.collection == name => batch.clone(),
+                _ => return bad_request(),
+            },
+        };
+
+        self.batches.get_mut(&batch_id).unwrap().records.extend(valid);
+        if commit {
+            let batch = self.batches.remove(&batch_id).unwrap();
+            let modified = self.apply(name, batch.records);
+            self.upload_reply(StatusCode::OK, modified, None, success, failed)
+        } else {
+            let modified = self.collection_modified(name);
+            self.upload_reply(StatusCode::ACCEPTED, modified, Some(batch_id), success, failed)
+        }
+    }
+
+    fn upload_reply(
+        &self,
+        status: StatusCode,
+        modified: ServerTimestamp,
+        batch: Option<String>,
+        success: Vec<String>,
+        failed: serde_json::Map<String, JsonValue>,
+    ) -> Reply {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_LAST_MODIFIED, timestamp_header(modified));
+        let mut body = json!({
+            "modified": modified.0,
+            "success": success,
+            "failed": failed,
+        });
+        if let Some(batch) = batch {
+            body["batch"] = json!(batch);
+        }
+        (status, headers, body)
+    }
+
+    /// Writes `bsos` to a collection with a single new timestamp, the same
+    /// way a batch commit does.
+    fn apply(&mut self, name: &str, bsos: Vec<IncomingBso>) -> ServerTimestamp {
+        let modified = self.tick();
+        let coll = self.collections.entry(name.into()).or_default();
+        for bso in bsos {
+            let id = bso.id.unwrap_or_default();
+            let stored = coll.records.entry(id.clone()).or_insert_with(|| StoredBso {
+                id,
+                modified,
+                sortindex: None,
+                ttl: None,
+                payload: String::new(),
+            });
+            stored.modified = modified;
+            if bso.sortindex.is_some() {
+                stored.sortindex = bso.sortindex;
+            }
+            if bso.ttl.is_some() {
+                stored.ttl = bso.ttl;
+            }
+            if let Some(payload) = bso.payload {
+                stored.payload = payload;
+            }
+        }
+        coll.modified = modified;
+        modified
+    }
+
+    fn delete_collection(&mut self, name: &str, req: &HttpRequest) -> Reply {
+        if xius(req).map_or(false, |xius| self.collection_modified(name) > xius) {
+            return precondition_failed();
+        }
+        let params = query_params(&req.url);
+        let modified = self.tick();
+        match params.get("ids") {
+            Some(ids) => {
+                if let Some(coll) = self.collections.get_mut(name) {
+                    for id in ids.split(',') {
+                        coll.records.remove(id);
+                    }
+                    coll.modified = modified;
+                }
+            }
+            None => {
+                self.collections.remove(name);
+            }
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(X_LAST_MODIFIED, timestamp_header(modified));
+        (StatusCode::OK, headers, json!({ "modified": modified.0 }))
+    }
+
+    fn find_record(&self, name: &str, id: &str) -> Option<&StoredBso> {
+        self.collections.get(name)
+            .and_then(|coll| coll.records.get(id))
+            .filter(|bso| is_live(bso, self.now))
+    }
+
+    fn get_record(&self, name: &str, id: &str, req: &HttpRequest) -> Reply {
+        let bso = match self.find_record(name, id) {
+            Some(bso) => bso,
+            None => return not_found(),
+        };
+        if xius(req).map_or(false, |xius| bso.modified > xius) {
+            return precondition_failed();
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(X_LAST_MODIFIED, timestamp_header(bso.modified));
+        (StatusCode::OK, headers, bso.to_json())
+    }
+
+    fn put_record(&mut self, name: &str, id: &str, req: &HttpRequest) -> Reply {
+        let existing_modified = self.find_record(name, id)
+                                    .map(|bso| bso.modified)
+                                    .unwrap_or_default();
+        if xius(req).map_or(false, |xius| existing_modified > xius) {
+            return precondition_failed();
+        }
+        let mut bso: IncomingBso = match parse_body(req) {
+            Some(bso) => bso,
+            None => return bad_request(),
+        };
+        if bso.payload.as_ref().map_or(0, |p| p.len()) > self.config.max_record_payload_bytes {
+            return reply(StatusCode::PAYLOAD_TOO_LARGE, json!(0));
+        }
+        bso.id = Some(id.into());
+        let modified = self.apply(name, vec![bso]);
+        let mut headers = HeaderMap::new();
+        headers.insert(X_LAST_MODIFIED, timestamp_header(modified));
+        (StatusCode::OK, headers, json!(modified.0))
+    }
+
+    fn delete_record(&mut self, name: &str, id: &str, req: &HttpRequest) -> Reply {
+        let existing_modified = match self.find_record(name, id) {
+            Some(bso) => bso.modified,
+            None => return not_found(),
+        };
+        if xius(req).map_or(false, |xius| existing_modified > xius) {
+            return precondition_failed();
+        }
+        let modified = self.tick();
+        let coll = self.collections.get_mut(name).unwrap();
+        coll.records.remove(id);
+        coll.modified = modified;
+        let mut headers = HeaderMap::new();
+        headers.insert(X_LAST_MODIFIED, timestamp_header(modified));
+        (StatusCode::OK, headers, json!({ "modified": modified.0 }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sync15_adapter::client::SetupStorageClient;
+    use sync15_adapter::{ErrorKind, KeyBundle, Payload};
+
+    fn records(count: usize) -> Vec<EncryptedBso> {
+        let key = KeyBundle::new_random().unwrap();
+        (0..count).map(|i| {
+            let payload = Payload::from_json(json!({ "id": format!("record{:06}", i) })).unwrap();
+            payload.into_bso("testing".into()).encrypt(&key).unwrap()
+        }).collect()
+    }
+
+    fn request(
+        server: &TestServer,
+        method: Method,
+        path: &str,
+        xius: Option<ServerTimestamp>,
+        body: Option<JsonValue>,
+    ) -> HttpResponse {
+        let url = Url::parse(&format!("{}/{}", server.api_endpoint(), path)).unwrap();
+        let mut req = HttpRequest::new(method, url);
+        req.headers.insert("Authorization", HeaderValue::from_static("Hawk test"));
+        if let Some(ts) = xius {
+            req.headers.insert(X_IF_UNMODIFIED_SINCE, timestamp_header(ts));
+        }
+        req.body = body.map(|body| serde_json::to_vec(&body).unwrap());
+        server.execute(req).unwrap()
+    }
+
+    fn get(server: &TestServer, path: &str, xius: Option<ServerTimestamp>) -> HttpResponse {
+        request(server, Method::GET, path, xius, None)
+    }
+
+    #[test]
+    fn test_batch_upload() {
+        let server = TestServer::new();
+        let bsos = records(5);
+        let post = |query: &str, bsos: &[EncryptedBso]| {
+            let body = serde_json::to_value(bsos).unwrap();
+            request(&server, Method::POST, &format!("storage/testing?{}", query), None, Some(body))
+        };
+
+        let resp = post("batch=true", &bsos[0..2]);
+        assert_eq!(resp.status, StatusCode::ACCEPTED);
+        let batch = resp.json::<JsonValue>().unwrap()["batch"].as_str().unwrap().to_string();
+        assert_eq!(post(&format!("batch={}", batch), &bsos[2..4]).status, StatusCode::ACCEPTED);
+        // Nothing is written until the batch is committed.
+        assert!(server.records("testing").is_empty());
+
+        let resp = post(&format!("batch={}&commit=true", batch), &bsos[4..]);
+        assert_eq!(resp.status, StatusCode::OK);
+        let records = server.records("testing");
+        assert_eq!(records.len(), 5);
+        // All records in a batch are committed with the same timestamp.
+        assert!(records.iter().all(|r| r.modified == records[0].modified));
+        // Committed batches are gone.
+        assert_eq!(post(&format!("batch={}", batch), &bsos[0..1]).status,
+                   StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_paging_and_conditions() {
+        let server = TestServer::new();
+        let modified = server.insert_records("testing", records(3));
+
+        let resp = get(&server, "storage/testing?sort=oldest&limit=2", None);
+        assert!(resp.is_success());
+        assert_eq!(resp.json::<Vec<String>>().unwrap().len(), 2);
+        assert_eq!(resp.headers[X_WEAVE_NEXT_OFFSET], "2");
+
+        let resp = get(&server, "storage/testing?sort=oldest&limit=2&offset=2", None);
+        assert_eq!(resp.json::<Vec<String>>().unwrap().len(), 1);
+        assert!(resp.headers.get(X_WEAVE_NEXT_OFFSET).is_none());
+
+        let resp = get(&server, "storage/testing", Some(modified));
+        assert!(resp.is_success());
+        let resp = get(&server, "storage/testing", Some(ServerTimestamp(modified.0 - 1.0)));
+        assert_eq!(resp.status, StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let server = TestServer::new();
+        let mut bso = records(1).pop().unwrap();
+        bso.ttl = Some(10);
+        server.insert_record("testing", bso);
+        assert_eq!(server.records("testing").len(), 1);
+        server.advance_time(11.0);
+        assert!(server.records("testing").is_empty());
+    }
+
+    #[test]
+    fn test_injected_failures() {
+        let server = TestServer::new();
+        let client = server.client();
+        server.fail_next(Method::GET, "/info/collections", StatusCode::INTERNAL_SERVER_ERROR);
+        match client.fetch_info_collections().unwrap_err().kind() {
+            ErrorKind::StorageHttpError { code: 500, .. } => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert!(client.fetch_info_collections().is_ok());
+    }
+
+    #[test]
+    fn test_node_reassignment() {
+        let server = TestServer::new();
+        server.insert_records("testing", records(1));
+
+        let client = server.client();
+        assert!(client.fetch_info_collections().is_ok());
+        let old_endpoint = server.api_endpoint();
+        server.reassign_node();
+        assert_ne!(server.api_endpoint(), old_endpoint);
+        assert!(server.records("testing").is_empty());
+
+        // The client still has a token for the old node.
+        match client.fetch_info_collections().unwrap_err().kind() {
+            ErrorKind::StorageHttpError { code: 401, .. } => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use std::time::{Duration, SystemTime};
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::clients::{self, DeviceType, Settings};
+use sync15_adapter::tabs;
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::{HeaderMap, HeaderValue, Method, StatusCode};
+use sync15_adapter::{ErrorKind, GlobalState, InterruptHandle, SyncManager};
+use sync15_test_server::TestServer;
+
+use common::{setup, MemoryStore, TestCommandProcessor};
+
+#[test]
+fn test_backoff_on_503() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let passwords = MemoryStore::new("passwords");
+    let tabs = MemoryStore::new("tabs");
+    SyncManager::new(vec![&passwords, &tabs])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert!(state.backoff_until.is_none());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Retry-After", HeaderValue::from_static("300"));
+    server.fail_next_with_headers(Method::GET,
+                                  "/storage/passwords?full=1&limit=1000&newer=0&sort=oldest",
+                                  StatusCode::SERVICE_UNAVAILABLE, headers);
+    let before = SystemTime::now();
+    let num_requests = server.requests().len();
+    let result = SyncManager::new(vec![&passwords, &tabs])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new())
+        .unwrap();
+    for name in &["passwords", "tabs"] {
+        match result.engine_results[*name].as_ref().unwrap_err().kind() {
+            ErrorKind::BackoffError(_) => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+    // We shouldn't have tried to sync tabs after the 503.
+    assert!(server.requests()[num_requests..]
+        .iter()
+        .all(|(_, url)| !url.path().ends_with("/storage/tabs")));
+    let until = state.backoff_until.expect("Should persist backoff");
+    assert!(until >= before + Duration::from_secs(300));
+
+    // Even with a new client, we should refuse to sync until the backoff
+    // period is over.
+    let num_requests = server.requests().len();
+    let mut state = GlobalState::from_persisted_string(&state.to_persistable_string()).unwrap();
+    let result = SyncManager::new(vec![&passwords, &tabs])
+        .sync(&server.client(), &mut state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new());
+    match result {
+        Err(e) => match e.kind() {
+            ErrorKind::BackoffError(when) => assert_eq!(*when, until),
+            other => panic!("Unexpected error {:?}", other),
+        },
+        Ok(_) => panic!("Should refuse to sync"),
+    }
+    assert_eq!(server.requests().len(), num_requests);
+}
+
+#[test]
+fn test_backoff_skips_builtin_engines() {
+    let (server, root_key, mut state) = setup();
+    let processor = TestCommandProcessor::new(Settings {
+        client_id: "ourclient123".into(),
+        fxa_device_id: None,
+        name: "Our Desktop".into(),
+        device_type: DeviceType::Desktop,
+    });
+    let clients_engine = clients::Engine::new(&processor);
+    let tabs_engine = tabs::Engine::new(&clients_engine);
+    tabs_engine.set_local_tabs(Vec::new());
+    let passwords = MemoryStore::new("passwords");
+
+    // The server is overloaded by the time we sync the clients engine, so
+    // we shouldn't sync tabs or passwords after it.
+    let mut headers = HeaderMap::new();
+    headers.insert("Retry-After", HeaderValue::from_static("300"));
+    server.fail_next_with_headers(Method::GET,
+                                  "/storage/clients?full=1&newer=0",
+                                  StatusCode::SERVICE_UNAVAILABLE, headers);
+    let num_requests = server.requests().len();
+    let result = SyncManager::new(vec![&passwords])
+        .clients_engine(&clients_engine)
+        .tabs_engine(&tabs_engine)
+        .sync(&server.client(), &mut state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new())
+        .unwrap();
+    for name in &["clients", "tabs", "passwords"] {
+        match result.engine_results[*name].as_ref().unwrap_err().kind() {
+            ErrorKind::BackoffError(_) => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+    assert!(server.requests()[num_requests..]
+        .iter()
+        .all(|(_, url)| !url.path().ends_with("/storage/tabs")
+                        && !url.path().ends_with("/storage/passwords")));
+}
+
+#[test]
+fn test_x_weave_backoff() {
+    let server = TestServer::new();
+    let client = server.client();
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Weave-Backoff", HeaderValue::from_static("1800"));
+    server.fail_next_with_headers(Method::GET, "/info/collections",
+                                  StatusCode::INTERNAL_SERVER_ERROR, headers);
+    // Unlike a 503, `X-Weave-Backoff` doesn't change the error.
+    match client.fetch_info_collections().unwrap_err().kind() {
+        ErrorKind::StorageHttpError { code: 500, .. } => {}
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert!(client.backoff_until().unwrap() > SystemTime::now() + Duration::from_secs(1700));
+}
@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use serde_json::Value as JsonValue;
+
+use sync15_adapter::clients::{self, Command, DeviceType, Settings};
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::Method;
+use sync15_adapter::{InterruptHandle, SyncManager};
+
+use common::{encrypt_json, requests_to, setup, TestCommandProcessor};
+
+#[test]
+fn test_clients_engine() {
+    let (server, root_key, mut state) = setup();
+    let key = state.key_for_collection("clients").unwrap().clone();
+
+    // Another client uploads its record, and sends us some commands.
+    server.insert_records("clients", vec![
+        encrypt_json(&key, "clients", json!({
+            "id": "otherclient1",
+            "name": "Other Phone",
+            "type": "mobile",
+            "commands": [],
+            "fxaDeviceId": "other-fxa-device",
+        })),
+        encrypt_json(&key, "clients", json!({
+            "id": "ourclient123",
+            "name": "Old Name",
+            "type": "desktop",
+            "commands": [
+                { "command": "wipeEngine", "args": ["bookmarks"] },
+                { "command": "frobnicate", "args": [] },
+                {
+                    "command": "displayURI",
+                    "args": ["https://example.com", "otherclient1", "Example"],
+                    "flowID": "flow",
+                },
+            ],
+            "os": "Linux",
+        })),
+    ]);
+
+    let processor = TestCommandProcessor::new(Settings {
+        client_id: "ourclient123".into(),
+        fxa_device_id: Some("our-fxa-device".into()),
+        name: "Our Desktop".into(),
+        device_type: DeviceType::Desktop,
+    });
+    let engine = clients::Engine::new(&processor);
+    let client = server.client();
+    let mut telem = SyncTelemetry::new();
+    let result = SyncManager::<sync15_adapter::Error>::new(vec![])
+        .clients_engine(&engine)
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    result.engine_results["clients"].as_ref().unwrap();
+    assert_eq!(*processor.commands.borrow(), vec![
+        Command::Wipe("bookmarks".into()),
+        Command::DisplayUri {
+            uri: "https://example.com".into(),
+            sender_id: Some("otherclient1".into()),
+            title: Some("Example".into()),
+        },
+    ]);
+    assert_eq!(telem.get_engines()[0].name(), "clients");
+
+    let recent_clients = engine.recent_clients();
+    assert_eq!(recent_clients.len(), 1);
+    let other = &recent_clients["otherclient1"];
+    assert_eq!(other.name, "Other Phone");
+    assert_eq!(other.device_type, Some(DeviceType::Mobile));
+    assert_eq!(other.fxa_device_id, Some("other-fxa-device".into()));
+
+    // Our record should be updated, without the commands, and with a TTL.
+    let uploaded = server.records("clients")
+        .into_iter()
+        .find(|bso| bso.id == "ourclient123")
+        .unwrap();
+    assert_eq!(uploaded.ttl, Some(21 * 24 * 60 * 60));
+    assert_eq!(JsonValue::from(uploaded.decrypt(&key).unwrap().payload), json!({
+        "id": "ourclient123",
+        "name": "Our Desktop",
+        "type": "desktop",
+        "commands": [],
+        "fxaDeviceId": "our-fxa-device",
+        "protocols": ["1.5"],
+        "os": "Linux",
+    }));
+    let client_uploads = || requests_to(&server, Method::POST, "/storage/clients").len();
+    assert_eq!(client_uploads(), 1);
+
+    // If nothing changed, we shouldn't upload our record again...
+    SyncManager::<sync15_adapter::Error>::new(vec![])
+        .clients_engine(&engine)
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert_eq!(processor.commands.borrow().len(), 2);
+    assert_eq!(client_uploads(), 1);
+
+    // ...until it's about to expire.
+    server.advance_time(8.0 * 24.0 * 60.0 * 60.0);
+    SyncManager::<sync15_adapter::Error>::new(vec![])
+        .clients_engine(&engine)
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert_eq!(client_uploads(), 2);
+}
@@ -0,0 +1,355 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Stores and helpers shared by the integration tests, which sync against
+//! the in-memory server from `sync15-test-server`. Each test binary only
+//! uses some of them.
+
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::Value as JsonValue;
+
+use sync15_adapter::bso_record::EncryptedBso;
+use sync15_adapter::clients::{Command, CommandProcessor, CommandStatus, Settings};
+use sync15_adapter::dry_run::{DryRunChanges, LocalChange};
+use sync15_adapter::request::InfoConfiguration;
+use sync15_adapter::telemetry::{self, SyncTelemetry};
+use sync15_adapter::transport::{Method, Url};
+use sync15_adapter::{self, GlobalState, IncomingChangeset, IncomingKind, IncomingRecord,
+                     InterruptHandle, KeyBundle, OutgoingChangeset, OutgoingRecord, Payload,
+                     RetrySet, ServerTimestamp, Store, SyncManager, SyncRecord, TypedStore,
+                     UploadPolicy};
+use sync15_test_server::TestServer;
+
+/// Creates a server, a root sync key, and the state of a client that has
+/// already synced once, so that `meta/global` and `crypto/keys` exist.
+pub fn setup() -> (TestServer, KeyBundle, GlobalState) {
+    setup_with_config(InfoConfiguration::default())
+}
+
+/// Like `setup`, but the server uses (and enforces) `config`.
+pub fn setup_with_config(config: InfoConfiguration) -> (TestServer, KeyBundle, GlobalState) {
+    let server = TestServer::with_config(config);
+    let root_key = KeyBundle::new_random().unwrap();
+    let mut state = GlobalState::default();
+    sync(&server, &mut state, &root_key, &MemoryStore::new("testing"));
+    (server, root_key, state)
+}
+
+/// Syncs `store` with a new storage client, and panics if the sync or the
+/// store fails. Pass a fresh `GlobalState` to sync as another client.
+pub fn sync(server: &TestServer, state: &mut GlobalState, root_key: &KeyBundle,
+            store: &MemoryStore) {
+    let result = SyncManager::new(vec![store])
+        .sync(&server.client(), state, root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    for (_, engine_result) in result.engine_results {
+        engine_result.unwrap();
+    }
+}
+
+/// Encrypts a cleartext record, as another client would before uploading it.
+pub fn encrypt_json(key: &KeyBundle, collection: &str, value: JsonValue) -> EncryptedBso {
+    let payload = Payload::from_json(value).unwrap();
+    payload.into_bso(collection.into()).encrypt(key).unwrap()
+}
+
+/// `count` records for the `testing` collection, with IDs that start with
+/// `prefix`.
+pub fn encrypted_records(key: &KeyBundle, prefix: &str, count: usize) -> Vec<EncryptedBso> {
+    (0..count).map(|i| {
+        let id = format!("{}{:08}", prefix, i);
+        encrypt_json(key, "testing", json!({ "id": id, "value": "remote" }))
+    }).collect()
+}
+
+/// The IDs of the live records in `collection`, sorted.
+pub fn uploaded_ids(server: &TestServer, collection: &str) -> Vec<String> {
+    let mut ids = server.records(collection)
+                        .into_iter()
+                        .map(|bso| bso.id)
+                        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+/// The URLs of every `method` request the server has received for a path
+/// ending with `path_suffix`, in order.
+pub fn requests_to(server: &TestServer, method: Method, path_suffix: &str) -> Vec<Url> {
+    server.requests()
+          .into_iter()
+          .filter(|(m, url)| *m == method && url.path().ends_with(path_suffix))
+          .map(|(_, url)| url)
+          .collect()
+}
+
+pub fn query_params(url: &Url) -> HashMap<String, String> {
+    url.query_pairs().map(|(k, v)| (k.into_owned(), v.into_owned())).collect()
+}
+
+/// A store that keeps records in memory, and uploads everything that
+/// changed locally since the last sync. If it has a retry set, it
+/// forgets about records that failed to upload, and leaves retrying them
+/// to the retry set.
+#[derive(Default)]
+pub struct MemoryStore {
+    pub name: &'static str,
+    pub records: RefCell<BTreeMap<String, Payload>>,
+    pub changed: RefCell<Vec<String>>,
+    pub last_sync: RefCell<ServerTimestamp>,
+    pub high_water_marks: RefCell<Vec<ServerTimestamp>>,
+    pub resets: RefCell<usize>,
+    pub allow_failures: bool,
+    pub failed: RefCell<HashMap<String, String>>,
+    pub retry_set: RefCell<Option<RetrySet>>,
+    pub interrupt_after_apply: RefCell<bool>,
+}
+
+impl MemoryStore {
+    pub fn new(name: &'static str) -> MemoryStore {
+        MemoryStore { name, ..MemoryStore::default() }
+    }
+
+    pub fn insert(&self, id: &str, value: &str) {
+        let payload = Payload::from_json(json!({ "id": id, "value": value })).unwrap();
+        self.records.borrow_mut().insert(id.into(), payload);
+        self.changed.borrow_mut().push(id.into());
+    }
+
+    pub fn value(&self, id: &str) -> Option<String> {
+        self.records.borrow().get(id).map(|p| p.data["value"].as_str().unwrap().into())
+    }
+}
+
+impl Store for MemoryStore {
+    type Error = sync15_adapter::Error;
+
+    fn collection_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn get_last_sync(&self) -> sync15_adapter::Result<Option<ServerTimestamp>> {
+        Ok(Some(*self.last_sync.borrow()))
+    }
+
+    fn reset(&self) -> sync15_adapter::Result<()> {
+        *self.resets.borrow_mut() += 1;
+        *self.last_sync.borrow_mut() = ServerTimestamp::default();
+        *self.changed.borrow_mut() = self.records.borrow().keys().cloned().collect();
+        Ok(())
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        interrupt: &InterruptHandle,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> sync15_adapter::Result<OutgoingChangeset> {
+        let mut records = self.records.borrow_mut();
+        incoming_telemetry.applied(inbound.changes.len() as u32);
+        for (payload, _) in inbound.changes {
+            // Remote wins.
+            records.insert(payload.id.clone(), payload);
+        }
+        let mut outgoing = OutgoingChangeset::new(inbound.collection, inbound.timestamp);
+        for id in self.changed.borrow().iter() {
+            outgoing.changes.push(records[id].clone());
+        }
+        if self.interrupt_after_apply.replace(false) {
+            interrupt.interrupt();
+        }
+        Ok(outgoing)
+    }
+
+    fn dry_run_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> sync15_adapter::Result<Option<DryRunChanges>> {
+        let records = self.records.borrow();
+        incoming_telemetry.applied(inbound.changes.len() as u32);
+        let mut local = Vec::new();
+        for (payload, _) in &inbound.changes {
+            let old = records.get(&payload.id).filter(|old| !old.is_tombstone());
+            let new = Some(payload).filter(|new| !new.is_tombstone());
+            local.extend(LocalChange::between(payload.id.clone(), old.map(|old| &old.data),
+                                              new.map(|new| &new.data)));
+        }
+        let mut outgoing = OutgoingChangeset::new(inbound.collection, inbound.timestamp);
+        for id in self.changed.borrow().iter() {
+            let remote = inbound.changes.iter().find(|(payload, _)| payload.id == *id);
+            outgoing.changes.push(remote.map_or(&records[id], |(payload, _)| payload).clone());
+        }
+        Ok(Some(DryRunChanges { local, outgoing }))
+    }
+
+    fn set_high_water_mark(
+        &self,
+        high_water_mark: ServerTimestamp,
+    ) -> sync15_adapter::Result<()> {
+        self.high_water_marks.borrow_mut().push(high_water_mark);
+        *self.last_sync.borrow_mut() = high_water_mark;
+        Ok(())
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+        records_failed: &HashMap<String, String>,
+    ) -> sync15_adapter::Result<()> {
+        if self.retry_set.borrow().is_some() {
+            self.changed.borrow_mut().clear();
+        } else {
+            self.changed.borrow_mut().retain(|id| !records_synced.contains(id));
+        }
+        *self.failed.borrow_mut() = records_failed.clone();
+        *self.last_sync.borrow_mut() = new_timestamp;
+        Ok(())
+    }
+
+    fn upload_policy(&self) -> UploadPolicy {
+        if self.allow_failures {
+            UploadPolicy::AllowFailures
+        } else {
+            UploadPolicy::Atomic
+        }
+    }
+
+    fn get_retry_set(&self) -> sync15_adapter::Result<Option<RetrySet>> {
+        Ok(self.retry_set.borrow().clone())
+    }
+
+    fn set_retry_set(&self, retry_set: RetrySet) -> sync15_adapter::Result<()> {
+        *self.retry_set.borrow_mut() = Some(retry_set);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+    pub id: String,
+    pub value: String,
+}
+
+impl SyncRecord for Note {
+    const COLLECTION: &'static str = "testing";
+    const STORAGE_VERSION: usize = 1;
+
+    fn record_id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A typed store that appends " (seen)" to every incoming note, and
+/// uploads it again. Notes that are too large to upload are cut in half
+/// until they fit, and notes that still don't fit are reported as failed.
+#[derive(Default)]
+pub struct NoteStore {
+    pub notes: RefCell<BTreeMap<String, String>>,
+    pub malformed: RefCell<Vec<String>>,
+    pub last_sync: RefCell<ServerTimestamp>,
+}
+
+impl TypedStore for NoteStore {
+    type Record = Note;
+    type Error = sync15_adapter::Error;
+
+    fn get_last_sync(&self) -> sync15_adapter::Result<Option<ServerTimestamp>> {
+        Ok(Some(*self.last_sync.borrow()))
+    }
+
+    fn reset(&self) -> sync15_adapter::Result<()> {
+        *self.last_sync.borrow_mut() = ServerTimestamp::default();
+        Ok(())
+    }
+
+    fn apply_incoming_records(
+        &self,
+        inbound: Vec<IncomingRecord<Note>>,
+        _interrupt: &InterruptHandle,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> sync15_adapter::Result<Vec<OutgoingRecord<Note>>> {
+        let mut outgoing = Vec::new();
+        for incoming in inbound {
+            match incoming.kind {
+                IncomingKind::Record { mut record, unknown_fields } => {
+                    record.value.push_str(" (seen)");
+                    self.notes.borrow_mut().insert(record.id.clone(), record.value.clone());
+                    incoming_telemetry.applied(1);
+                    outgoing.push(OutgoingRecord::Record { record, unknown_fields });
+                }
+                IncomingKind::Tombstone => {
+                    self.notes.borrow_mut().remove(&incoming.id);
+                    incoming_telemetry.applied(1);
+                }
+                IncomingKind::Malformed { .. } => {
+                    self.malformed.borrow_mut().push(incoming.id);
+                }
+            }
+        }
+        Ok(outgoing)
+    }
+
+    fn set_high_water_mark(
+        &self,
+        high_water_mark: ServerTimestamp,
+    ) -> sync15_adapter::Result<()> {
+        *self.last_sync.borrow_mut() = high_water_mark;
+        Ok(())
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        _records_synced: &[String],
+        _records_failed: &HashMap<String, String>,
+    ) -> sync15_adapter::Result<()> {
+        *self.last_sync.borrow_mut() = new_timestamp;
+        Ok(())
+    }
+
+    fn upload_policy(&self) -> UploadPolicy {
+        UploadPolicy::AllowFailures
+    }
+
+    fn shrink_record(&self, mut record: Note, _max_payload_bytes: usize) -> Option<Note> {
+        if record.value.is_empty() {
+            return None;
+        }
+        let len = record.value.len() / 2;
+        record.value.truncate(len);
+        Some(record)
+    }
+}
+
+/// Remembers the commands it's asked to apply.
+pub struct TestCommandProcessor {
+    pub settings: Settings,
+    pub commands: RefCell<Vec<Command>>,
+}
+
+impl TestCommandProcessor {
+    pub fn new(settings: Settings) -> TestCommandProcessor {
+        TestCommandProcessor { settings, commands: RefCell::new(Vec::new()) }
+    }
+}
+
+impl CommandProcessor for TestCommandProcessor {
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn apply_incoming_command(&self, command: Command) -> CommandStatus {
+        let status = match command {
+            Command::DisplayUri { .. } => CommandStatus::Unsupported,
+            _ => CommandStatus::Applied,
+        };
+        self.commands.borrow_mut().push(command);
+        status
+    }
+}
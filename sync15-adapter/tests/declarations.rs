@@ -0,0 +1,92 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use std::collections::{HashMap, HashSet};
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::{Method, StatusCode};
+use sync15_adapter::{EngineDeclarations, GlobalState, InterruptHandle, KeyBundle, SyncManager};
+use sync15_test_server::TestServer;
+
+use common::MemoryStore;
+
+#[test]
+fn test_declare_engines() {
+    // Desktop starts fresh, so this test doesn't use `setup`.
+    let server = TestServer::new();
+    let root_key = KeyBundle::new_random().unwrap();
+
+    // Desktop syncs first, and declines history.
+    let desktop = server.client();
+    let passwords = MemoryStore::new("passwords");
+    let mut desktop_state = GlobalState::default();
+    SyncManager::new(vec![&passwords])
+        .declare_engines(EngineDeclarations {
+            supported: HashMap::new(),
+            declined: vec!["history".to_string()].into_iter().collect(),
+            undeclined: HashSet::new(),
+        })
+        .sync(&desktop, &mut desktop_state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new())
+        .unwrap();
+    let global = desktop.fetch_meta_global().unwrap();
+    assert_eq!(global.payload.declined, vec!["history".to_string()]);
+    assert!(!global.payload.engines.contains_key("history"));
+
+    // Another client declines tabs, and adds an engine of its own. Its
+    // first attempt to upload `meta/global` loses a race.
+    let client = server.client();
+    let tabs = MemoryStore::new("tabs");
+    tabs.insert("aaaaaaaaaaaa", "tab");
+    let testing = MemoryStore::new("testing");
+    testing.insert("bbbbbbbbbbbb", "value");
+    server.fail_next(Method::PUT, "/storage/meta/global", StatusCode::PRECONDITION_FAILED);
+    let mut state = GlobalState::default();
+    let mut telem = SyncTelemetry::new();
+    let result = SyncManager::new(vec![&passwords, &tabs, &testing])
+        .declare_engines(EngineDeclarations {
+            supported: vec![("testing".to_string(), 1)].into_iter().collect(),
+            declined: vec!["tabs".to_string()].into_iter().collect(),
+            undeclined: HashSet::new(),
+        })
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    assert_eq!(result.declined, vec!["tabs".to_string()]);
+    result.engine_results["testing"].as_ref().unwrap();
+    assert!(server.records("tabs").is_empty());
+    assert_eq!(server.records("testing").len(), 1);
+    let sequence = telem.get_setup_sequence();
+    assert_eq!(sequence.iter().filter(|s| **s == "NeedsMetaGlobalUpdate").count(), 2);
+
+    // Desktop's choices should survive, and its sync ID shouldn't change.
+    let new_global = client.fetch_meta_global().unwrap();
+    assert_eq!(new_global.payload.sync_id, global.payload.sync_id);
+    assert_eq!(new_global.payload.declined, vec!["history".to_string(), "tabs".to_string()]);
+    assert!(new_global.payload.engines.contains_key("testing"));
+    assert!(!new_global.payload.engines.contains_key("tabs"));
+    assert_eq!(state.global.as_ref().unwrap().modified, new_global.modified);
+
+    // Syncing again shouldn't upload another `meta/global`.
+    let mut telem = SyncTelemetry::new();
+    SyncManager::new(vec![&passwords, &tabs, &testing])
+        .declare_engines(EngineDeclarations {
+            supported: vec![("testing".to_string(), 1)].into_iter().collect(),
+            declined: vec!["tabs".to_string()].into_iter().collect(),
+            undeclined: HashSet::new(),
+        })
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    assert!(!telem.get_setup_sequence().contains(&"NeedsMetaGlobalUpdate"));
+    assert_eq!(client.fetch_meta_global().unwrap().modified, new_global.modified);
+}
@@ -0,0 +1,70 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::transport::Method;
+use sync15_adapter::{ErrorKind, ServerTimestamp};
+
+use common::{encrypted_records, requests_to, setup, sync, uploaded_ids, MemoryStore};
+
+#[test]
+fn test_remote_deletion() {
+    let (server, root_key, mut state) = setup();
+    let store = MemoryStore::new("testing");
+    for i in 0..110 {
+        store.insert(&format!("record{:06}", i), "value");
+    }
+    sync(&server, &mut state, &root_key, &store);
+    let key = state.key_for_collection("bookmarks").unwrap();
+    server.insert_records("bookmarks", encrypted_records(key, "bookmark", 3));
+    let client = server.client();
+    client.fetch_info_collections().unwrap();
+
+    // Deleting a single record checks that record's modified time.
+    let stale = ServerTimestamp(server.now().0 - 100.0);
+    let err = client.delete_record("testing", "record000000", Some(stale)).unwrap_err();
+    match err.kind() {
+        ErrorKind::StorageHttpError { code: 412, .. } => {}
+        other => panic!("Unexpected error {:?}", other),
+    }
+    client.delete_record("testing", "record000000", None).unwrap();
+    // Deleting a record that's already gone isn't an error.
+    client.delete_record("testing", "record000000", None).unwrap();
+    assert_eq!(server.records("testing").len(), 109);
+
+    // The server only accepts 100 IDs per request.
+    let ids = (1..106).map(|i| format!("record{:06}", i)).collect::<Vec<_>>();
+    let modified = client.delete_records(
+        "testing",
+        &ids,
+        server.collection_modified("testing"),
+    ).unwrap();
+    assert_eq!(Some(modified), server.collection_modified("testing"));
+    let deletes = requests_to(&server, Method::DELETE, "/storage/testing")
+        .into_iter()
+        .filter(|url| url.query().is_some())
+        .count();
+    assert_eq!(deletes, 2);
+    assert_eq!(uploaded_ids(&server, "testing"), vec!["record000106", "record000107",
+                                                      "record000108", "record000109"]);
+
+    // Deleting a collection leaves the others alone.
+    let err = client.delete_collection("testing", Some(stale)).unwrap_err();
+    match err.kind() {
+        ErrorKind::StorageHttpError { code: 412, .. } => {}
+        other => panic!("Unexpected error {:?}", other),
+    }
+    client.delete_collection("testing", server.collection_modified("testing")).unwrap();
+    assert!(server.records("testing").is_empty());
+    assert_eq!(server.records("bookmarks").len(), 3);
+}
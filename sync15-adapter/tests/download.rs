@@ -0,0 +1,140 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::{Method, StatusCode};
+use sync15_adapter::{ErrorKind, GlobalState, InterruptHandle, ServerTimestamp, SyncManager};
+
+use common::{encrypt_json, encrypted_records, query_params, requests_to, setup, sync,
+             MemoryStore};
+
+#[test]
+fn test_paged_download() {
+    let (server, root_key, mut state) = setup();
+    let store = MemoryStore::new("testing");
+    store.insert("local0000000", "local");
+    sync(&server, &mut state, &root_key, &store);
+
+    // Two batches of records, so that the first page ends in the middle
+    // of the second batch.
+    let key = state.key_for_collection("testing").unwrap().clone();
+    let first = server.insert_records("testing", encrypted_records(&key, "first", 600));
+    let second = server.insert_records("testing", encrypted_records(&key, "second", 600));
+    store.high_water_marks.borrow_mut().clear();
+    store.insert("local0000001", "local");
+    let num_gets = requests_to(&server, Method::GET, "/storage/testing").len();
+    sync(&server, &mut state, &root_key, &store);
+
+    assert_eq!(store.records.borrow().len(), 1202);
+    // We can only resume from the end of the first batch after the first
+    // page, since the second page has more records from the second batch.
+    assert_eq!(*store.high_water_marks.borrow(), vec![first, second]);
+    let gets = requests_to(&server, Method::GET, "/storage/testing")[num_gets..]
+        .iter()
+        .map(query_params)
+        .collect::<Vec<_>>();
+    assert_eq!(gets.len(), 2);
+    assert_eq!(gets[0].get("sort").map(|s| s.as_str()), Some("oldest"));
+    assert_eq!(gets[0].get("limit").map(|s| s.as_str()), Some("1000"));
+    assert_eq!(gets[1].get("offset").map(|s| s.as_str()), Some("1000"));
+    assert_eq!(server.records("testing").len(), 1202);
+}
+
+#[test]
+fn test_resume_interrupted_download() {
+    let (server, root_key, state) = setup();
+    let key = state.key_for_collection("testing").unwrap();
+    let mut last = ServerTimestamp::default();
+    for record in encrypted_records(key, "remote", 1500) {
+        last = server.insert_record("testing", record);
+    }
+
+    // Fail the second page.
+    server.fail_next(Method::GET, "offset=1000", StatusCode::INTERNAL_SERVER_ERROR);
+    let client = server.client();
+    let store = MemoryStore::new("testing");
+    let mut state = GlobalState::default();
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert!(result.engine_results["testing"].is_err());
+    assert_eq!(store.records.borrow().len(), 1000);
+    let high_water_mark = *store.last_sync.borrow();
+    assert!(high_water_mark < last);
+
+    // The next sync should pick up where we left off.
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert!(result.engine_results["testing"].is_ok());
+    assert_eq!(store.records.borrow().len(), 1500);
+    let url = requests_to(&server, Method::GET, "/storage/testing").pop().unwrap();
+    assert_eq!(query_params(&url).get("newer").map(|s| s.as_str()),
+               Some(high_water_mark.to_string().as_str()));
+}
+
+#[test]
+fn test_restart_download_on_concurrent_modification() {
+    let (server, root_key, mut state) = setup();
+    let key = state.key_for_collection("testing").unwrap().clone();
+    let first = server.insert_records("testing", encrypted_records(&key, "first", 600));
+    server.insert_records("testing", encrypted_records(&key, "second", 600));
+
+    // Another client changes a record from the first page while we're
+    // downloading, moving it to the end, so that the second page would
+    // start one record later than it should.
+    let changed = encrypt_json(&key, "testing", json!({
+        "id": "first00000000",
+        "value": "changed",
+    }));
+    server.insert_records_before_next(Method::GET, "offset=1000", "testing", vec![changed]);
+    let num_gets = requests_to(&server, Method::GET, "/storage/testing").len();
+    let store = MemoryStore::new("testing");
+    sync(&server, &mut state, &root_key, &store);
+
+    assert_eq!(store.records.borrow().len(), 1200);
+    assert_eq!(store.value("first00000000"), Some("changed".into()));
+    assert_eq!(store.value("second00000400"), Some("remote".into()));
+    // We should start over from the end of the first batch, without an
+    // offset.
+    let gets = requests_to(&server, Method::GET, "/storage/testing")[num_gets..]
+        .iter()
+        .map(query_params)
+        .collect::<Vec<_>>();
+    assert_eq!(gets.len(), 3);
+    assert_eq!(gets[1].get("offset").map(|s| s.as_str()), Some("1000"));
+    assert_eq!(gets[2].get("offset"), None);
+    assert_eq!(gets[2].get("newer").map(|s| s.as_str()), Some(first.to_string().as_str()));
+}
+
+#[test]
+fn test_give_up_on_repeated_concurrent_modification() {
+    let (server, root_key, mut state) = setup();
+    let key = state.key_for_collection("testing").unwrap().clone();
+    server.insert_records("testing", encrypted_records(&key, "remote", 10));
+
+    for _ in 0..3 {
+        server.fail_next(Method::GET, "sort=oldest", StatusCode::PRECONDITION_FAILED);
+    }
+    let store = MemoryStore::new("testing");
+    let result = SyncManager::new(vec![&store])
+        .sync(&server.client(), &mut state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new())
+        .unwrap();
+    let err = result.engine_results["testing"].as_ref().unwrap_err();
+    match err.kind() {
+        ErrorKind::ConcurrentModification(collection) => assert_eq!(collection, "testing"),
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert!(store.records.borrow().is_empty());
+}
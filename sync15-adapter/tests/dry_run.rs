@@ -0,0 +1,95 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::dry_run::{self, FieldChange, LocalChange, LocalChangeKind};
+use sync15_adapter::transport::Method;
+use sync15_adapter::{ErrorKind, Payload};
+
+use common::{encrypt_json, setup, sync, MemoryStore, NoteStore};
+
+#[test]
+fn test_dry_run() {
+    let (server, root_key, mut state) = setup();
+    let store = MemoryStore::new("testing");
+    store.insert("aaaaaaaaaaaa", "first");
+    store.insert("bbbbbbbbbbbb", "second");
+    sync(&server, &mut state, &root_key, &store);
+
+    // Another client changes A, deletes B, and adds C...
+    let key = state.key_for_collection("testing").unwrap().clone();
+    server.insert_records("testing", vec![
+        encrypt_json(&key, "testing", json!({ "id": "aaaaaaaaaaaa", "value": "changed" })),
+        Payload::new_tombstone("bbbbbbbbbbbb".into())
+            .into_bso("testing".into())
+            .encrypt(&key)
+            .unwrap(),
+        encrypt_json(&key, "testing", json!({ "id": "cccccccccccc", "value": "third" })),
+    ]);
+    // ...While we add D, and delete E.
+    store.insert("dddddddddddd", "fourth");
+    store.records.borrow_mut().insert("eeeeeeeeeeee".into(),
+                                      Payload::new_tombstone("eeeeeeeeeeee".into()));
+    store.changed.borrow_mut().push("eeeeeeeeeeee".into());
+
+    let client = server.client();
+    let num_requests = server.requests().len();
+    let last_sync = *store.last_sync.borrow();
+    let report = dry_run::dry_run(&client, &mut state, &root_key, &store, last_sync).unwrap();
+    assert_eq!(report.incoming_ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+    assert_eq!(report.incoming_counts.get_applied(), 3);
+    assert_eq!(report.local_changes, vec![LocalChange {
+        id: "aaaaaaaaaaaa".into(),
+        kind: LocalChangeKind::Update,
+        fields: vec![FieldChange {
+            name: "value".into(),
+            old: Some(json!("first")),
+            new: Some(json!("changed")),
+        }],
+    }, LocalChange {
+        id: "bbbbbbbbbbbb".into(),
+        kind: LocalChangeKind::Delete,
+        fields: vec![FieldChange {
+            name: "value".into(),
+            old: Some(json!("second")),
+            new: None,
+        }],
+    }, LocalChange {
+        id: "cccccccccccc".into(),
+        kind: LocalChangeKind::Insert,
+        fields: vec![FieldChange {
+            name: "value".into(),
+            old: None,
+            new: Some(json!("third")),
+        }],
+    }]);
+    assert_eq!(report.outgoing_ids, vec!["dddddddddddd", "eeeeeeeeeeee"]);
+    assert_eq!(report.outgoing_tombstone_ids, vec!["eeeeeeeeeeee"]);
+    assert_eq!(serde_json::to_value(&report).unwrap()["local_changes"][1]["kind"], "delete");
+
+    // Nothing should have changed locally or on the server.
+    assert_eq!(store.value("aaaaaaaaaaaa"), Some("first".to_string()));
+    assert_eq!(store.value("cccccccccccc"), None);
+    assert_eq!(*store.last_sync.borrow(), last_sync);
+    assert_eq!(store.changed.borrow().len(), 2);
+    assert!(server.requests()[num_requests..].iter().all(|(method, _)| {
+        *method == Method::GET
+    }));
+
+    // Typed stores need to opt in to dry runs.
+    let err = dry_run::dry_run(&client, &mut state, &root_key, &NoteStore::default(),
+                               last_sync).unwrap_err();
+    match err.kind() {
+        ErrorKind::DryRunUnsupported(name) => assert_eq!(name, "testing"),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
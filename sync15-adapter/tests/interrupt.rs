@@ -0,0 +1,78 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::telemetry::{self, SyncTelemetry};
+use sync15_adapter::transport::Method;
+use sync15_adapter::{GlobalState, InterruptHandle, SyncManager};
+
+use common::{encrypted_records, setup, MemoryStore};
+
+#[test]
+fn test_interrupt_sync() {
+    let (server, root_key, state) = setup();
+    let key = state.key_for_collection("testing").unwrap();
+    for record in encrypted_records(key, "remote", 1500) {
+        server.insert_record("testing", record);
+    }
+    let store = MemoryStore::new("testing");
+    let other = MemoryStore::new("other");
+    store.insert("local0000000", "local");
+
+    // The app interrupts a new client while the store is applying the
+    // first page.
+    let client = server.client();
+    *store.interrupt_after_apply.borrow_mut() = true;
+    let num_requests = server.requests().len();
+    let mut state = GlobalState::default();
+    let mut telem = SyncTelemetry::new();
+    let interrupt = InterruptHandle::new();
+    let result = SyncManager::new(vec![&store, &other])
+        .sync(&client, &mut state, &root_key, &interrupt, &mut telem)
+        .unwrap();
+    for name in &["testing", "other"] {
+        assert!(result.engine_results[*name].as_ref().unwrap_err().is_interrupted());
+    }
+    assert_eq!(telem.get_engines()[0].get_failure(), Some(&telemetry::SyncFailure::Shutdown));
+    assert_eq!(store.records.borrow().len(), 1001);
+    assert_eq!(store.high_water_marks.borrow().len(), 1);
+    // We shouldn't have uploaded anything, or synced the other engine.
+    assert!(server.requests()[num_requests..].iter().all(|(method, url)| {
+        *method == Method::GET && !url.path().ends_with("/storage/other")
+    }));
+    assert!(state.keys.is_some());
+
+    assert!(interrupt.was_interrupted());
+
+    // The next sync, with the same client and a new handle, picks up where
+    // we left off.
+    let result = SyncManager::new(vec![&store, &other])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert!(result.engine_results.values().all(|r| r.is_ok()));
+    assert_eq!(store.records.borrow().len(), 1501);
+    assert_eq!(server.records("testing").len(), 1501);
+}
+
+#[test]
+fn test_interrupt_before_token() {
+    let (server, _, _) = setup();
+
+    // A new client needs a token before its first request, but shouldn't
+    // fetch one if it's already interrupted.
+    let client = server.client();
+    client.interrupt_handle().interrupt();
+    let num_requests = server.requests().len();
+    assert!(client.fetch_info_collections().unwrap_err().is_interrupted());
+    assert_eq!(server.requests().len(), num_requests);
+}
@@ -0,0 +1,213 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::collection_keys::CollectionKeys;
+use sync15_adapter::telemetry::{self, SyncTelemetry};
+use sync15_adapter::transport::{Method, StatusCode};
+use sync15_adapter::{ErrorKind, GlobalState, InterruptHandle, KeyBundle, SetupStateMachine,
+                     SyncManager, UploadPolicy};
+
+use common::{encrypt_json, encrypted_records, query_params, requests_to, setup, sync,
+             uploaded_ids, MemoryStore};
+
+#[test]
+fn test_recover_from_hmac_mismatch() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("testing");
+    sync(&server, &mut state, &root_key, &store);
+
+    // Another client changes the keys after we fetched them, and uploads
+    // records encrypted with the new keys...
+    let new_keys = CollectionKeys::new_random().unwrap();
+    client.put_crypto_keys(&new_keys.to_encrypted_bso(&root_key).unwrap(), None).unwrap();
+    let key = new_keys.key_for_collection("testing");
+    server.insert_records("testing", encrypted_records(key, "good", 2));
+    // ...And a record that we can't decrypt with either key.
+    let unknown_key = KeyBundle::new_random().unwrap();
+    server.insert_records("testing", encrypted_records(&unknown_key, "bad", 1));
+
+    let last_sync = *store.last_sync.borrow();
+    let mut telem_engine = telemetry::Engine::new("testing");
+    let info = sync15_adapter::synchronize(&client, &mut state, &root_key, &store,
+                                           last_sync, UploadPolicy::Atomic,
+                                           &InterruptHandle::new(), &mut telem_engine)
+                                           .unwrap();
+    assert_eq!(info.bad_incoming_ids, vec!["bad00000000".to_string()]);
+    assert_eq!(telem_engine.get_incoming().get_applied(), 2);
+    assert_eq!(telem_engine.get_incoming().get_failed(), 1);
+    assert_eq!(store.records.borrow().len(), 2);
+    assert_eq!(state.keys.as_ref().unwrap().default, new_keys.default);
+}
+
+#[test]
+fn test_reset_when_keys_change_while_downloading() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("passwords");
+    sync(&server, &mut state, &root_key, &store);
+    let last_sync = *store.last_sync.borrow();
+    let resets = *store.resets.borrow();
+
+    // Another client changes the keys, and uploads a record encrypted with
+    // them, and a record that we can't decrypt with either key.
+    let new_keys = CollectionKeys::new_random().unwrap();
+    client.put_crypto_keys(&new_keys.to_encrypted_bso(&root_key).unwrap(), None).unwrap();
+    let unknown_key = KeyBundle::new_random().unwrap();
+    server.insert_records("passwords", vec![
+        encrypt_json(new_keys.key_for_collection("passwords"), "passwords",
+                     json!({ "id": "aaaaaaaaaaaa", "value": "good" })),
+        encrypt_json(&unknown_key, "passwords", json!({ "id": "bbbbbbbbbbbb", "value": "bad" })),
+    ]);
+
+    // We should reset as soon as we see the new keys, and download
+    // everything again, including the bad record.
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    let info = result.engine_results["passwords"].as_ref().unwrap();
+    assert_eq!(*store.resets.borrow(), resets + 1);
+    assert_eq!(info.bad_incoming_ids, vec!["bbbbbbbbbbbb".to_string()]);
+    assert_eq!(store.value("aaaaaaaaaaaa"), Some("good".to_string()));
+    let newer = requests_to(&server, Method::GET, "/storage/passwords")
+        .iter()
+        .map(|url| query_params(url)["newer"].parse::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(&newer[newer.len() - 2..], &[last_sync.0, 0.0]);
+
+    // We shouldn't reset again on the next sync.
+    sync(&server, &mut state, &root_key, &store);
+    assert_eq!(*store.resets.borrow(), resets + 1);
+}
+
+#[test]
+fn test_rotate_keys() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("passwords");
+    store.insert("aaaaaaaaaaaa", "first");
+    store.insert("bbbbbbbbbbbb", "second");
+    sync(&server, &mut state, &root_key, &store);
+    let old_keys = state.keys.clone().unwrap();
+    let old_global = state.global.clone().unwrap();
+
+    // Another client changed the keys since we fetched them, so we
+    // shouldn't change anything.
+    let other_keys = CollectionKeys::new_random().unwrap();
+    client.put_crypto_keys(&other_keys.to_encrypted_bso(&root_key).unwrap(), None).unwrap();
+    let err = state.rotate_keys(&client, &root_key, &["passwords"]).unwrap_err();
+    match err.kind() {
+        ErrorKind::ConcurrentModification(collection) => assert_eq!(collection, "crypto"),
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert_eq!(uploaded_ids(&server, "passwords").len(), 2);
+    assert_eq!(state.keys.as_ref(), Some(&old_keys));
+
+    state.refresh_keys(&client, &root_key).unwrap();
+    state.rotate_keys(&client, &root_key, &["passwords"]).unwrap();
+    assert!(uploaded_ids(&server, "passwords").is_empty());
+    let new_keys = CollectionKeys::from_encrypted_bso(client.fetch_crypto_keys().unwrap(),
+                                                      &root_key).unwrap();
+    assert!(state.keys.is_none() && state.global.is_none());
+    assert_ne!(new_keys.default, other_keys.default);
+    assert_ne!(new_keys.key_for_collection("passwords"), &new_keys.default);
+    let new_global = client.fetch_meta_global().unwrap();
+    for (name, engine) in &old_global.payload.engines {
+        assert_ne!(new_global.payload.engines[name].sync_id, engine.sync_id);
+    }
+
+    // We fetch the new keys, and reset and reupload our records...
+    let resets = *store.resets.borrow();
+    SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert_eq!(*store.resets.borrow(), resets + 1);
+    assert_eq!(state.keys.as_ref(), Some(&new_keys));
+    assert_eq!(uploaded_ids(&server, "passwords"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+
+    // ...Which other clients can decrypt.
+    let other_store = MemoryStore::new("passwords");
+    sync(&server, &mut GlobalState::default(), &root_key, &other_store);
+    assert_eq!(other_store.value("bbbbbbbbbbbb"), Some("second".to_string()));
+}
+
+#[test]
+fn test_rotate_keys_meta_global_conflict() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("passwords");
+    store.insert("aaaaaaaaaaaa", "first");
+    sync(&server, &mut state, &root_key, &store);
+    let old_global = state.global.clone().unwrap();
+
+    // Another client changes `meta/global` after we upload the new keys. We
+    // shouldn't wipe anything, but should forget our stale keys.
+    server.fail_next(Method::PUT, "/storage/meta/global", StatusCode::PRECONDITION_FAILED);
+    let err = state.rotate_keys(&client, &root_key, &[]).unwrap_err();
+    match err.kind() {
+        ErrorKind::ConcurrentModification(collection) => assert_eq!(collection, "meta"),
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert!(state.keys.is_none() && state.global.is_none());
+    assert_eq!(uploaded_ids(&server, "passwords"), vec!["aaaaaaaaaaaa"]);
+    assert_eq!(client.fetch_meta_global().unwrap().payload, old_global.payload);
+
+    // Advancing the state machine fetches the new keys, and then we can
+    // finish rotating.
+    state = SetupStateMachine::for_full_sync(&client, &root_key).to_ready(state).unwrap();
+    state.rotate_keys(&client, &root_key, &[]).unwrap();
+    assert!(uploaded_ids(&server, "passwords").is_empty());
+    let new_global = client.fetch_meta_global().unwrap();
+    for (name, engine) in &old_global.payload.engines {
+        assert_ne!(new_global.payload.engines[name].sync_id, engine.sync_id);
+    }
+    sync(&server, &mut state, &root_key, &store);
+    assert_eq!(uploaded_ids(&server, "passwords"), vec!["aaaaaaaaaaaa"]);
+}
+
+#[test]
+fn test_rotate_keys_finishes_wipe_on_next_sync() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("passwords");
+    store.insert("aaaaaaaaaaaa", "first");
+    store.insert("bbbbbbbbbbbb", "second");
+    sync(&server, &mut state, &root_key, &store);
+
+    // We publish the new keys and sync IDs, but fail to wipe the old records.
+    server.fail_next(Method::DELETE, "/storage/passwords", StatusCode::INTERNAL_SERVER_ERROR);
+    state.rotate_keys(&client, &root_key, &[]).unwrap_err();
+    assert!(state.pending_wipe.is_some());
+    assert_eq!(uploaded_ids(&server, "passwords").len(), 2);
+
+    // Before our next sync, another client uploads records with the new keys.
+    let other_store = MemoryStore::new("forms");
+    other_store.insert("cccccccccccc", "third");
+    sync(&server, &mut GlobalState::default(), &root_key, &other_store);
+    assert_eq!(uploaded_ids(&server, "forms"), vec!["cccccccccccc"]);
+
+    // Our next sync finishes the wipe, but leaves the other client's records
+    // alone, since they're newer than the sync IDs.
+    SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert!(state.pending_wipe.is_none());
+    assert!(requests_to(&server, Method::DELETE, "/storage/forms").is_empty());
+    assert_eq!(uploaded_ids(&server, "forms"), vec!["cccccccccccc"]);
+    assert_eq!(uploaded_ids(&server, "passwords"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+
+    let new_store = MemoryStore::new("passwords");
+    sync(&server, &mut GlobalState::default(), &root_key, &new_store);
+    assert_eq!(new_store.value("bbbbbbbbbbbb"), Some("second".to_string()));
+}
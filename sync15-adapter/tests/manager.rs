@@ -0,0 +1,129 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::telemetry::{self, SyncTelemetry};
+use sync15_adapter::transport::{Method, StatusCode};
+use sync15_adapter::{GlobalState, InterruptHandle, SyncManager};
+
+use common::{setup, sync, MemoryStore};
+
+#[test]
+fn test_sync_between_clients() {
+    let (server, root_key, mut state) = setup();
+    let first = MemoryStore::new("testing");
+    first.insert("aaaaaaaaaaaa", "first");
+    first.insert("bbbbbbbbbbbb", "second");
+    sync(&server, &mut state, &root_key, &first);
+
+    let records = server.records("testing");
+    assert_eq!(records.len(), 2);
+    // The records should be encrypted with the keys in `crypto/keys`.
+    assert!(records[0].clone().decrypt(state.key_for_collection("testing").unwrap()).is_ok());
+
+    let second = MemoryStore::new("testing");
+    second.insert("cccccccccccc", "third");
+    sync(&server, &mut GlobalState::default(), &root_key, &second);
+    assert_eq!(second.value("aaaaaaaaaaaa"), Some("first".to_string()));
+    assert_eq!(second.value("bbbbbbbbbbbb"), Some("second".to_string()));
+    assert_eq!(*second.last_sync.borrow(), server.collection_modified("testing").unwrap());
+
+    sync(&server, &mut state, &root_key, &first);
+    assert_eq!(first.value("cccccccccccc"), Some("third".to_string()));
+}
+
+#[test]
+fn test_sync_manager() {
+    let (server, root_key, _) = setup();
+    let client = server.client();
+
+    let passwords = MemoryStore::new("passwords");
+    passwords.insert("aaaaaaaaaaaa", "password");
+    let tabs = MemoryStore::new("tabs");
+    tabs.insert("bbbbbbbbbbbb", "tab");
+
+    let mut state = GlobalState::default();
+    let result = SyncManager::new(vec![&passwords, &tabs])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert!(result.declined.is_empty());
+    assert_eq!(result.engine_results.len(), 2);
+    assert!(result.engine_results.values().all(|r| r.is_ok()));
+    // A new client resets every engine.
+    assert_eq!(*passwords.resets.borrow(), 1);
+    assert_eq!(*tabs.resets.borrow(), 1);
+    assert_eq!(server.records("passwords").len(), 1);
+    assert_eq!(server.records("tabs").len(), 1);
+
+    // Syncing again with the same state shouldn't reset anything.
+    SyncManager::new(vec![&passwords, &tabs])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert_eq!(*passwords.resets.borrow(), 1);
+    assert_eq!(*tabs.resets.borrow(), 1);
+
+    // Decline tabs, as if from another device.
+    let mut global = client.fetch_meta_global().unwrap();
+    global.payload.declined.push("tabs".into());
+    client.put_meta_global(&global, None).unwrap();
+
+    tabs.insert("cccccccccccc", "declined tab");
+    let result = SyncManager::new(vec![&passwords, &tabs])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    assert_eq!(result.declined, vec!["tabs".to_string()]);
+    assert_eq!(result.engine_results.keys().collect::<Vec<_>>(), vec!["passwords"]);
+    assert_eq!(server.records("tabs").len(), 1);
+}
+
+#[test]
+fn test_telemetry() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let passwords = MemoryStore::new("passwords");
+    passwords.insert("aaaaaaaaaaaa", "password");
+    passwords.insert("bbbbbbbbbbbb", "password");
+    let tabs = MemoryStore::new("tabs");
+
+    let mut telem = SyncTelemetry::new();
+    SyncManager::new(vec![&passwords, &tabs])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    assert_eq!(telem.get_setup_sequence().last(), Some(&"Ready"));
+    assert!(telem.get_setup_sequence().contains(&"InitialWithLiveToken"));
+    assert!(telem.get_failure().is_none());
+    let engines = telem.get_engines();
+    assert_eq!(engines.len(), 2);
+    assert_eq!(engines[0].name(), "passwords");
+    assert_eq!(engines[0].get_outgoing(), &[telemetry::EngineOutgoing::new(2, 0)]);
+    assert!(engines[1].get_outgoing().is_empty());
+
+    let other = MemoryStore::new("passwords");
+    let other_tabs = MemoryStore::new("tabs");
+    let tabs_path = "/storage/tabs?full=1&limit=1000&newer=0&sort=oldest";
+    server.fail_next(Method::GET, tabs_path, StatusCode::INTERNAL_SERVER_ERROR);
+    let mut telem = SyncTelemetry::new();
+    SyncManager::new(vec![&other, &other_tabs])
+        .sync(&client, &mut GlobalState::default(), &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    let engines = telem.get_engines();
+    assert_eq!(engines[0].get_incoming().get_applied(), 2);
+    assert!(engines[0].get_failure().is_none());
+    assert_eq!(engines[1].get_failure(), Some(&telemetry::SyncFailure::Http { code: 500 }));
+
+    let mut ping = telemetry::SyncTelemetryPing::new(client.hashed_uid().unwrap());
+    ping.sync(telem);
+    let json = serde_json::to_value(&ping).unwrap();
+    assert_eq!(json["uid"], "test-hashed-fxa-uid");
+    assert_eq!(json["syncs"][0]["engines"][1]["failureReason"]["name"], "httperror");
+}
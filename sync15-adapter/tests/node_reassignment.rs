@@ -0,0 +1,79 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::{Method, StatusCode};
+use sync15_adapter::{InterruptHandle, SyncManager};
+use sync15_test_server::{TestServer, TOKENSERVER_HOST};
+
+use common::{setup, MemoryStore};
+
+fn token_requests(server: &TestServer) -> usize {
+    server.requests()
+        .iter()
+        .filter(|(_, url)| url.host_str() == Some(TOKENSERVER_HOST))
+        .count()
+}
+
+#[test]
+fn test_recover_from_node_reassignment() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("passwords");
+    store.insert("aaaaaaaaaaaa", "value");
+    SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    let resets = *store.resets.borrow();
+    let old_sync_id = state.global.as_ref().unwrap().payload.sync_id.clone();
+
+    server.reassign_node();
+
+    // The same client and state should recover in a single sync, by
+    // fetching a token for the new node, resetting, and reuploading.
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    result.engine_results["passwords"].as_ref().unwrap();
+    assert!(!client.node_reassigned());
+    assert_eq!(*store.resets.borrow(), resets + 1);
+    assert_ne!(state.global.as_ref().unwrap().payload.sync_id, old_sync_id);
+    let records = server.records("passwords");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].id, "aaaaaaaaaaaa");
+}
+
+#[test]
+fn test_recover_from_rejected_token() {
+    let (server, root_key, mut state) = setup();
+    let client = server.client();
+    let store = MemoryStore::new("passwords");
+    store.insert("aaaaaaaaaaaa", "value");
+    SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    let tokens = token_requests(&server);
+    let resets = *store.resets.borrow();
+
+    // A 401 from the same node means our token expired early, so we
+    // should fetch a new one and carry on, without resetting.
+    server.fail_next(Method::GET, "/info/collections", StatusCode::UNAUTHORIZED);
+    store.insert("bbbbbbbbbbbb", "value");
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    result.engine_results["passwords"].as_ref().unwrap();
+    assert_eq!(token_requests(&server), tokens + 1);
+    assert_eq!(*store.resets.borrow(), resets);
+    assert_eq!(server.records("passwords").len(), 2);
+}
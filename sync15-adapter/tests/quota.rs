@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::{ErrorKind, InterruptHandle, SyncManager};
+
+use common::{setup, sync, MemoryStore};
+
+#[test]
+fn test_quota() {
+    let (server, root_key, mut state) = setup();
+    let store = MemoryStore::new("testing");
+    for i in 0..10 {
+        store.insert(&format!("record{:06}", i), "value");
+    }
+    sync(&server, &mut state, &root_key, &store);
+    let client = server.client();
+    client.fetch_info_collections().unwrap();
+    assert_eq!(client.quota_remaining_kb(), None);
+
+    let counts = client.fetch_info_collection_counts().unwrap();
+    assert_eq!(counts.get("testing"), Some(&10));
+    let usage = client.fetch_info_collection_usage().unwrap();
+    let quota = client.fetch_info_quota().unwrap();
+    assert!(usage["testing"] > 0.0);
+    assert!(quota.usage_kb >= usage["testing"]);
+    assert_eq!(quota.quota_kb, None);
+
+    // Once we're close to the quota, the server tells us how much space
+    // we have left...
+    let used = (quota.usage_kb * 1024.0) as usize;
+    server.set_quota(Some(used + 2048));
+    assert_eq!(client.fetch_info_quota().unwrap().quota_kb, Some(used as f64 / 1024.0 + 2.0));
+    let mut telem = SyncTelemetry::new();
+    store.insert("record000010", "value");
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    result.engine_results["testing"].as_ref().unwrap();
+    let remaining = client.quota_remaining_kb().unwrap();
+    assert!(remaining > 0.0 && remaining < 2.0);
+
+    // ...and rejects writes that would go over it.
+    for i in 11..50 {
+        store.insert(&format!("record{:06}", i), "value");
+    }
+    let result = SyncManager::new(vec![&store])
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut telem)
+        .unwrap();
+    let err = result.engine_results["testing"].as_ref().unwrap_err();
+    assert!(err.is_quota_exceeded());
+    match err.kind() {
+        ErrorKind::QuotaExceeded { route } => assert!(route.ends_with("/storage/testing")),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
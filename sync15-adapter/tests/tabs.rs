@@ -0,0 +1,130 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::client::SetupStorageClient;
+use sync15_adapter::clients::{self, DeviceType, Settings};
+use sync15_adapter::request::InfoConfiguration;
+use sync15_adapter::tabs::{self, Tab};
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::Method;
+use sync15_adapter::{GlobalState, InterruptHandle, SyncManager};
+
+use common::{encrypt_json, requests_to, setup_with_config, TestCommandProcessor};
+
+fn tab(index: usize) -> Tab {
+    Tab {
+        title: format!("Tab {}", index),
+        url_history: vec![format!("https://example.com/{}", index)],
+        icon: None,
+        last_used: 1_500_000_000 + index as u64,
+    }
+}
+
+#[test]
+fn test_tabs_engine() {
+    let (server, root_key, mut state) = setup_with_config(InfoConfiguration {
+        max_record_payload_bytes: 2048,
+        ..InfoConfiguration::default()
+    });
+    let key = state.key_for_collection("clients").unwrap().clone();
+
+    // Another client uploads its client and tabs records. There's also a
+    // tabs record for a client without a client record.
+    server.insert_record("clients", encrypt_json(&key, "clients", json!({
+        "id": "otherclient1",
+        "name": "Other Phone",
+        "type": "mobile",
+        "commands": [],
+    })));
+    server.insert_records("tabs", vec![
+        encrypt_json(&key, "tabs", json!({
+            "id": "otherclient1",
+            "clientName": "Old Name",
+            "tabs": [serde_json::to_value(tab(1)).unwrap(),
+                     serde_json::to_value(tab(2)).unwrap()],
+        })),
+        encrypt_json(&key, "tabs", json!({
+            "id": "goneclient12",
+            "clientName": "Gone",
+            "tabs": [serde_json::to_value(tab(3)).unwrap()],
+        })),
+    ]);
+
+    let processor = TestCommandProcessor::new(Settings {
+        client_id: "ourclient123".into(),
+        fxa_device_id: None,
+        name: "Our Desktop".into(),
+        device_type: DeviceType::Desktop,
+    });
+    let clients_engine = clients::Engine::new(&processor);
+    let tabs_engine = tabs::Engine::new(&clients_engine);
+    // More tabs than fit in one record.
+    tabs_engine.set_local_tabs((0..50).map(tab).collect());
+    let client = server.client();
+    let result = SyncManager::<sync15_adapter::Error>::new(vec![])
+        .clients_engine(&clients_engine)
+        .tabs_engine(&tabs_engine)
+        .sync(&client, &mut state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+        .unwrap();
+    result.engine_results["tabs"].as_ref().unwrap();
+
+    let remote_tabs = tabs_engine.remote_tabs();
+    assert_eq!(remote_tabs.len(), 1);
+    assert_eq!(remote_tabs[0].client_id, "otherclient1");
+    assert_eq!(remote_tabs[0].client_name, "Other Phone");
+    assert_eq!(remote_tabs[0].device_type, Some(DeviceType::Mobile));
+    assert_eq!(remote_tabs[0].tabs, vec![tab(2), tab(1)]);
+
+    // We should upload our most recently used tabs, with a TTL.
+    let tabs_uploads = || requests_to(&server, Method::POST, "/storage/tabs").len();
+    let uploaded = server.records("tabs")
+        .into_iter()
+        .find(|bso| bso.id == "ourclient123")
+        .unwrap();
+    assert_eq!(uploaded.ttl, Some(21 * 24 * 60 * 60));
+    assert!(uploaded.payload.serialized_len() <= 2048);
+    let record = uploaded.decrypt(&key)
+                         .unwrap()
+                         .payload
+                         .into_record::<tabs::TabsRecord>()
+                         .unwrap();
+    assert_eq!(record.client_name, "Our Desktop");
+    assert!(record.tabs.len() < 50);
+    assert_eq!(record.tabs[0], tab(49));
+    assert_eq!(tabs_uploads(), 1);
+
+    // We shouldn't upload the same tabs again, but should upload new ones.
+    let sync_tabs = |state: &mut GlobalState| {
+        SyncManager::<sync15_adapter::Error>::new(vec![])
+            .clients_engine(&clients_engine)
+            .tabs_engine(&tabs_engine)
+            .sync(&client, state, &root_key, &InterruptHandle::new(), &mut SyncTelemetry::new())
+            .unwrap();
+    };
+    sync_tabs(&mut state);
+    assert_eq!(tabs_uploads(), 1);
+    tabs_engine.set_local_tabs(vec![tab(100)]);
+    sync_tabs(&mut state);
+    assert_eq!(tabs_uploads(), 2);
+
+    // If another client changes the tabs sync ID, we should reset, and
+    // upload our tabs again, even though they haven't changed.
+    let mut global = client.fetch_meta_global().unwrap();
+    global.payload.engines.get_mut("tabs").unwrap().sync_id = "newtabssync1".into();
+    client.put_meta_global(&global, None).unwrap();
+    sync_tabs(&mut state);
+    assert_eq!(tabs_uploads(), 3);
+    assert!(!state.engines_that_need_local_reset().contains("tabs"));
+    sync_tabs(&mut state);
+    assert_eq!(tabs_uploads(), 3);
+}
@@ -0,0 +1,137 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use serde_json::Value as JsonValue;
+
+use sync15_adapter::request::InfoConfiguration;
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::{ErrorKind, GlobalState, InterruptHandle, SyncManager};
+
+use common::{encrypt_json, setup, setup_with_config, sync, uploaded_ids, MemoryStore,
+             NoteStore};
+
+#[test]
+fn test_typed_store() {
+    let (server, root_key, state) = setup();
+    let key = state.key_for_collection("testing").unwrap();
+
+    // A newer client uploads a note with a field we don't know about, and
+    // another client uploads a note we can't understand.
+    server.insert_records("testing", vec![
+        encrypt_json(key, "testing", json!({
+            "id": "aaaaaaaaaaaa",
+            "value": "remote",
+            "color": "red",
+        })),
+        encrypt_json(key, "testing", json!({ "id": "bbbbbbbbbbbb", "value": 5 })),
+    ]);
+
+    let store = NoteStore::default();
+    let mut telem = SyncTelemetry::new();
+    let result = SyncManager::new(vec![&store])
+        .sync(&server.client(), &mut GlobalState::default(), &root_key, &InterruptHandle::new(),
+              &mut telem)
+        .unwrap();
+    result.engine_results["testing"].as_ref().unwrap();
+    assert_eq!(store.notes.borrow()["aaaaaaaaaaaa"], "remote (seen)");
+    assert_eq!(*store.malformed.borrow(), vec!["bbbbbbbbbbbb".to_string()]);
+    let incoming = telem.get_engines()[0].get_incoming();
+    assert_eq!(incoming.get_applied(), 1);
+    assert_eq!(incoming.get_failed(), 1);
+
+    // We should keep the unknown field when we upload our change.
+    let uploaded = server.records("testing")
+        .into_iter()
+        .find(|bso| bso.id == "aaaaaaaaaaaa")
+        .unwrap()
+        .decrypt(key)
+        .unwrap();
+    assert_eq!(JsonValue::from(uploaded.payload), json!({
+        "id": "aaaaaaaaaaaa",
+        "value": "remote (seen)",
+        "color": "red",
+    }));
+}
+
+#[test]
+fn test_shrink_oversized_records() {
+    let (server, root_key, mut state) = setup_with_config(InfoConfiguration {
+        max_record_payload_bytes: 2048,
+        ..InfoConfiguration::default()
+    });
+
+    // Atomic stores that can't shrink records fail the sync if one is too
+    // large, without uploading anything.
+    let atomic = MemoryStore::new("testing");
+    atomic.insert("aaaaaaaaaaaa", "small");
+    atomic.insert("bbbbbbbbbbbb", &"x".repeat(2048));
+    let result = SyncManager::new(vec![&atomic])
+        .sync(&server.client(), &mut state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new())
+        .unwrap();
+    match result.engine_results["testing"].as_ref().unwrap_err().kind() {
+        ErrorKind::RecordTooLargeError => {}
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert!(server.records("testing").is_empty());
+
+    // Stores that allow failures skip the records that are too large,
+    // without failing the sync.
+    let store = MemoryStore {
+        allow_failures: true,
+        ..MemoryStore::new("testing")
+    };
+    store.insert("aaaaaaaaaaaa", "small");
+    store.insert("bbbbbbbbbbbb", &"x".repeat(2048));
+    sync(&server, &mut state, &root_key, &store);
+    assert_eq!(uploaded_ids(&server, "testing"), vec!["aaaaaaaaaaaa"]);
+    assert_eq!(store.failed.borrow()["bbbbbbbbbbbb"], "Record too large to upload");
+    let key = state.key_for_collection("testing").unwrap();
+
+    // Another client with a larger limit uploads two large notes. We can
+    // shrink the first to fit, but the second has a large field that we
+    // don't know about.
+    server.insert_records("testing", vec![
+        encrypt_json(key, "testing", json!({
+            "id": "cccccccccccc",
+            "value": "y".repeat(2048),
+            "color": "red",
+        })),
+        encrypt_json(key, "testing", json!({
+            "id": "dddddddddddd",
+            "value": "remote",
+            "color": "z".repeat(2048),
+        })),
+    ]);
+
+    let notes = NoteStore::default();
+    let mut telem = SyncTelemetry::new();
+    let result = SyncManager::new(vec![&notes])
+        .sync(&server.client(), &mut GlobalState::default(), &root_key, &InterruptHandle::new(),
+              &mut telem)
+        .unwrap();
+    result.engine_results["testing"].as_ref().unwrap();
+    assert_eq!(telem.get_engines()[0].get_outgoing()[0].get_failed(), 1);
+
+    let find = |id: &str| {
+        server.records("testing").into_iter().find(|bso| bso.id == id).unwrap()
+    };
+    let shrunk = find("cccccccccccc");
+    assert!(shrunk.payload.serialized_len() <= 2048);
+    let shrunk = JsonValue::from(shrunk.decrypt(key).unwrap().payload);
+    assert!(shrunk["value"].as_str().unwrap().len() < 2048);
+    assert_eq!(shrunk["color"], "red");
+    // The other client's version is still on the server.
+    let skipped = JsonValue::from(find("dddddddddddd").decrypt(key).unwrap().payload);
+    assert_eq!(skipped["value"], "remote");
+}
@@ -0,0 +1,140 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use std::cell::RefCell;
+
+use sync15_adapter::request::InfoConfiguration;
+use sync15_adapter::sync::MAX_UPLOAD_ATTEMPTS;
+use sync15_adapter::telemetry::SyncTelemetry;
+use sync15_adapter::transport::Method;
+use sync15_adapter::{ErrorKind, InterruptHandle, RetrySet, SyncManager};
+
+use common::{encrypt_json, query_params, requests_to, setup, setup_with_config, sync,
+             uploaded_ids, MemoryStore};
+
+#[test]
+fn test_batch_upload() {
+    let (server, root_key, mut state) = setup_with_config(InfoConfiguration {
+        max_post_records: 2,
+        max_total_records: 10,
+        ..InfoConfiguration::default()
+    });
+    let store = MemoryStore::new("testing");
+    for i in 0..5 {
+        store.insert(&format!("record{:06}", i), "value");
+    }
+    sync(&server, &mut state, &root_key, &store);
+
+    let records = server.records("testing");
+    assert_eq!(records.len(), 5);
+    // All records in a batch are committed with the same timestamp.
+    assert!(records.iter().all(|r| r.modified == records[0].modified));
+
+    let posts = requests_to(&server, Method::POST, "/storage/testing")
+        .iter()
+        .map(query_params)
+        .collect::<Vec<_>>();
+    assert_eq!(posts.len(), 3);
+    assert_eq!(posts[0].get("batch").map(|s| s.as_str()), Some("true"));
+    assert!(posts[1].contains_key("batch"));
+    assert_eq!(posts[2].get("commit").map(|s| s.as_str()), Some("true"));
+}
+
+#[test]
+fn test_upload_failures() {
+    let (server, root_key, mut state) = setup();
+
+    // By default, a rejected record fails the sync.
+    let atomic = MemoryStore::new("testing");
+    atomic.insert("aaaaaaaaaaaa", "first");
+    atomic.insert("bbbbbbbbbbbb", "second");
+    server.reject_next_upload("aaaaaaaaaaaa", "retry later");
+    let result = SyncManager::new(vec![&atomic])
+        .sync(&server.client(), &mut state, &root_key, &InterruptHandle::new(),
+              &mut SyncTelemetry::new())
+        .unwrap();
+    match result.engine_results["testing"].as_ref().unwrap_err().kind() {
+        ErrorKind::RecordUploadFailed => {}
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert_eq!(atomic.changed.borrow().len(), 2);
+
+    // Stores that allow failures learn which records failed, and why.
+    let store = MemoryStore {
+        allow_failures: true,
+        ..MemoryStore::new("testing")
+    };
+    store.insert("aaaaaaaaaaaa", "first");
+    store.insert("bbbbbbbbbbbb", "second");
+    server.reject_next_upload("aaaaaaaaaaaa", "retry later");
+    sync(&server, &mut state, &root_key, &store);
+    assert_eq!(uploaded_ids(&server, "testing"), vec!["bbbbbbbbbbbb"]);
+    assert_eq!(store.failed.borrow().len(), 1);
+    assert_eq!(store.failed.borrow()["aaaaaaaaaaaa"], "retry later");
+    assert_eq!(*store.changed.borrow(), vec!["aaaaaaaaaaaa".to_string()]);
+
+    // The failed record is still marked as changed, so we try again.
+    sync(&server, &mut state, &root_key, &store);
+    assert_eq!(uploaded_ids(&server, "testing"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+    assert!(store.failed.borrow().is_empty());
+    assert!(store.changed.borrow().is_empty());
+}
+
+#[test]
+fn test_retry_set() {
+    let (server, root_key, mut state) = setup();
+    let store = MemoryStore {
+        allow_failures: true,
+        retry_set: RefCell::new(Some(RetrySet::new())),
+        ..MemoryStore::new("testing")
+    };
+    store.insert("aaaaaaaaaaaa", "first");
+    store.insert("bbbbbbbbbbbb", "second");
+    store.insert("cccccccccccc", "third");
+    server.reject_next_upload("aaaaaaaaaaaa", "retry later");
+    server.reject_next_upload("bbbbbbbbbbbb", "retry later");
+    for _ in 0..MAX_UPLOAD_ATTEMPTS {
+        server.reject_next_upload("cccccccccccc", "always rejected");
+    }
+    sync(&server, &mut state, &root_key, &store);
+    assert!(store.changed.borrow().is_empty());
+    assert!(server.records("testing").is_empty());
+    {
+        let retry_set = store.retry_set.borrow();
+        let retry_set = retry_set.as_ref().unwrap();
+        assert_eq!(retry_set.len(), 3);
+        assert_eq!(retry_set.reason("aaaaaaaaaaaa"), Some("retry later"));
+        assert_eq!(retry_set.reason("cccccccccccc"), Some("always rejected"));
+    }
+
+    // Another client changes B, so we don't retry our old version.
+    let key = state.key_for_collection("testing").unwrap().clone();
+    server.insert_record("testing", encrypt_json(&key, "testing", json!({
+        "id": "bbbbbbbbbbbb",
+        "value": "remote",
+    })));
+
+    // A is retried without changing locally.
+    sync(&server, &mut state, &root_key, &store);
+    assert_eq!(uploaded_ids(&server, "testing"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+    assert_eq!(store.value("bbbbbbbbbbbb"), Some("remote".to_string()));
+    assert_eq!(store.retry_set.borrow().as_ref().unwrap().len(), 1);
+
+    // We give up on C after it fails too many times.
+    sync(&server, &mut state, &root_key, &store);
+    assert!(store.retry_set.borrow().as_ref().unwrap().is_empty());
+    assert_eq!(store.failed.borrow()["cccccccccccc"], "always rejected");
+    sync(&server, &mut state, &root_key, &store);
+    assert!(store.failed.borrow().is_empty());
+    assert_eq!(uploaded_ids(&server, "testing"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+}
@@ -0,0 +1,52 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sync15_adapter;
+extern crate sync15_test_server;
+
+mod common;
+
+use sync15_adapter::validation::{self, Problem, TypedValidator};
+use sync15_adapter::{KeyBundle, Payload};
+
+use common::{encrypt_json, setup, sync, MemoryStore, Note};
+
+#[test]
+fn test_validate_collection() {
+    let (server, root_key, mut state) = setup();
+    let store = MemoryStore::new("testing");
+    store.insert("aaaaaaaaaaaa", "first");
+    sync(&server, &mut state, &root_key, &store);
+
+    let key = state.key_for_collection("testing").unwrap();
+    let other_key = KeyBundle::new_random().unwrap();
+    server.insert_records("testing", vec![
+        encrypt_json(key, "testing", json!({ "id": "bbbbbbbbbbbb", "value": 2 })),
+        encrypt_json(&other_key, "testing", json!({ "id": "cccccccccccc", "value": "third" })),
+        Payload::new_tombstone("dddddddddddd".into())
+            .into_bso("testing".into())
+            .encrypt(key)
+            .unwrap(),
+    ]);
+
+    let validator = TypedValidator::<Note>::new("testing");
+    let report = validation::fetch_and_validate(&server.client(), &state, &validator)
+        .unwrap();
+    assert_eq!(report.record_count, 4);
+    assert_eq!(report.problems.len(), 2);
+    match report.problems[0] {
+        Problem::Undecryptable { ref id, .. } => assert_eq!(id, "cccccccccccc"),
+        ref problem => panic!("Unexpected problem {:?}", problem),
+    }
+    match report.problems[1] {
+        Problem::InvalidRecord { ref id, .. } => assert_eq!(id, "bbbbbbbbbbbb"),
+        ref problem => panic!("Unexpected problem {:?}", problem),
+    }
+    let summary = serde_json::to_value(&report).unwrap()["summary"].clone();
+    assert_eq!(summary, json!({ "invalidRecord": 1, "undecryptable": 1 }));
+}